@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ailmedak::api_layer::{parse_request, Request, MAX_REQUEST_LEN};
+
+//whatever arrives on the api port, parsing returns instead of panicking
+fuzz_target!(|data: &[u8]| {
+    if let Ok((request, _)) = parse_request(data, 8) {
+        let key = match request {
+            Request::Get {key, ..} | Request::Set {key, ..} | Request::Delete {key, ..} => key
+        };
+        assert!(key.len() <= MAX_REQUEST_LEN);
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ailmedak::message_protocol::{try_decode, MessageView, MAX_MESSAGE_LEN};
+
+//whatever arrives on the cluster port, decoding returns instead of panicking, and reading a view
+//gives the same message as decoding it
+fuzz_target!(|data: &[u8]| {
+    match MessageView::parse(data) {
+        Ok((view, sender)) => {
+            assert!(data.len() <= MAX_MESSAGE_LEN);
+            assert_eq!(Some(&view.opcode()), data.first());
+            let decoded = try_decode(data, &20).unwrap();
+            assert_eq!((view.to_message(), *sender), decoded);
+        },
+        Err(e) => assert_eq!(try_decode(data, &20).unwrap_err(), e)
+    }
+});
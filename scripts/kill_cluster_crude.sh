@@ -1,2 +1,3 @@
 #!/usr/bin/env bash
-pkill -f ailmedak
+# kills every node outright, without handing off its values
+pkill -KILL -f ailmedak
@@ -0,0 +1,3 @@
+#!/usr/bin/env bash
+# asks every node to leave the network, handing its values off first
+pkill -TERM -f ailmedak
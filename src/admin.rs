@@ -0,0 +1,241 @@
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use api_layer::{ClientMessage, REQUEST_NAMES};
+use config::Config;
+use ratelimit::{RateLimits, Rate};
+use auth::ClusterAuth;
+use message_protocol::{Key, NodeContact, MESSAGE_NAMES};
+use node::machine::MessageType;
+use node::leave::Shutdown;
+use node::state::KademliaNode;
+use node::trace::LookupTrace;
+use storage::ValueStore;
+use utils::fmt::{as_hex_string, from_hex_string, JsonStr};
+use utils::loggerator::Loggerator;
+use utils::networking::{read_http_get, write_http_response};
+
+/// seconds the admin thread waits on the node to answer a query
+const ANSWER_TIMEOUT_SECS: u64 = 5;
+
+/// What the admin port reports on, other than the configuration, which the admin thread knows
+/// without asking the node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminQuery {
+    //the id of the node and a summary of its state
+    Node,
+    //the k-buckets that hold contacts
+    Buckets,
+    //the keys held locally, deleted ones included
+    Keys,
+    //the lookups the alpha thread is carrying out
+    Lookups,
+    //the last traced lookups, of one key or of any
+    Traces(Option<Key>)
+}
+
+impl AdminQuery {
+    /// the query served at path
+    pub fn from_path (path: &str) -> Option<AdminQuery> {
+        match path {
+            "/node" => Some(AdminQuery::Node),
+            "/buckets" => Some(AdminQuery::Buckets),
+            "/keys" => Some(AdminQuery::Keys),
+            "/lookups" => Some(AdminQuery::Lookups),
+            "/traces" => Some(AdminQuery::Traces(None)),
+            _ => {
+                let key = from_hex_string(path.strip_prefix("/traces/")?)?;
+                if key.len() != 20 {
+                    return None
+                }
+                let mut k = [0; 20];
+                k.copy_from_slice(&key);
+                Some(AdminQuery::Traces(Some(k)))
+            }
+        }
+    }
+}
+
+/// A lookup in progress, as the alpha thread reports it
+#[derive(Clone, Debug)]
+pub struct LookupStatus {
+    pub key: Key,
+    pub purpose: &'static str,
+    pub age: Duration,
+    //candidates that are yet to be asked, were asked, answered and timed out
+    pub waiting: usize,
+    pub in_flight: usize,
+    pub answered: usize,
+    pub timed_out: usize
+}
+
+fn json_opt <T: Display> (value: &Option<T>) -> String {
+    value.as_ref().map_or("null".to_string(), |v| v.to_string())
+}
+
+fn rate_json (rate: &Rate) -> String {
+    format!("{{\"per_sec\":{},\"burst\":{}}}", rate.per_sec, rate.burst)
+}
+
+fn limits_json (limits: &RateLimits, names: &[&str]) -> String {
+    let mut per_type = limits.per_type.iter().collect::<Vec<(&u8, &Rate)>>();
+    per_type.sort_by_key(|&(opcode, _)| *opcode);
+    let per_type = per_type.iter().map(|&(opcode, rate)| {
+        format!("{}:{}", JsonStr(names.get(*opcode as usize).unwrap_or(&"?")), rate_json(rate))
+    }).collect::<Vec<String>>();
+    format!("{{\"default\":{},\"per_type\":{{{}}},\"ban_after\":{},\"ban_secs\":{}}}",
+            limits.default.as_ref().map_or("null".to_string(), rate_json), per_type.join(","),
+            json_opt(&limits.ban_after), limits.ban_secs)
+}
+
+/// when each cluster key expires, never the keys themselves
+fn cluster_keys_json (auth: &Option<ClusterAuth>) -> String {
+    match *auth {
+        Some(ref auth) => {
+            let keys = auth.keys().iter().map(|k| format!("{{\"expires\":{}}}", json_opt(&k.expires))).collect::<Vec<String>>();
+            format!("[{}]", keys.join(","))
+        },
+        None => "null".to_string()
+    }
+}
+
+fn json_opt_str (value: &Option<String>) -> String {
+    value.as_ref().map_or("null".to_string(), |v| JsonStr(v).to_string())
+}
+
+/// the id of the node along with counts of what it holds
+pub fn node_json (state: &KademliaNode) -> String {
+    format!("{{\"id\":\"{}\",\"contacts\":{},\"keys\":{},\"bytes\":{},\"pending_handoffs\":{},\"leaving\":{}}}",
+            as_hex_string(&state.addr_id), state.buckets.iter().map(|b| b.len()).sum::<usize>(),
+            state.data.len(), state.data.bytes(), state.handoff.len(), state.leaving)
+}
+
+/// every k-bucket that holds contacts, with the unix time each contact was last heard from
+pub fn buckets_json (state: &KademliaNode) -> String {
+    let buckets = state.routing_table().iter().map(|(index, contacts)| {
+        let contacts = contacts.iter().map(|(id, addr)| {
+            format!("{{\"id\":\"{}\",\"addr\":\"{}\",\"last_seen\":{}}}",
+                    as_hex_string(id), addr, json_opt(&state.last_seen.get(id)))
+        }).collect::<Vec<String>>();
+        format!("{{\"index\":{},\"contacts\":[{}]}}", index, contacts.join(","))
+    }).collect::<Vec<String>>();
+    format!("[{}]", buckets.join(","))
+}
+
+/// Every key held, by its versions, their total size in bytes and the unix time the last of them
+/// expires. Deleted keys are listed until their tombstone expires
+pub fn keys_json (data: &ValueStore) -> String {
+    let mut keys = data.keys();
+    keys.sort();
+    let keys = keys.iter().map(|key| {
+        let versions = data.versions(key);
+        let (deleted, expires_at) = match data.tombstone(key) {
+            Some(t) if versions.is_empty() => (true, t.expires_at),
+            _ => (false, versions.iter().map(|v| v.expires_at).max().unwrap_or(0))
+        };
+        format!("{{\"key\":\"{}\",\"versions\":{},\"bytes\":{},\"expires_at\":{},\"deleted\":{}}}",
+                as_hex_string(key), versions.len(), versions.iter().map(|v| v.data.len()).sum::<usize>(),
+                expires_at, deleted)
+    }).collect::<Vec<String>>();
+    format!("[{}]", keys.join(","))
+}
+
+pub fn lookups_json (lookups: &[LookupStatus]) -> String {
+    let lookups = lookups.iter().map(|l| {
+        format!("{{\"key\":\"{}\",\"purpose\":\"{}\",\"age_ms\":{},\"waiting\":{},\"in_flight\":{},\"answered\":{},\"timed_out\":{}}}",
+                as_hex_string(&l.key), l.purpose, l.age.as_millis(), l.waiting, l.in_flight, l.answered, l.timed_out)
+    }).collect::<Vec<String>>();
+    format!("[{}]", lookups.join(","))
+}
+
+fn addr_of (contact: &NodeContact<Key>) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::from(contact.ip), contact.port)
+}
+
+fn contact_json (contact: &NodeContact<Key>) -> String {
+    format!("{{\"id\":\"{}\",\"addr\":\"{}\"}}", as_hex_string(&contact.id), addr_of(contact))
+}
+
+fn millis (d: &Option<Duration>) -> String {
+    json_opt(&d.map(|d| format!("{:.3}", d.as_secs_f64() * 1000.0)))
+}
+
+/// Traced lookups with every contact they considered: when it was queried and answered (in
+/// milliseconds since the lookup started), the colors it went through and what it returned
+pub fn traces_json (traces: &[&LookupTrace]) -> String {
+    let traces = traces.iter().map(|t| {
+        let hops = t.hops.iter().map(|h| {
+            let colors = h.colors.iter().map(|(at, color)| format!("{{\"at_ms\":{},\"color\":\"{}\"}}", millis(&Some(*at)), color))
+                                 .collect::<Vec<String>>();
+            let returned = h.returned.iter().map(contact_json).collect::<Vec<String>>();
+            format!("{{\"id\":\"{}\",\"addr\":\"{}\",\"sent_ms\":{},\"received_ms\":{},\"colors\":[{}],\"returned\":[{}],\"had_value\":{}}}",
+                    as_hex_string(&h.contact.id), addr_of(&h.contact), millis(&h.sent), millis(&h.received), colors.join(","),
+                    returned.join(","), h.had_value)
+        }).collect::<Vec<String>>();
+        format!("{{\"key\":\"{}\",\"purpose\":\"{}\",\"duration_ms\":{},\"hops\":[{}]}}",
+                as_hex_string(&t.key), t.purpose, millis(&t.duration), hops.join(","))
+    }).collect::<Vec<String>>();
+    format!("[{}]", traces.join(","))
+}
+
+pub fn config_json (config: &Config) -> String {
+    let limits = &config.store_limits;
+    let neighbors = config.initial_neighbors.iter().map(|n| JsonStr(n).to_string()).collect::<Vec<String>>();
+    let modules = config.log.modules.iter().map(|(m, l)| format!("{}:\"{}\"", JsonStr(m), l)).collect::<Vec<String>>();
+    format!("{{\"network_port\":{},\"api_port\":{},\"metrics_port\":{},\"admin_port\":{},\"k_val\":{},\
+             \"async_poll_interval\":{},\"initial_neighbors\":[{}],\"data_dir\":{},\"fsync_policy\":\"{}\",\
+             \"value_ttl\":{},\"tombstone_ttl\":{},\"routing_snapshot_interval\":{},\"anti_entropy_interval\":{},\
+             \"handoff_batch\":{},\"leave_timeout\":{},\"store_limits\":{{\"max_bytes\":{},\"max_keys\":{},\
+             \"max_value_size\":{},\"per_source_bytes\":{}}},\"eviction_policy\":\"{}\",\"replication_factor\":{},\
+             \"read_quorum\":{},\"write_quorum\":{},\"token_rotation\":{},\"cluster_keys\":{},\"encryption\":\"{}\",\"diversity\":{{\"same_ip\":\"{}\",\"same_prefix\":\"{}\",\
+             \"exempt_local\":{}}},\"protocol_limits\":{},\"api_limits\":{},\"log\":{{\"level\":\"{}\",\"modules\":{{{}}},\"format\":\"{}\",\"file\":{}}}}}",
+            config.network_port, json_opt(&config.api_port), json_opt(&config.metrics_port), json_opt(&config.admin_port),
+            config.k_val, config.async_poll_interval, neighbors.join(","), json_opt_str(&config.data_dir),
+            config.fsync_policy, config.value_ttl, config.tombstone_ttl, config.routing_snapshot_interval,
+            config.anti_entropy_interval, config.handoff_batch, config.leave_timeout, json_opt(&limits.max_bytes),
+            json_opt(&limits.max_keys), json_opt(&limits.max_value_size), json_opt(&limits.per_source_bytes),
+            config.eviction_policy, config.replication_factor, config.read_quorum, config.write_quorum, config.token_rotation, cluster_keys_json(&config.cluster_auth), config.encryption,
+            config.diversity.same_ip, config.diversity.same_prefix, config.diversity.exempt_local, limits_json(&config.protocol_limits, &MESSAGE_NAMES), limits_json(&config.api_limits, &REQUEST_NAMES),
+            config.log.level, modules.join(","), config.log.format, json_opt_str(&config.log.file))
+}
+
+///Serves the state of the node as JSON over HTTP, on localhost only. A GET of `/node`, `/buckets`,
+///`/keys`, `/lookups`, `/traces`, `/traces/<key in hex>` or `/config` is answered, anything else gets a 404. The listener is polled so the
+///thread notices when it is stopped
+pub fn spawn_admin_thread (port: u16, node: Sender<MessageType>, config: String, logger: &Loggerator, poll: Duration, stop: Shutdown) -> JoinHandle<()> {
+    let logger = logger.for_module(module_path!());
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let _ = listener.set_nonblocking(true);
+    thread::spawn(move || {
+        logger.info("admin listening", &[("port", &port)]);
+        while !stop.is_triggered() {
+            match listener.accept() {
+                Ok((stream, _)) => serve(stream, &node, &config, poll),
+                Err(_) => thread::sleep(poll)
+            }
+        }
+    })
+}
+
+fn serve (mut stream: TcpStream, node: &Sender<MessageType>, config: &str, timeout: Duration) {
+    let path = read_http_get(&mut stream, timeout);
+    let (status, body) = match path.as_deref() {
+        Some("/config") => ("200 OK", config.to_string()),
+        Some(path) => match AdminQuery::from_path(path) {
+            //the node stopped, or is too busy to answer
+            Some(query) => ask(node, query).map_or(("503 Service Unavailable", String::new()), |report| ("200 OK", report)),
+            None => ("404 Not Found", String::new())
+        },
+        None => ("404 Not Found", String::new())
+    };
+    write_http_response(&mut stream, status, "application/json", &body);
+}
+
+fn ask (node: &Sender<MessageType>, query: AdminQuery) -> Option<String> {
+    let (tx, rx) = channel();
+    node.send(MessageType::FromClient(ClientMessage::Admin(query, tx))).ok()?;
+    rx.recv_timeout(Duration::from_secs(ANSWER_TIMEOUT_SECS)).ok()
+}
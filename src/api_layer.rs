@@ -1,98 +1,407 @@
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use std::sync::mpsc::{Sender, channel};
+use std::sync::mpsc::{Sender, RecvTimeoutError, channel};
 use std::net::{UdpSocket, SocketAddr};
 use crypto::sha1::Sha1;
 use crypto::digest::Digest;
 use node::machine::MessageType;
-use std::collections::hash_map::Entry::{Occupied, Vacant};
+use node::leave::Shutdown;
+use metrics::Metrics;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::fmt;
+use utils::loggerator::Loggerator;
 use utils::fmt::as_hex_string;
 use utils::u8_4_to_u32;
+use message_protocol::{StoreStatus, Sibling, NodeContact, Key};
+use node::state::RoutingTable;
+use admin::AdminQuery;
+use config::{Config, Replication};
+use ratelimit::{RateLimiter, Verdict};
+use storage::version::VectorClock;
 
+///Status byte sent back for a set or delete when no replica accepted the value and none gave a reason
+///(i.e. they failed or timed out). Otherwise the status byte is a StoreStatus code
+pub const STATUS_UNAVAILABLE: u8 = 255;
+
+//status byte leading the reply to a get
+pub const READ_FOUND: u8 = 0;
+pub const READ_NOT_FOUND: u8 = 1;
+pub const READ_QUORUM_FAILED: u8 = 2;
+
+///Status byte of the reply to a request that could not be parsed, followed by the reason
+pub const REQUEST_MALFORMED: u8 = 254;
+
+///Set in the opcode of a request to trace the lookup it starts
+pub const TRACE_FLAG: u8 = 0x80;
+
+///largest request read from the api port
+pub const MAX_REQUEST_LEN: usize = 4096;
+
+///names of the requests, by opcode
+pub const REQUEST_NAMES: [&str; 3] = ["get", "set", "delete"];
+
+///Identifies a get, set or delete from when its requester is registered until it is answered
+pub type RequestId = u64;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+///a request id no other request of this process has
+pub fn next_request_id () -> RequestId {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+///Requests from clients. The optional count is the read (R) or write (W) quorum of the request,
+///None to use the node's default. Writes may carry the causal context returned by a get, which
+///makes them supersede the siblings that get returned
 #[derive(Debug)]
 pub enum ClientMessage {
-    Get([u8; 20]),
-    Set([u8; 20], Vec<u8>)
+    Get(RequestId, [u8; 20], Option<usize>),
+    Set(RequestId, [u8; 20], Vec<u8>, Option<usize>, Option<VectorClock>),
+    Delete(RequestId, [u8; 20], Option<usize>, Option<VectorClock>),
+    //the closest nodes to an id, found by a lookup
+    FindNode([u8; 20], Sender<Vec<NodeContact<Key>>>),
+    //a snapshot of the k-buckets
+    RoutingTable(Sender<RoutingTable>),
+    //a report for the admin port, as JSON
+    Admin(AdminQuery, Sender<String>),
+    //traces the next lookup of the key
+    Trace([u8; 20])
+}
+
+///The outcome of a get
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReadResult {
+    //the latest versions of the value. more than one means concurrent writes conflicted
+    Found(Vec<Sibling>),
+    NotFound,
+    //fewer replicas than the read quorum answered before the lookup ended
+    QuorumFailed
 }
 
+impl ReadResult {
+    ///the reply sent to the client: a status byte, followed by the causal context and the siblings
+    ///if the value was found
+    ///```text
+    ///[READ_FOUND][context][count: 2]([value length: 4][value])*
+    ///```
+    ///the context is the vector clock all siblings descend to, as sent back with a write
+    pub fn encode (&self) -> Vec<u8> {
+        match *self {
+            ReadResult::Found(ref siblings) => {
+                let mut context = VectorClock::new();
+                for (clock, _) in siblings.iter() {
+                    context.merge(clock);
+                }
+                let mut reply = vec![READ_FOUND];
+                context.encode_into(&mut reply);
+                reply.extend_from_slice(&(siblings.len() as u16).to_be_bytes());
+                for (_, val) in siblings.iter() {
+                    reply.extend_from_slice(&(val.len() as u32).to_be_bytes());
+                    reply.extend_from_slice(val);
+                }
+                reply
+            },
+            ReadResult::NotFound => vec![READ_NOT_FOUND],
+            ReadResult::QuorumFailed => vec![READ_QUORUM_FAILED]
+        }
+    }
+}
+
+///The outcome of a set or delete
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WriteResult {
+    //Ok if any replica accepted the write, None if none did and none gave a reason
+    pub status: Option<StoreStatus>,
+    //how many replicas acknowledged it
+    pub acks: usize
+}
+
+///Where the answer to a request goes: a client of the UDP api, or a NodeHandle in the same process
+pub enum Requester<T> {
+    Udp(SocketAddr),
+    Local(Sender<T>)
+}
+
+///Requests are answered by id, as there may be more than one on the same key at a time
 pub enum Callback {
-    Register([u8; 20], SocketAddr),
+    Register(RequestId, Requester<ReadResult>),
     //TODO: an Arc wrapper, RwLock, or just a large array might be more performant
-    Resolve([u8; 20], Vec<u8>)
+    Resolve(RequestId, ReadResult),
+    RegisterWrite(RequestId, Requester<WriteResult>),
+    //the outcome of a set or delete (Ok if any replica accepted it) and how many replicas
+    //acknowledged it
+    Written(RequestId, Option<StoreStatus>, usize)
+}
+
+///Client keys are arbitrary bytes. they are hashed into the 160 bit namespace of the nodes
+pub fn hash_key (key: &[u8]) -> [u8; 20] {
+    let mut sha = Sha1::new();
+    sha.input(key);
+    let mut hash_key:[u8; 20] = [0; 20];
+    sha.result(&mut hash_key);
+    hash_key
+}
+
+///Why a datagram is not a request
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RequestError {
+    Empty,
+    UnknownOp(u8),
+    //a length field runs past the end of the datagram
+    Truncated,
+    //a length field is over MAX_REQUEST_LEN
+    Oversize(usize),
+    //a field holds something it cannot, such as a context that is not a vector clock
+    Malformed(&'static str),
+    //a read or write quorum over the replication factor, which could never be met
+    Quorum {quorum: usize, replicas: usize}
+}
+
+impl fmt::Display for RequestError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Empty => write!(f, "empty request"),
+            RequestError::UnknownOp(op) => write!(f, "unknown operation {}", op),
+            RequestError::Truncated => write!(f, "request is truncated"),
+            RequestError::Oversize(len) => write!(f, "length {} is over {}", len, MAX_REQUEST_LEN),
+            RequestError::Malformed(field) => write!(f, "invalid {}", field),
+            RequestError::Quorum {quorum, replicas} => write!(f, "quorum {} is over the replication factor {}", quorum, replicas)
+        }
+    }
+}
+
+///A request of a client, borrowing the key and value from the datagram it came in
+#[derive(Debug, PartialEq)]
+pub enum Request<'a> {
+    Get {key: &'a [u8], quorum: Option<usize>},
+    Set {key: &'a [u8], val: &'a [u8], quorum: Option<usize>, context: Option<VectorClock>},
+    Delete {key: &'a [u8], quorum: Option<usize>, context: Option<VectorClock>}
+}
+
+///a 4 byte length and the field it prefixes, split off the front of bytes
+fn length_prefixed (bytes: &[u8]) -> Result<(&[u8], &[u8]), RequestError> {
+    let len = u8_4_to_u32(bytes.get(..4).ok_or(RequestError::Truncated)?) as usize;
+    if len > MAX_REQUEST_LEN {
+        return Err(RequestError::Oversize(len))
+    }
+    let field = bytes.get(4..4 + len).ok_or(RequestError::Truncated)?;
+    Ok((field, &bytes[4 + len..]))
+}
+
+///The quorum byte that may trail a request and the causal context that may follow it. A quorum
+///of 0 (or leaving it off) means the node's default
+fn trailer (bytes: &[u8], with_context: bool) -> Result<(Option<usize>, Option<VectorClock>), RequestError> {
+    let (quorum, rest) = match bytes.split_first() {
+        None => return Ok((None, None)),
+        Some((&0, rest)) => (None, rest),
+        Some((&n, rest)) => (Some(n as usize), rest)
+    };
+    if rest.is_empty() {
+        return Ok((quorum, None))
+    }
+    if !with_context {
+        return Err(RequestError::Malformed("trailing bytes"))
+    }
+    match VectorClock::decode(rest) {
+        Some((context, used)) if used == rest.len() => Ok((quorum, Some(context))),
+        Some(_) => Err(RequestError::Malformed("trailing bytes")),
+        None => Err(RequestError::Malformed("context"))
+    }
+}
+
+///Parses a request (see spawn_api_thread for the format), checking every length against the
+///datagram and the quorum against the replication factor. Returns the request and whether its
+///lookup is traced
+pub fn parse_request (datagram: &[u8], replicas: usize) -> Result<(Request<'_>, bool), RequestError> {
+    let (&op, body) = datagram.split_first().ok_or(RequestError::Empty)?;
+    let traced = op & TRACE_FLAG != 0;
+    let op = op & !TRACE_FLAG;
+    if op as usize >= REQUEST_NAMES.len() {
+        return Err(RequestError::UnknownOp(op))
+    }
+    let (key, rest) = length_prefixed(body)?;
+    let request = match op {
+        0 => {
+            let (quorum, _) = trailer(rest, false)?;
+            Request::Get {key, quorum}
+        },
+        1 => {
+            let (val, rest) = length_prefixed(rest)?;
+            let (quorum, context) = trailer(rest, true)?;
+            Request::Set {key, val, quorum, context}
+        },
+        _ => {
+            let (quorum, context) = trailer(rest, true)?;
+            Request::Delete {key, quorum, context}
+        }
+    };
+    match request {
+        Request::Get {quorum: Some(quorum), ..} | Request::Set {quorum: Some(quorum), ..} | Request::Delete {quorum: Some(quorum), ..} if quorum > replicas => {
+            Err(RequestError::Quorum {quorum, replicas})
+        },
+        request => Ok((request, traced))
+    }
 }
 
 ///Exposes Ailmedak to consumers (not nodes) who would like to access the core as a key value
 ///store. for now only UDP is used as transport
+///
+///requests (lengths are 4 byte big endian, the trailing quorum byte is optional and may be
+///followed by the causal context of a previous get):
+///```text
+///get:    [0][key length][key][R]
+///set:    [1][key length][key][value length][value][W][context]
+///delete: [2][key length][key][W][context]
+///```
+///
+///Setting TRACE_FLAG in the opcode traces the lookup the request starts, see the admin port.
+///Requests that do not parse are answered with REQUEST_MALFORMED and the reason
+///
+///The listener times out every poll to notice when it is stopped
+///
 ///Returns a tuple of the handle of the thread, and a Sender that the thread listens to messages on
-pub fn spawn_api_thread (port: u16, send: Sender<MessageType>) -> (JoinHandle<()>, Sender<Callback>){
-    let (tx, rx) = channel();
+pub fn spawn_api_thread (port: u16, config: &Config, send: Sender<MessageType>, metrics: Arc<Metrics>, logger: &Loggerator, stop: Shutdown) -> (JoinHandle<()>, Sender<Callback>){
+    let logger = logger.for_module(module_path!());
+    let poll = Duration::from_millis(config.async_poll_interval as u64);
+    let Replication {n: replicas, ..} = config.replication();
+    let ban_secs = config.api_limits.ban_secs;
+    let mut limiter = RateLimiter::new(config.api_limits.clone());
     let bind = UdpSocket::bind(("0.0.0.0", port)).unwrap();
+    let (response_thread, tx) = spawn_callback_thread(Some(bind.try_clone().unwrap()), poll, stop.clone());
     let tx_clone = tx.clone();
 
     let listener = bind.try_clone().unwrap();
+    let _ = listener.set_read_timeout(Some(poll));
     let request_thread = thread::spawn(move || {
-        println!("[STATUS] API LISTENING ON PORT <{}>", port);
-        loop {
-            let mut buf:[u8; 4096] = [0; 4096];
-            let (_, src) = listener.recv_from(&mut buf).unwrap();
-            match buf.first() {
-                Some(&0) => { //this is a lookup type
-                    let key_length = u8_4_to_u32(&buf[1..5]) as usize;
-                    let key = &buf[5..5+key_length];
-                    let mut sha = Sha1::new();
-                    let _ = sha.input(key);
-                    let mut hash_key:[u8; 20] = [0; 20];
-                    let _ = sha.result(&mut hash_key);
-                    let _ = tx.send(Callback::Register(hash_key.clone(), src));
-                    println!("GETTING: Key({}) from {:?}", as_hex_string(&hash_key), src);
-                    let _ = send.send(MessageType::FromClient(ClientMessage::Get(hash_key)));
+        logger.info("api listening", &[("port", &port)]);
+        while !stop.is_triggered() {
+            let mut buf = [0; MAX_REQUEST_LEN];
+            let (num_read, src) = match listener.recv_from(&mut buf) {
+                Ok(read) => read,
+                Err(_) => continue
+            };
+            let datagram = &buf[..num_read];
+            let op = datagram.first().map(|op| op & !TRACE_FLAG);
+            if let Some(op) = op {
+                metrics.api_request(op);
+                match limiter.check(src.ip(), op, Instant::now()) {
+                    Verdict::Allow => (),
+                    verdict => {
+                        metrics.api_rate_limited(op);
+                        if verdict == Verdict::Ban {
+                            metrics.banned();
+                            logger.warn("banned flooding client", &[("ip", &src.ip()), ("secs", &ban_secs)]);
+                        }
+                        continue
+                    }
+                }
+            }
+            let (request, traced) = match parse_request(datagram, replicas) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    metrics.api_malformed();
+                    logger.debug("malformed request", &[("reason", &e), ("from", &src)]);
+                    let mut reply = vec![REQUEST_MALFORMED];
+                    reply.extend_from_slice(e.to_string().as_bytes());
+                    let _ = listener.send_to(&reply, src);
+                    continue
+                }
+            };
+            //the trace is asked for before the request, so it applies to the lookup the request starts
+            let trace = |key| if traced {
+                let _ = send.send(MessageType::FromClient(ClientMessage::Trace(key)));
+            };
+            match request {
+                Request::Get {key, quorum} => {
+                    let hash_key = hash_key(key);
+                    let id = next_request_id();
+                    let _ = tx.send(Callback::Register(id, Requester::Udp(src)));
+                    logger.debug("get", &[("key", &as_hex_string(&hash_key)), ("from", &src)]);
+                    trace(hash_key);
+                    let _ = send.send(MessageType::FromClient(ClientMessage::Get(id, hash_key, quorum)));
+                },
+                Request::Set {key, val, quorum, context} => {
+                    let hash_key = hash_key(key);
+                    let id = next_request_id();
+                    logger.debug("set", &[("key", &as_hex_string(&hash_key)), ("from", &src)]);
+                    let _ = tx.send(Callback::RegisterWrite(id, Requester::Udp(src)));
+                    trace(hash_key);
+                    let _ = send.send(MessageType::FromClient(ClientMessage::Set(id, hash_key, val.to_owned(), quorum, context)));
                 },
-                Some(&1) => { //this is a store
-                    let key_length = u8_4_to_u32(&buf[1..5]) as usize;
-                    let key = &buf[5..5+key_length];
-                    let val_length = u8_4_to_u32(&buf[5+key_length..9+key_length]) as usize;
-
-                    let val = &buf[9+key_length..9+key_length+val_length];
-                    let mut sha = Sha1::new();
-                    let _ = sha.input(key);
-                    let mut hash_key:[u8; 20] = [0; 20];
-                    let _ = sha.result(&mut hash_key);
-                    println!("SETTING: Key({}) from {:?}", as_hex_string(&hash_key), src);
-                    let _ = send.send(MessageType::FromClient(ClientMessage::Set(hash_key, val.to_owned())));
+                Request::Delete {key, quorum, context} => {
+                    let hash_key = hash_key(key);
+                    let id = next_request_id();
+                    logger.debug("delete", &[("key", &as_hex_string(&hash_key)), ("from", &src)]);
+                    let _ = tx.send(Callback::RegisterWrite(id, Requester::Udp(src)));
+                    trace(hash_key);
+                    let _ = send.send(MessageType::FromClient(ClientMessage::Delete(id, hash_key, quorum, context)));
                 }
-                _ => ()
             };
         }
+        drop(tx);
+        let _ = response_thread.join();
     });
 
+    (request_thread, tx_clone)
+}
+
+///Answers requests once the node has their results, over the api socket or the channel of the
+///requester. Without an api socket only local requesters are answered. The thread exits once
+///stop is triggered and the callbacks queued up to then are handled
+///
+///Returns a tuple of the handle of the thread, and a Sender that the thread listens to messages on
+pub fn spawn_callback_thread (socket: Option<UdpSocket>, poll: Duration, stop: Shutdown) -> (JoinHandle<()>, Sender<Callback>) {
+    let (tx, rx) = channel();
     //there are a lot of threads going on. we could just do stuff from the state thread but at
     //least there's modularity this way
-    let response_socket = bind.try_clone().unwrap();
-    let _ = thread::spawn(move || {
-        let mut req_map:HashMap<[u8; 20], Vec<SocketAddr>> = HashMap::new();
+    let thread = thread::spawn(move || {
+        let mut req_map:HashMap<RequestId, Requester<ReadResult>> = HashMap::new();
+        let mut write_map:HashMap<RequestId, Requester<WriteResult>> = HashMap::new();
         loop {
-            match rx.recv().unwrap() {
-                Callback::Register(key, src) =>  {
-                    let res = match req_map.entry(key) {
-                        Vacant(entry) => entry.insert(Vec::new()),
-                        Occupied(entry) => entry.into_mut()
-                    };
-                    res.push(src);
-                    //storing
-                    //println!("recv {} from {:?}", as_hex_string(&key), src);
+            let callback = match rx.recv_timeout(poll) {
+                Ok(callback) => callback,
+                Err(RecvTimeoutError::Timeout) if !stop.is_triggered() => continue,
+                Err(_) => break
+            };
+            match callback {
+                Callback::Register(id, requester) =>  {
+                    req_map.insert(id, requester);
                 },
-                Callback::Resolve(key, val) => {
-                    if let Some(vec) = req_map.remove(&key) {
-                        for addr in vec {
-                            let _ = response_socket.send_to(&val, addr);
-                        }
+                Callback::Resolve(id, result) => {
+                    match req_map.remove(&id) {
+                        Some(Requester::Udp(addr)) => {
+                            if let Some(ref sock) = socket {
+                                let _ = sock.send_to(&result.encode(), addr);
+                            }
+                        },
+                        Some(Requester::Local(sender)) => {
+                            let _ = sender.send(result);
+                        },
+                        None => ()
+                    }
+                },
+                Callback::RegisterWrite(id, requester) => {
+                    write_map.insert(id, requester);
+                },
+                Callback::Written(id, status, acks) => {
+                    match write_map.remove(&id) {
+                        Some(Requester::Udp(addr)) => {
+                            if let Some(ref sock) = socket {
+                                let status_byte = status.map(|s| s.code()).unwrap_or(STATUS_UNAVAILABLE);
+                                let _ = sock.send_to(&[status_byte, acks.min(255) as u8], addr);
+                            }
+                        },
+                        Some(Requester::Local(sender)) => {
+                            let _ = sender.send(WriteResult {status, acks});
+                        },
+                        None => ()
                     }
-
                 }
             }
         }
     });
-
-    (request_thread, tx_clone)
+    (thread, tx)
 }
@@ -0,0 +1,91 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use utils::fmt::from_hex_string;
+
+/// bytes of the HMAC-SHA256 trailing every datagram of a cluster with a pre-shared key
+pub const MAC_LEN: usize = 16;
+/// shortest pre-shared key accepted, in bytes
+pub const MIN_KEY_LEN: usize = 16;
+
+/// A pre-shared key, used until it expires (a unix time in seconds) if it does
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterKey {
+    pub secret: Vec<u8>,
+    pub expires: Option<i64>
+}
+
+/// The pre-shared keys of a private cluster. Every datagram a node sends carries an HMAC under the
+/// first key that has not expired, and datagrams are accepted under any key that has not. Keys
+/// are rotated by giving every node `old@expiry,new`: until the expiry the nodes still sign with
+/// the old key but already accept the new one, so nodes can be restarted one at a time
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterAuth {
+    keys: Vec<ClusterKey>
+}
+
+impl ClusterAuth {
+    pub fn new (keys: Vec<ClusterKey>) -> Result<ClusterAuth, String> {
+        if keys.is_empty() {
+            return Err("a cluster needs at least one key".to_string())
+        }
+        if keys.iter().any(|k| k.secret.len() < MIN_KEY_LEN) {
+            return Err(format!("cluster keys must be at least {} bytes", MIN_KEY_LEN))
+        }
+        Ok(ClusterAuth {keys})
+    }
+
+    /// Parses a comma separated list of hex encoded keys, each optionally followed by @ and the
+    /// unix time it expires at, e.g. `<old key>@1700000000,<new key>`
+    pub fn parse (spec: &str) -> Result<ClusterAuth, String> {
+        let keys = spec.split(',').map(|k| k.trim()).filter(|k| !k.is_empty()).map(|k| {
+            let mut parts = k.splitn(2, '@');
+            let secret = parts.next().and_then(from_hex_string).ok_or_else(|| format!("invalid cluster key {}, expected hex", k))?;
+            let expires = match parts.next() {
+                Some(t) => Some(t.parse::<i64>().map_err(|_| format!("invalid expiry in {}, expected a unix time", k))?),
+                None => None
+            };
+            Ok(ClusterKey {secret, expires})
+        }).collect::<Result<Vec<ClusterKey>, String>>()?;
+        ClusterAuth::new(keys)
+    }
+
+    pub fn keys (&self) -> &[ClusterKey] {
+        &self.keys
+    }
+
+    fn live (&self, now: i64) -> impl Iterator<Item = &ClusterKey> {
+        self.keys.iter().filter(move |k| k.expires.is_none_or(|t| now < t))
+    }
+
+    /// msg followed by its HMAC under the signing key. Once every key expired the last one signs
+    pub fn seal (&self, msg: &[u8], now: i64) -> Vec<u8> {
+        let key = self.live(now).next().unwrap_or_else(|| self.keys.last().unwrap());
+        let mut sealed = Vec::with_capacity(msg.len() + MAC_LEN);
+        sealed.extend_from_slice(msg);
+        sealed.extend_from_slice(&mac(&key.secret, msg));
+        sealed
+    }
+
+    /// the message of datagram, None if its HMAC is not that of any key that has not expired
+    pub fn open <'a> (&self, datagram: &'a [u8], now: i64) -> Option<&'a [u8]> {
+        if datagram.len() < MAC_LEN {
+            return None
+        }
+        let (msg, tag) = datagram.split_at(datagram.len() - MAC_LEN);
+        if self.live(now).any(|k| fixed_time_eq(&mac(&k.secret, msg), tag)) {
+            Some(msg)
+        } else {
+            None
+        }
+    }
+}
+
+fn mac (secret: &[u8], msg: &[u8]) -> [u8; MAC_LEN] {
+    let mut hmac = Hmac::new(Sha256::new(), secret);
+    hmac.input(msg);
+    let mut tag = [0; MAC_LEN];
+    tag.copy_from_slice(&hmac.result().code()[..MAC_LEN]);
+    tag
+}
@@ -1,59 +1,84 @@
 extern crate ailmedak;
 use std::env;
-use std::net::UdpSocket;
+use std::io::{Read, Write};
+use std::net::{TcpStream, UdpSocket};
 use std::mem;
-use std::thread;
+use std::time::Duration;
+use ailmedak::message_protocol::StoreStatus;
+use ailmedak::api_layer::{READ_FOUND, READ_NOT_FOUND, READ_QUORUM_FAILED, REQUEST_MALFORMED, TRACE_FLAG, hash_key};
+use ailmedak::storage::version::VectorClock;
+use ailmedak::utils::fmt::{as_hex_string, from_hex_string};
+
+const REPLY_TIMEOUT_SECS: u64 = 5;
 
 /// Basic cmd line tool to get and/or set from an ailmedak cluster
 ///
 /// usage:
-/// $ ./client get <key> <entry address> <local_port>
-/// $ ./client set <key> <val> <entry address> <local_port>
+/// $ ./client get <key> <entry address> <local_port> [read quorum]
+/// $ ./client set <key> <val> <entry address> <local_port> [write quorum] [context]
+/// $ ./client del <key> <entry address> <local_port> [write quorum] [context]
+/// $ ./client admin <node|buckets|keys|lookups|traces|config> <admin address>
+///
+/// get, set and del take `--trace <admin address>` anywhere after the method to trace the lookup
+/// of the request and print the trace from the admin port of the node once it is answered
+///
+/// get prints every sibling of the value along with the causal context (in hex) to pass to a set
+/// that resolves them
+///
+/// admin prints the JSON the admin port of a node reports
 ///
 /// ex:
 /// $ ./client get hello 127.0.0.1:5000 5999
+/// $ ./client set hello world 127.0.0.1:5000 5999 2
+/// $ ./client set hello merged 127.0.0.1:5000 5999 0 0001...
+/// $ ./client get hello 127.0.0.1:5000 5999 --trace 127.0.0.1:7000
+/// $ ./client admin buckets 127.0.0.1:7000
 
 fn main () {
-    let method = env::args().nth(1).unwrap();
+    let method = args().nth(1).unwrap();
     //hastily written byte manipulations
     match method.as_ref() {
         "get" => {
-            let key = env::args().nth(2).unwrap();
-            let addr = env::args().nth(3).unwrap();
-            let binding = format!("0.0.0.0:{}", env::args().nth(4).unwrap().parse::<u16>().unwrap());
+            let key = args().nth(2).unwrap();
+            let addr = args().nth(3).unwrap();
+            let binding = format!("0.0.0.0:{}", args().nth(4).unwrap().parse::<u16>().unwrap());
             let local_binding:&str = binding.as_ref();
             let sock = UdpSocket::bind(local_binding).unwrap();
-            let rec_sock = sock.try_clone().unwrap();
-
-            let handle = thread::spawn(move || {
-                loop {
-                    let mut buf = [0; 60000];
-                    let (bytes_read, _) = rec_sock.recv_from(&mut buf).unwrap();
-                    println!("{:?}", &buf[..bytes_read]);
-                }
-            });
 
-            let mut msg = vec![0];
+            let mut msg = vec![opcode(0)];
             let key_as_bytes = key.into_bytes();
             let len_as_bytes:[u8; 4] = unsafe{ mem::transmute((key_as_bytes.len() as u32).to_be())};
 
             msg.extend(len_as_bytes.iter().chain(key_as_bytes.iter()));
+            msg.push(quorum_arg(5));
             let addr_ref:&str = addr.as_ref();
             let _ = sock.send_to(&msg, addr_ref);
 
-            let _ = handle.join();
+            let _ = sock.set_read_timeout(Some(Duration::from_secs(REPLY_TIMEOUT_SECS)));
+            let mut buf = [0; 60000];
+            match sock.recv_from(&mut buf) {
+                Ok((bytes_read, _)) if bytes_read > 0 => match buf[0] {
+                    READ_FOUND => print_siblings(&buf[1..bytes_read]),
+                    READ_NOT_FOUND => println!("not found"),
+                    READ_QUORUM_FAILED => println!("failed: too few replicas answered"),
+                    REQUEST_MALFORMED => println!("rejected: {}", String::from_utf8_lossy(&buf[1..bytes_read])),
+                    _ => println!("unexpected reply {:?}", &buf[..bytes_read])
+                },
+                _ => println!("no reply received")
+            }
+            print_trace(&key_as_bytes);
         },
         "set" => {
-            let key = env::args().nth(2).unwrap();
+            let key = args().nth(2).unwrap();
             println!("key is: {}", key);
-            let val = env::args().nth(3).unwrap();
+            let val = args().nth(3).unwrap();
             println!("val is: {}", val);
-            let addr = env::args().nth(4).unwrap();
-            let binding = format!("0.0.0.0:{}", env::args().nth(5).unwrap().parse::<u16>().unwrap());
+            let addr = args().nth(4).unwrap();
+            let binding = format!("0.0.0.0:{}", args().nth(5).unwrap().parse::<u16>().unwrap());
             let local_binding:&str = binding.as_ref();
             let sock = UdpSocket::bind(local_binding).unwrap();
 
-            let mut msg = vec![1];
+            let mut msg = vec![opcode(1)];
             let key_as_bytes = key.into_bytes();
             println!("kab: {:?}", key_as_bytes);
             let val_as_bytes = val.into_bytes();
@@ -67,12 +92,145 @@ fn main () {
                 key_len_as_bytes.iter().chain(key_as_bytes.iter())
                                        .chain(val_len_as_bytes.iter())
                                        .chain(val_as_bytes.iter()));
+            msg.push(quorum_arg(6));
+            msg.extend(context_arg(7));
+
+            let addr_ref:&str = addr.as_ref();
+            let _ = sock.send_to(&msg, addr_ref);
+
+            print_write_ack(&sock, "stored");
+            print_trace(&key_as_bytes);
+        },
+        "del" => {
+            let key = args().nth(2).unwrap();
+            let addr = args().nth(3).unwrap();
+            let binding = format!("0.0.0.0:{}", args().nth(4).unwrap().parse::<u16>().unwrap());
+            let local_binding:&str = binding.as_ref();
+            let sock = UdpSocket::bind(local_binding).unwrap();
+
+            let mut msg = vec![opcode(2)];
+            let key_as_bytes = key.into_bytes();
+            msg.extend((key_as_bytes.len() as u32).to_be_bytes().iter().chain(key_as_bytes.iter()));
+            msg.push(quorum_arg(5));
+            msg.extend(context_arg(6));
 
             let addr_ref:&str = addr.as_ref();
             let _ = sock.send_to(&msg, addr_ref);
+            print_write_ack(&sock, "deleted");
+            print_trace(&key_as_bytes);
+        },
+        "admin" => {
+            let report = args().nth(2).unwrap();
+            let addr = args().nth(3).unwrap();
+            print_admin_report(&report, &addr);
         },
         _ => {
             println!("invalid usage");
         }
     }
 }
+
+/// the command line without the --trace option
+fn args () -> std::vec::IntoIter<String> {
+    let mut args = env::args().collect::<Vec<String>>();
+    if let Some(i) = args.iter().position(|a| a == "--trace") {
+        args.drain(i..(i + 2).min(args.len()));
+    }
+    args.into_iter()
+}
+
+/// the admin address given with --trace
+fn trace_arg () -> Option<String> {
+    env::args().skip_while(|a| a != "--trace").nth(1)
+}
+
+/// op, with the TRACE_FLAG set if the request is traced
+fn opcode (op: u8) -> u8 {
+    if trace_arg().is_some() { op | TRACE_FLAG } else { op }
+}
+
+/// prints the traces the admin port kept of lookups of key, if the request was traced
+fn print_trace (key: &[u8]) {
+    if let Some(admin) = trace_arg() {
+        print_admin_report(&format!("traces/{}", as_hex_string(&hash_key(key))), &admin);
+    }
+}
+
+/// fetches a report from the admin port at addr and prints its body
+fn print_admin_report (report: &str, addr: &str) {
+    let mut stream = match TcpStream::connect(addr) {
+        Ok(s) => s,
+        Err(e) => return println!("unable to connect: {}", e)
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(REPLY_TIMEOUT_SECS)));
+    let _ = write!(stream, "GET /{} HTTP/1.1\r\nHost: {}\r\n\r\n", report, addr);
+    let mut response = String::new();
+    if stream.read_to_string(&mut response).is_err() {
+        return println!("no reply received")
+    }
+    match response.find("\r\n\r\n") {
+        Some(i) if response.starts_with("HTTP/1.1 200") => println!("{}", &response[i+4..]),
+        _ => println!("failed: {}", response.lines().next().unwrap_or("malformed reply"))
+    }
+}
+
+/// the optional quorum argument at position n, 0 (the node's default) if it was left off
+fn quorum_arg (n: usize) -> u8 {
+    args().nth(n).map(|q| q.parse::<u8>().unwrap()).unwrap_or(0)
+}
+
+/// the optional causal context argument at position n, as it is sent after the quorum byte
+fn context_arg (n: usize) -> Vec<u8> {
+    args().nth(n).map(|c| from_hex_string(&c).expect("context must be hex")).unwrap_or_default()
+}
+
+/// prints the [context][count]([length][value])* body of a found value
+fn print_siblings (body: &[u8]) {
+    match parse_siblings(body) {
+        Some((context, siblings)) => {
+            if siblings.len() > 1 {
+                println!("conflict: {} siblings", siblings.len());
+            }
+            for val in siblings.iter() {
+                println!("{}", String::from_utf8_lossy(val));
+            }
+            println!("context: {}", as_hex_string(&context.encode()));
+        },
+        None => println!("malformed reply")
+    }
+}
+
+/// the context and siblings of a found value, None if a length runs past the end of the reply
+/// or something trails the last sibling
+fn parse_siblings (body: &[u8]) -> Option<(VectorClock, Vec<&[u8]>)> {
+    let (context, offset) = VectorClock::decode(body)?;
+    let count = body.get(offset..offset + 2)?;
+    let mut rest = &body[offset + 2..];
+    let mut siblings = Vec::new();
+    for _ in 0..u16::from_be_bytes([count[0], count[1]]) {
+        let len = rest.get(..4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        siblings.push(rest.get(4..4usize.checked_add(len)?)?);
+        rest = &rest[4 + len..];
+    }
+    if rest.is_empty() { Some((context, siblings)) } else { None }
+}
+
+/// waits for the [status][acks] reply to a set or delete and prints it
+fn print_write_ack (sock: &UdpSocket, verb: &str) {
+    let _ = sock.set_read_timeout(Some(Duration::from_secs(REPLY_TIMEOUT_SECS)));
+    let mut buf = [0; 256];
+    match sock.recv_from(&mut buf) {
+        Ok((n, _)) if n > 0 && buf[0] == REQUEST_MALFORMED => println!("rejected: {}", String::from_utf8_lossy(&buf[1..n])),
+        Ok((2, _)) => match StoreStatus::from_code(buf[0]) {
+            Some(StoreStatus::Ok) => println!("{} on {} replicas", verb, buf[1]),
+            Some(StoreStatus::TooLarge) => println!("rejected: value too large"),
+            Some(StoreStatus::QuotaExceeded) => println!("rejected: quota exceeded"),
+            Some(StoreStatus::InvalidSignature) => println!("rejected: invalid signature"),
+            Some(StoreStatus::Deleted) => println!("rejected: key was recently deleted"),
+            Some(StoreStatus::InvalidToken) => println!("rejected: invalid write token"),
+            None => println!("rejected: no replica available")
+        },
+        _ => println!("no acknowledgement received")
+    }
+}
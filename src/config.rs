@@ -1,9 +1,70 @@
+use storage::{FsyncPolicy, StoreLimits, EvictionPolicy, DEFAULT_VALUE_TTL, DEFAULT_TOMBSTONE_TTL};
+use node::handoff::DEFAULT_HANDOFF_BATCH;
+use utils::loggerator::LogConfig;
+use ratelimit::{RateLimits, Rate};
+use node::diversity::DiversityLimits;
+use node::tokens::DEFAULT_TOKEN_ROTATION;
+use auth::ClusterAuth;
+use transport::Encryption;
+
 pub struct Config {
     pub network_port: u16,
     pub api_port: Option<u16>,
+    //port serving metrics over HTTP, none are served if None
+    pub metrics_port: Option<u16>,
+    //localhost port serving the state of the node as JSON over HTTP, none is served if None
+    pub admin_port: Option<u16>,
     pub k_val: usize,
     pub async_poll_interval: u32,
-    pub initial_neighbors: Vec<String>
+    pub initial_neighbors: Vec<String>,
+    //directory holding the write ahead log and snapshots. values are kept in memory only if None
+    pub data_dir: Option<String>,
+    pub fsync_policy: FsyncPolicy,
+    //seconds a stored value lives for before it expires
+    pub value_ttl: i64,
+    //seconds a deleted key is kept from being stored again by other nodes
+    pub tombstone_ttl: i64,
+    //seconds between saving the routing table to data_dir
+    pub routing_snapshot_interval: i64,
+    //seconds between anti-entropy exchanges with one of the neighboring replicas
+    pub anti_entropy_interval: i64,
+    //keys handed off to newly joined nodes per poll interval
+    pub handoff_batch: usize,
+    //seconds a leaving node spends handing off its keys before it gives up on the rest
+    pub leave_timeout: i64,
+    //bounds on what other nodes and clients may store on this node
+    pub store_limits: StoreLimits,
+    //what to evict once store_limits.max_bytes or max_keys is reached
+    pub eviction_policy: EvictionPolicy,
+    //number of nodes a value is written to (N)
+    pub replication_factor: usize,
+    //default number of replicas that must answer a get (R)
+    pub read_quorum: usize,
+    //default number of replicas that must acknowledge a set or delete (W)
+    pub write_quorum: usize,
+    //seconds between rotations of the secret write tokens are derived from. a token is good for
+    //at least this long
+    pub token_rotation: i64,
+    //pre-shared keys of a private cluster: only nodes holding one are heard. open if None
+    pub cluster_auth: Option<ClusterAuth>,
+    //whether messages between nodes are encrypted in per peer sessions
+    pub encryption: Encryption,
+    //caps on contacts from the same ip or network prefix in the k-buckets
+    pub diversity: DiversityLimits,
+    //levels, format and destination of the node's log
+    pub log: LogConfig,
+    //messages any one source ip may send the protocol socket, by opcode
+    pub protocol_limits: RateLimits,
+    //requests any one source ip may send the api port, by opcode
+    pub api_limits: RateLimits
+}
+
+/// the N, R and W of a node. clients may override R and W per request
+#[derive(Clone, Copy, Debug)]
+pub struct Replication {
+    pub n: usize,
+    pub r: usize,
+    pub w: usize
 }
 
 impl Config {
@@ -13,9 +74,54 @@ impl Config {
     Config {
         network_port: port,
         api_port: None,
+        metrics_port: None,
+        admin_port: None,
         k_val: 8,
         async_poll_interval: 300,
-        initial_neighbors: vec![]
+        initial_neighbors: vec![],
+        data_dir: None,
+        fsync_policy: FsyncPolicy::Periodic,
+        value_ttl: DEFAULT_VALUE_TTL,
+        tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+        routing_snapshot_interval: 60,
+        anti_entropy_interval: 30,
+        handoff_batch: DEFAULT_HANDOFF_BATCH,
+        leave_timeout: 60,
+        store_limits: StoreLimits::default(),
+        eviction_policy: EvictionPolicy::Lru,
+        replication_factor: 8,
+        read_quorum: 1,
+        write_quorum: 1,
+        token_rotation: DEFAULT_TOKEN_ROTATION,
+        cluster_auth: None,
+        encryption: Encryption::Off,
+        diversity: DiversityLimits::default(),
+        log: LogConfig::default(),
+        protocol_limits: RateLimits::with_default(Rate::new(500.0, 1000.0)),
+        api_limits: RateLimits::with_default(Rate::new(200.0, 400.0))
+    }
+  }
+
+  //the N, R and W of the node. a quorum over the replication factor could never be met, so it is
+  //clamped to it
+  pub fn replication(&self) -> Replication {
+    let n = self.replication_factor.max(1);
+    Replication {
+        n,
+        r: self.read_quorum.clamp(1, n),
+        w: self.write_quorum.clamp(1, n)
     }
   }
 }
+
+impl Replication {
+    /// the R of a get that asked for quorum, the default if it did not. between 1 and N
+    pub fn read_quorum(&self, quorum: Option<usize>) -> usize {
+        quorum.unwrap_or(self.r).clamp(1, self.n)
+    }
+
+    /// the W of a set or delete that asked for quorum, the default if it did not. between 1 and N
+    pub fn write_quorum(&self, quorum: Option<usize>) -> usize {
+        quorum.unwrap_or(self.w).clamp(1, self.n)
+    }
+}
@@ -1,6 +1,7 @@
 extern crate crypto;
 extern crate rand;
 extern crate time;
+extern crate signal_hook;
 
 #[macro_use]
 pub mod utils;
@@ -9,3 +10,9 @@ pub mod node;
 pub mod message_protocol;
 pub mod api_layer;
 pub mod config;
+pub mod storage;
+pub mod metrics;
+pub mod admin;
+pub mod ratelimit;
+pub mod auth;
+pub mod transport;
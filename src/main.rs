@@ -3,8 +3,16 @@ extern crate getopts;
 
 use ailmedak::node::machine::{AilmedakMachine};
 use ailmedak::config::Config;
+use ailmedak::storage::{FsyncPolicy, StoreLimits, EvictionPolicy};
+use ailmedak::utils::loggerator::LogFormat;
+use ailmedak::message_protocol::MESSAGE_NAMES;
+use ailmedak::api_layer::REQUEST_NAMES;
+use ailmedak::node::diversity::Cap;
+use ailmedak::auth::ClusterAuth;
+use ailmedak::transport::Encryption;
 use std::env;
-use getopts::Options;
+use std::fs;
+use getopts::{Options, Matches};
 
 const DEFAULT_NETPORT:u16 = 3000;
 
@@ -15,6 +23,31 @@ fn main () {
 
     opts.optopt("p", "port", "port for internal ailmedak protocols", "PORTNUM");
     opts.optopt("a", "api-port", "client port", "PORTNUM");
+    opts.optopt("", "metrics-port", "port serving prometheus metrics over http", "PORTNUM");
+    opts.optopt("", "admin-port", "localhost port serving the state of the node as json over http", "PORTNUM");
+    opts.optopt("d", "data-dir", "directory to persist stored values in", "PATH");
+    opts.optopt("", "fsync", "when to flush the write ahead log: always, periodic or never", "POLICY");
+    opts.optopt("", "max-bytes", "total bytes of values to hold", "BYTES");
+    opts.optopt("", "max-keys", "number of keys to hold", "COUNT");
+    opts.optopt("", "max-value-size", "largest value to accept", "BYTES");
+    opts.optopt("", "source-quota", "bytes any one source ip may store", "BYTES");
+    opts.optopt("", "eviction", "what to evict when full: lru, farthest or expiry", "POLICY");
+    opts.optopt("", "cluster-key-file", "file holding the hex pre-shared keys of a private cluster, as KEY[@EXPIRY],KEY", "PATH");
+    opts.optopt("", "encryption", "whether messages to other nodes are encrypted: off, preferred or required", "MODE");
+    opts.optopt("", "replication", "number of nodes a value is written to (N)", "COUNT");
+    opts.optopt("", "read-quorum", "default number of replicas that must answer a get (R), at most N", "COUNT");
+    opts.optopt("", "write-quorum", "default number of replicas that must acknowledge a write (W), at most N", "COUNT");
+    opts.optopt("", "token-rotation", "seconds between rotations of the secret write tokens derive from", "SECS");
+    opts.optopt("", "ip-cap", "contacts from one ip per k-bucket and in the routing table, either may be none", "BUCKET:TABLE");
+    opts.optopt("", "prefix-cap", "contacts from one /24 or /64 per k-bucket and in the routing table", "BUCKET:TABLE");
+    opts.optflag("", "cap-local", "cap loopback and private addresses too");
+    opts.optopt("", "rate-limit", "messages per second any one ip may send the cluster port, e.g. 500,store=50:100", "RATES");
+    opts.optopt("", "api-rate-limit", "requests per second any one ip may send the api port, e.g. 200,set=20", "RATES");
+    opts.optopt("", "ban-after", "drops after which an ip is banned, 0 never bans", "COUNT");
+    opts.optopt("", "ban-secs", "seconds a ban lasts", "SECS");
+    opts.optopt("", "log-level", "levels to log at, e.g. info or warn,api_layer=debug", "FILTER");
+    opts.optopt("", "log-format", "format of log lines: text or json", "FORMAT");
+    opts.optopt("", "log-file", "file to append the log to instead of stdout", "PATH");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -31,14 +64,119 @@ fn main () {
         None => None
     };
 
-    let configuration = Config {
-        network_port: port,
-        api_port: api_port_opt,
-        k_val: 8,
-        async_poll_interval: 300,
-        initial_neighbors: vec![]
+    let mut configuration = Config::default_with_port(port);
+    configuration.api_port = api_port_opt;
+    configuration.metrics_port = matches.opt_str("metrics-port").map(|s| match s.parse::<u16>() {
+        Ok(p) => p,
+        Err(_) => panic!("--metrics-port expects a port, got {}", s)
+    });
+    configuration.admin_port = matches.opt_str("admin-port").map(|s| match s.parse::<u16>() {
+        Ok(p) => p,
+        Err(_) => panic!("--admin-port expects a port, got {}", s)
+    });
+    configuration.data_dir = matches.opt_str("d");
+
+    if let Some(policy) = matches.opt_str("fsync") {
+        configuration.fsync_policy = match policy.parse::<FsyncPolicy>() {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e)
+        };
+    }
+
+    configuration.store_limits = StoreLimits {
+        max_bytes: opt_usize(&matches, "max-bytes"),
+        max_keys: opt_usize(&matches, "max-keys"),
+        max_value_size: opt_usize(&matches, "max-value-size"),
+        per_source_bytes: opt_usize(&matches, "source-quota")
     };
 
+    if let Some(policy) = matches.opt_str("eviction") {
+        configuration.eviction_policy = match policy.parse::<EvictionPolicy>() {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e)
+        };
+    }
+
+    if let Some(path) = matches.opt_str("cluster-key-file") {
+        let spec = fs::read_to_string(&path).unwrap_or_else(|e| panic!("unable to read {}: {}", path, e));
+        configuration.cluster_auth = Some(ClusterAuth::parse(&spec).unwrap_or_else(|e| panic!("{}", e)));
+    }
+
+    if let Some(mode) = matches.opt_str("encryption") {
+        configuration.encryption = mode.parse::<Encryption>().unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    if let Some(n) = opt_usize(&matches, "replication") {
+        if n == 0 {
+            panic!("--replication expects at least 1 node")
+        }
+        configuration.replication_factor = n;
+    }
+    if let Some(r) = opt_usize(&matches, "read-quorum") {
+        configuration.read_quorum = r;
+    }
+    if let Some(w) = opt_usize(&matches, "write-quorum") {
+        configuration.write_quorum = w;
+    }
+    for (name, quorum) in [("read-quorum", configuration.read_quorum), ("write-quorum", configuration.write_quorum)] {
+        if quorum == 0 || quorum > configuration.replication_factor {
+            panic!("--{} expects 1 to the replication factor {}, got {}", name, configuration.replication_factor, quorum)
+        }
+    }
+
+    if let Some(secs) = opt_usize(&matches, "token-rotation") {
+        configuration.token_rotation = secs as i64;
+    }
+
+    if let Some(cap) = matches.opt_str("ip-cap") {
+        configuration.diversity.same_ip = cap.parse::<Cap>().unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(cap) = matches.opt_str("prefix-cap") {
+        configuration.diversity.same_prefix = cap.parse::<Cap>().unwrap_or_else(|e| panic!("{}", e));
+    }
+    configuration.diversity.exempt_local = !matches.opt_present("cap-local");
+
+    if let Some(spec) = matches.opt_str("rate-limit") {
+        if let Err(e) = configuration.protocol_limits.set_rates(&spec, &MESSAGE_NAMES) {
+            panic!("{}", e)
+        }
+    }
+    if let Some(spec) = matches.opt_str("api-rate-limit") {
+        if let Err(e) = configuration.api_limits.set_rates(&spec, &REQUEST_NAMES) {
+            panic!("{}", e)
+        }
+    }
+    if let Some(n) = opt_usize(&matches, "ban-after") {
+        let ban_after = if n == 0 { None } else { Some(n as u32) };
+        configuration.protocol_limits.ban_after = ban_after;
+        configuration.api_limits.ban_after = ban_after;
+    }
+    if let Some(secs) = opt_usize(&matches, "ban-secs") {
+        configuration.protocol_limits.ban_secs = secs as u64;
+        configuration.api_limits.ban_secs = secs as u64;
+    }
+
+    if let Some(filter) = matches.opt_str("log-level") {
+        if let Err(e) = configuration.log.set_filter(&filter) {
+            panic!("{}", e)
+        }
+    }
+
+    if let Some(format) = matches.opt_str("log-format") {
+        configuration.log.format = match format.parse::<LogFormat>() {
+            Ok(f) => f,
+            Err(e) => panic!("{}", e)
+        };
+    }
+    configuration.log.file = matches.opt_str("log-file");
+
     AilmedakMachine::start(configuration, None);
 
 }
+
+fn opt_usize (matches: &Matches, name: &str) -> Option<usize> {
+    matches.opt_str(name).map(|s| match s.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => panic!("--{} expects a number, got {}", name, s)
+    })
+}
@@ -5,9 +5,16 @@ use std::mem::transmute;
 use std::mem;
 use std::fmt;
 use std::fmt::{Formatter, Debug};
+use std::convert::{TryFrom, TryInto};
+use std::iter::Map;
+use std::slice::ChunksExact;
 use node::state::NodeAddr;
 use utils::{u8_2_to_u16, u8_4_to_u32};
 use utils::fmt::as_hex_string;
+use storage::version::{VectorClock, ClockView};
+use auth::ClusterAuth;
+use transport::Sessions;
+use time::get_time;
 
 #[derive(PartialEq, Clone, Copy)]
 pub struct NodeContact<K> {
@@ -22,38 +29,180 @@ impl Debug for NodeContact<NodeAddr> {
     }
 }
 
+impl Debug for NodeContact<&NodeAddr> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{{ id: {}, ip: {:?}, port: {} }}", as_hex_string(self.id), &self.ip, &self.port)
+    }
+}
+
+/// The outcome of a Store, as reported back in a StoreResp
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum StoreStatus {
+    Ok,
+    TooLarge,
+    QuotaExceeded,
+    InvalidSignature,
+    //the key was deleted and may not be stored again for now
+    Deleted,
+    //the store did not carry a write token this node gave its source lately
+    InvalidToken
+}
+
+impl StoreStatus {
+    pub fn code (&self) -> u8 {
+        match *self {
+            StoreStatus::Ok => 0,
+            StoreStatus::TooLarge => 1,
+            StoreStatus::QuotaExceeded => 2,
+            StoreStatus::InvalidSignature => 3,
+            StoreStatus::Deleted => 4,
+            StoreStatus::InvalidToken => 5
+        }
+    }
+
+    pub fn from_code (code: u8) -> Option<StoreStatus> {
+        match code {
+            0 => Some(StoreStatus::Ok),
+            1 => Some(StoreStatus::TooLarge),
+            2 => Some(StoreStatus::QuotaExceeded),
+            3 => Some(StoreStatus::InvalidSignature),
+            4 => Some(StoreStatus::Deleted),
+            5 => Some(StoreStatus::InvalidToken),
+            _ => None
+        }
+    }
+}
+
+//opcodes, as they appear in the first byte of a message
+pub const OP_PING: u8 = 0;
+pub const OP_PING_RESP: u8 = 1;
+pub const OP_STORE: u8 = 2;
+pub const OP_FIND_NODE: u8 = 3;
+pub const OP_FIND_VAL: u8 = 4;
+pub const OP_FIND_NODE_RESP: u8 = 5;
+pub const OP_FIND_VAL_RESP: u8 = 6;
+pub const OP_STORE_RESP: u8 = 7;
+pub const OP_ERROR: u8 = 8;
+pub const OP_DELETE: u8 = 9;
+pub const OP_SYNC_TREE: u8 = 10;
+pub const OP_SYNC_KEYS: u8 = 11;
+pub const OP_LEAVE: u8 = 12;
+pub const OP_HANDSHAKE: u8 = 13;
+pub const OP_HANDSHAKE_RESP: u8 = 14;
+/// not a message but the frame of one encrypted in a session between nodes, see transport
+pub const OP_SEALED: u8 = 15;
+
+/// the name of the Message variant an opcode stands for
+pub fn message_name (opcode: u8) -> Option<&'static str> {
+    MESSAGE_NAMES.get(opcode as usize).cloned()
+}
+
+/// names of the message types, by opcode
+pub const MESSAGE_NAMES: [&str; 15] = ["Ping", "PingResp", "Store", "FindNode", "FindVal", "FindNodeResp", "FindValResp",
+                                       "StoreResp", "Error", "Delete", "SyncTree", "SyncKeys", "Leave", "Handshake",
+                                       "HandshakeResp"];
+
+/// most (index, hash) pairs a SyncTree carries, to stay within a datagram
+pub const MAX_SYNC_NODES: usize = 160;
+/// most (key, digest) pairs a SyncKeys carries. a leaf with more keys is only partly synced
+pub const MAX_SYNC_KEYS: usize = 96;
+
 #[derive(PartialEq)]
 pub enum Message <K, V> { 
     //out
     Ping,
-    Store(K, V),
+    //a version of a value, with its vector clock and the write token the receiver gave the sender
+    Store(K, VectorClock, V, Token),
     FindNode(K),
     FindVal(K),
+    //deletes the versions the clock descends from. it needs a write token like a Store
+    Delete(K, VectorClock, Token),
     //acks
     PingResp,
-    FindNodeResp(K, Vec<NodeContact<K>>),
-    FindValResp(K, V)
+    //the closest contacts known and a write token for the requester
+    FindNodeResp(K, Vec<NodeContact<K>>, Token),
+    //every sibling held for the key and a write token for the requester
+    FindValResp(K, Vec<(VectorClock, V)>, Token),
+    //acknowledges a Store or a Delete
+    StoreResp(K, StoreStatus),
+    //the opcode of the request that failed and why
+    Error(u8, String),
+    //anti-entropy: Merkle tree nodes (index, hash) of the sender to compare against
+    SyncTree(Vec<(u16, K)>),
+    //anti-entropy: the keys of a Merkle leaf with their digests, and whether the sender wants the
+    //receiver's keys of the leaf in return
+    SyncKeys(u16, bool, Vec<(K, K)>),
+    //the sender is leaving the network and should be dropped from the k-buckets
+    Leave,
+    //starts an encrypted session: the sender's index for it, ephemeral key and static key
+    Handshake(u32, [u8; 32], [u8; 32]),
+    //accepts a Handshake: the initiator's index, the responder's index, ephemeral key and static key
+    HandshakeResp(u32, u32, [u8; 32], [u8; 32])
+}
+
+impl <K, V> Message<K, V> {
+    pub fn opcode (&self) -> u8 {
+        match *self {
+            Message::Ping => OP_PING,
+            Message::PingResp => OP_PING_RESP,
+            Message::Store(..) => OP_STORE,
+            Message::FindNode(..) => OP_FIND_NODE,
+            Message::FindVal(..) => OP_FIND_VAL,
+            Message::FindNodeResp(..) => OP_FIND_NODE_RESP,
+            Message::FindValResp(..) => OP_FIND_VAL_RESP,
+            Message::StoreResp(..) => OP_STORE_RESP,
+            Message::Error(..) => OP_ERROR,
+            Message::Delete(..) => OP_DELETE,
+            Message::SyncTree(..) => OP_SYNC_TREE,
+            Message::SyncKeys(..) => OP_SYNC_KEYS,
+            Message::Leave => OP_LEAVE,
+            Message::Handshake(..) => OP_HANDSHAKE,
+            Message::HandshakeResp(..) => OP_HANDSHAKE_RESP
+        }
+    }
 }
 
 impl Debug for Message<NodeAddr, Vec<u8>> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self {
-            &Message::Ping => write!(f, "Ping"),
-            &Message::PingResp => write!(f, "PingResp"),
-            &Message::Store(ref k, ref v) => {
-                write!(f, "Store({}, {:?})", as_hex_string(k), v)
+        match *self {
+            Message::Ping => write!(f, "Ping"),
+            Message::PingResp => write!(f, "PingResp"),
+            Message::Store(ref k, ref c, ref v, ref t) => {
+                write!(f, "Store({}, {:?}, {:?}, {})", as_hex_string(k), c, v, as_hex_string(t))
             },
-            &Message::FindNode(ref k) => {
+            Message::FindNode(ref k) => {
                 write!(f, "FindNode({})", as_hex_string(k))
             },
-            &Message::FindNodeResp(ref a, ref b) => {
-                write!(f, "FindNodeResp({}, {:?})", as_hex_string(a), b)
+            Message::FindNodeResp(ref a, ref b, ref t) => {
+                write!(f, "FindNodeResp({}, {:?}, {})", as_hex_string(a), b, as_hex_string(t))
             },
-            &Message::FindVal(ref k) => {
+            Message::FindVal(ref k) => {
                 write!(f, "FindVal({})", as_hex_string(k))
             },
-            &Message::FindValResp(ref k, ref v) => {
-                write!(f, "FindValResp({}, {:?})", as_hex_string(k), v)
+            Message::Delete(ref k, ref c, ref t) => {
+                write!(f, "Delete({}, {:?}, {})", as_hex_string(k), c, as_hex_string(t))
+            },
+            Message::FindValResp(ref k, ref v, ref t) => {
+                write!(f, "FindValResp({}, {:?}, {})", as_hex_string(k), v, as_hex_string(t))
+            },
+            Message::StoreResp(ref k, ref status) => {
+                write!(f, "StoreResp({}, {:?})", as_hex_string(k), status)
+            },
+            Message::Error(ref opcode, ref reason) => {
+                write!(f, "Error({}, {:?})", opcode, reason)
+            },
+            Message::SyncTree(ref nodes) => {
+                write!(f, "SyncTree({:?})", nodes.iter().map(|&(i, _)| i).collect::<Vec<u16>>())
+            },
+            Message::SyncKeys(ref leaf, ref reply, ref keys) => {
+                write!(f, "SyncKeys({}, {}, {} keys)", leaf, reply, keys.len())
+            },
+            Message::Leave => write!(f, "Leave"),
+            Message::Handshake(ref index, _, ref s) => {
+                write!(f, "Handshake({}, {})", index, as_hex_string(s))
+            },
+            Message::HandshakeResp(ref initiator, ref responder, _, ref s) => {
+                write!(f, "HandshakeResp({}, {}, {})", initiator, responder, as_hex_string(s))
             }
         }
     }
@@ -89,6 +238,18 @@ const KEYSIZE:usize = 20;
 
 pub type Key = [u8; 20];
 pub type Value = Vec<u8>;
+/// one version of a value as it travels between nodes
+pub type Sibling = (VectorClock, Value);
+
+pub const TOKEN_LEN: usize = 8;
+/// a write token, handed out in FindNode and FindVal responses and required on Stores and Deletes
+pub type Token = [u8; TOKEN_LEN];
+/// a token that is not one, for writes whose token is filled in later
+pub const NO_TOKEN: Token = [0; TOKEN_LEN];
+/// where the token of a Store or Delete starts: after the opcode, sender id, length and key
+pub const STORE_TOKEN_OFFSET: usize = 1 + 20 + 4 + KEYSIZE;
+/// bytes of a sealed frame before the ciphertext: opcode, sender id, length, index and counter
+pub const SEALED_HEADER_LEN: usize = 1 + 20 + 4 + 4 + 8;
 
 pub trait ProtoMessage {
     fn id (&self) -> &Key;
@@ -113,13 +274,17 @@ pub trait ProtoMessage {
         bytes
     }
 
-    fn store_msg (&self, key: &Key, val: &[u8]) -> Vec<u8> {
-        let mut vec = Vec::with_capacity(key.len() + val.len() + 5);
-        let payload_size: [u8; 4] = unsafe{transmute(((key.len() + val.len()) as u32).to_be())};
-        vec.extend([2].iter().chain(self.id().iter())
-                      .chain(payload_size.iter())
-                      .chain(key.iter())
-                      .chain(val.iter()));
+    /// [2][id][len][key][token: 8][clock][value]
+    fn store_msg (&self, key: &Key, clock: &VectorClock, val: &[u8], token: &Token) -> Vec<u8> {
+        let payload_size = key.len() + TOKEN_LEN + clock.encoded_len() + val.len();
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + payload_size);
+        vec.push(OP_STORE);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&(payload_size as u32).to_be_bytes());
+        vec.extend_from_slice(key);
+        vec.extend_from_slice(token);
+        clock.encode_into(&mut vec);
+        vec.extend_from_slice(val);
         vec
     }
 
@@ -148,105 +313,560 @@ pub trait ProtoMessage {
         ret
     }
 
-    fn find_node_resp (&self, closest: &Vec<(Key, (Key, ([u8; 4], [u8; 2])))>, key: &Key) -> Vec<u8> {
+    /// [5][id][len][key][token: 8]([id: 20][ip: 4][port: 2])*
+    fn find_node_resp (&self, closest: &Vec<(Key, (Key, ([u8; 4], [u8; 2])))>, key: &Key, token: &Token) -> Vec<u8> {
         let payload_size = (mem::size_of::<Key>() + 6) * closest.len();
-        let mut vec = Vec::with_capacity(payload_size + key.len() + 5 + 20);
-        let bytes: [u8; 4] = unsafe {transmute(((payload_size + key.len()) as u32 ).to_be())};
+        let mut vec = Vec::with_capacity(payload_size + key.len() + TOKEN_LEN + 5 + 20);
+        let bytes: [u8; 4] = unsafe {transmute(((payload_size + key.len() + TOKEN_LEN) as u32 ).to_be())};
         vec.extend(
             [5].iter().chain(self.id().iter())
                       .chain(bytes.iter())
-                      .chain(key.iter()));
+                      .chain(key.iter())
+                      .chain(token.iter()));
         vec.extend(closest.iter().flat_map(|&(_, (ref a, (ref b, ref c)))| {
             a.iter().chain(b.iter()).chain(c.iter())
         }).map(|b| *b));
         vec
     }
 
-    fn find_val_resp (&self, key: &[u8; 20], val: &[u8]) -> Vec<u8> {
-        let pure_payload = key.len() + val.len();
-        let mut vec:Vec<u8> = Vec::with_capacity(pure_payload + 5 + 20);
-        let len:[u8; 4] = unsafe { transmute(((key.len() + val.len()) as u32).to_be())};
+    /// [6][id][len][key][token: 8][count: 2]([clock][value len: 4][value])*
+    fn find_val_resp (&self, key: &[u8; 20], siblings: &[Sibling], token: &Token) -> Vec<u8> {
+        let payload_size = key.len() + TOKEN_LEN + 2 + siblings.iter().map(|(c, v)| c.encoded_len() + 4 + v.len()).sum::<usize>();
+        let mut vec:Vec<u8> = Vec::with_capacity(1 + 20 + 4 + payload_size);
+        vec.push(OP_FIND_VAL_RESP);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&(payload_size as u32).to_be_bytes());
+        vec.extend_from_slice(key);
+        vec.extend_from_slice(token);
+        vec.extend_from_slice(&(siblings.len() as u16).to_be_bytes());
+        for (clock, val) in siblings.iter() {
+            clock.encode_into(&mut vec);
+            vec.extend_from_slice(&(val.len() as u32).to_be_bytes());
+            vec.extend_from_slice(val);
+        }
+        vec
+    }
 
-        vec.extend(
-            [6].iter().chain(self.id().iter())
-                      .chain(len.iter())
-                      .chain(key.iter())
-                      .chain(val.iter()));
+    /// [9][id][len][key][token: 8][clock]
+    fn delete_msg (&self, key: &Key, clock: &VectorClock, token: &Token) -> Vec<u8> {
+        let payload_size = key.len() + TOKEN_LEN + clock.encoded_len();
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + payload_size);
+        vec.push(OP_DELETE);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&(payload_size as u32).to_be_bytes());
+        vec.extend_from_slice(key);
+        vec.extend_from_slice(token);
+        clock.encode_into(&mut vec);
+        vec
+    }
+
+    fn store_resp_msg (&self, key: &Key, status: StoreStatus) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + key.len() + 1);
+        vec.push(OP_STORE_RESP);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&((key.len() + 1) as u32).to_be_bytes());
+        vec.extend_from_slice(key);
+        vec.push(status.code());
+        vec
+    }
+
+    fn error_msg (&self, failed_opcode: u8, reason: &str) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + 1 + reason.len());
+        vec.push(OP_ERROR);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&((reason.len() + 1) as u32).to_be_bytes());
+        vec.push(failed_opcode);
+        vec.extend_from_slice(reason.as_bytes());
+        vec
+    }
+
+    /// [10][id][len][count: 2]([index: 2][hash: 20])*
+    fn sync_tree_msg (&self, nodes: &[(u16, Key)]) -> Vec<u8> {
+        let payload_size = 2 + nodes.len() * (2 + KEYSIZE);
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + payload_size);
+        vec.push(OP_SYNC_TREE);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&(payload_size as u32).to_be_bytes());
+        vec.extend_from_slice(&(nodes.len() as u16).to_be_bytes());
+        for (index, hash) in nodes.iter() {
+            vec.extend_from_slice(&index.to_be_bytes());
+            vec.extend_from_slice(hash);
+        }
+        vec
+    }
+
+    /// [11][id][len][leaf: 2][reply: 1][count: 2]([key: 20][digest: 20])*
+    fn sync_keys_msg (&self, leaf: u16, reply: bool, keys: &[(Key, Key)]) -> Vec<u8> {
+        let payload_size = 2 + 1 + 2 + keys.len() * 2 * KEYSIZE;
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + payload_size);
+        vec.push(OP_SYNC_KEYS);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&(payload_size as u32).to_be_bytes());
+        vec.extend_from_slice(&leaf.to_be_bytes());
+        vec.push(reply as u8);
+        vec.extend_from_slice(&(keys.len() as u16).to_be_bytes());
+        for (key, digest) in keys.iter() {
+            vec.extend_from_slice(key);
+            vec.extend_from_slice(digest);
+        }
+        vec
+    }
+
+    /// [12][id][len: 0]
+    fn leave_msg (&self) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(1 + 20 + 4);
+        vec.push(OP_LEAVE);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&0u32.to_be_bytes());
+        vec
+    }
+
+    /// [13][id][len][index: 4][ephemeral: 32][static: 32]
+    fn handshake_msg (&self, index: u32, ephemeral: &[u8; 32], static_key: &[u8; 32]) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + 4 + 64);
+        vec.push(OP_HANDSHAKE);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&68u32.to_be_bytes());
+        vec.extend_from_slice(&index.to_be_bytes());
+        vec.extend_from_slice(ephemeral);
+        vec.extend_from_slice(static_key);
+        vec
+    }
+
+    /// [14][id][len][initiator index: 4][responder index: 4][ephemeral: 32][static: 32]
+    fn handshake_resp_msg (&self, initiator: u32, responder: u32, ephemeral: &[u8; 32], static_key: &[u8; 32]) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(1 + 20 + 4 + 8 + 64);
+        vec.push(OP_HANDSHAKE_RESP);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&72u32.to_be_bytes());
+        vec.extend_from_slice(&initiator.to_be_bytes());
+        vec.extend_from_slice(&responder.to_be_bytes());
+        vec.extend_from_slice(ephemeral);
+        vec.extend_from_slice(static_key);
+        vec
+    }
+
+    /// [15][id][len][receiver index: 4][counter: 8], followed by body_len bytes of ciphertext and tag
+    fn sealed_header (&self, index: u32, counter: u64, body_len: usize) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(SEALED_HEADER_LEN + body_len);
+        vec.push(OP_SEALED);
+        vec.extend_from_slice(self.id());
+        vec.extend_from_slice(&((12 + body_len) as u32).to_be_bytes());
+        vec.extend_from_slice(&index.to_be_bytes());
+        vec.extend_from_slice(&counter.to_be_bytes());
         vec
     }
 
 }
 
-fn key_cpy (key_addr: &[u8]) -> Key {
-    let mut key: Key = [0; 20]; //for now hardcode... later use macros
-    for (a, b) in key_addr.iter().zip(key.iter_mut()) {
-        *b = *a;
-    }
-    key
-}
-
-fn ip_cpy (key_addr: &[u8]) -> [u8; 4] {
-    let mut key:[u8; 4] = [0; 4]; //for now hardcode... later use macros
-    for (a, b) in key_addr.iter().zip(key.iter_mut()) {
-        *b = *a;
-    }
-    key
-}
-
-//this is actually possible without any copies at all (even on the stack)
-//for now do it this way
-pub fn try_decode <'a> (bytes: &'a [u8], keysize: &usize) -> Option<(Message<Key, Value>, Key)> {
-    let node_id = key_cpy(&bytes[1..keysize+1]);
-    let some_msg = match *(bytes.first().unwrap()) {
-        0 => Message::Ping,
-        1 => Message::PingResp,
-        x => {
-            let len = u8_4_to_u32(&bytes[keysize+1..keysize+5]) as usize;
-            let rest = &bytes[keysize+5..];
-            match x {
-                2 => Message::Store(key_cpy(&rest[0..*keysize]), (&rest[*keysize..]).to_owned()),
-                3 => Message::FindNode(key_cpy(&rest[0..])),
-                4 => Message::FindVal(key_cpy(&rest[0..])),
-                5 => { 
-                    let key = key_cpy(&rest[0..*keysize]);
-                    let nfield = &rest[*keysize..];
-                    let num_returned = (len-keysize)/26;
-                    let result_vec = (0..num_returned).map(|n| {
-                        let section = &nfield[n * (*keysize + 6)..];
-                        let node_id = key_cpy(&section[0..*keysize]);
-                        let ip_addr = ip_cpy(&section[*keysize..*keysize+4]);
-                        let port = u8_2_to_u16(&section[*keysize+4..*keysize+6]);
-                        NodeContact {id: node_id, ip: ip_addr, port: port}
-                    }).collect::<Vec<NodeContact<Key>>>();
-                    Message::FindNodeResp(key, result_vec)
-                },
-                6 => Message::FindValResp(key_cpy(&rest[0..*keysize]), (&rest[*keysize..]).to_owned()),
-                _ => return None
+/// largest message accepted, as much as a datagram is read into
+pub const MAX_MESSAGE_LEN: usize = 4096;
+/// opcode, sender id and payload length, ahead of the payload of every message but pings
+const HEADER_LEN: usize = 1 + KEYSIZE + 4;
+
+/// Why bytes are not a message
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DecodeError {
+    //the bytes end before a field does
+    Truncated,
+    UnknownOpcode(u8),
+    //declared is the payload length the length field states (0 for pings, which have none) and
+    //actual the bytes that are really there: those after the header, or those the fields of the
+    //message take up when they do not fill the declared length exactly
+    LengthMismatch {declared: usize, actual: usize},
+    //the message, or a count of items in it, is over what the protocol allows
+    Oversize(usize),
+    //a field holds a value it cannot have, such as an unknown status code
+    Malformed(&'static str)
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt (&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            DecodeError::LengthMismatch {declared, actual} => write!(f, "payload is declared as {} bytes but holds {}", declared, actual),
+            DecodeError::Oversize(size) => write!(f, "{} is over the protocol limit", size),
+            DecodeError::Malformed(field) => write!(f, "invalid {}", field)
+        }
+    }
+}
+
+impl ::std::error::Error for DecodeError {}
+
+/// what was decoded, or why the bytes were not it
+pub type Decoded<T> = ::std::result::Result<T, DecodeError>;
+
+/// reads the fields of a message front to back, failing instead of reading past its end
+struct Reader <'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl <'a> Reader<'a> {
+    fn new (bytes: &'a [u8]) -> Reader<'a> {
+        Reader {bytes, offset: 0}
+    }
+
+    fn take (&mut self, len: usize) -> Decoded<&'a [u8]> {
+        let end = self.offset.checked_add(len).ok_or(DecodeError::Truncated)?;
+        let field = self.bytes.get(self.offset..end).ok_or(DecodeError::Truncated)?;
+        self.offset = end;
+        Ok(field)
+    }
+
+    /// a fixed size field, such as a key
+    fn array <T> (&mut self) -> Decoded<&'a T> where &'a T: TryFrom<&'a [u8]>, T: 'a {
+        let len = mem::size_of::<T>();
+        self.take(len)?.try_into().map_err(|_| DecodeError::Truncated)
+    }
+
+    fn rest (&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        rest
+    }
+
+    fn remaining (&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    fn u8 (&mut self) -> Decoded<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16 (&mut self) -> Decoded<u16> {
+        Ok(u8_2_to_u16(self.take(2)?))
+    }
+
+    fn u32 (&mut self) -> Decoded<u32> {
+        Ok(u8_4_to_u32(self.take(4)?))
+    }
+
+    fn clock (&mut self) -> Decoded<ClockView<'a>> {
+        let (clock, used) = ClockView::parse(&self.bytes[self.offset..]).ok_or(DecodeError::Truncated)?;
+        self.offset += used;
+        Ok(clock)
+    }
+
+    /// a count of items of item_len bytes each, checked against max and the bytes left
+    fn count (&mut self, item_len: usize, max: usize) -> Decoded<usize> {
+        let count = self.u16()? as usize;
+        if count > max {
+            return Err(DecodeError::Oversize(count))
+        }
+        if count * item_len > self.remaining() {
+            return Err(DecodeError::Truncated)
+        }
+        Ok(count)
+    }
+
+    /// the next count items of item_len bytes each, as one slice
+    fn items (&mut self, item_len: usize, max: usize) -> Decoded<&'a [u8]> {
+        let count = self.count(item_len, max)?;
+        self.take(count * item_len)
+    }
+}
+
+//id, ipv4 and port
+const CONTACT_LEN: usize = KEYSIZE + 4 + 2;
+
+/// the contacts of a FindNodeResp, read as they are iterated over
+pub type Contacts<'a> = Map<ChunksExact<'a, u8>, fn(&'a [u8]) -> NodeContact<&'a Key>>;
+/// the (index, hash) Merkle tree nodes of a SyncTree
+pub type SyncNodes<'a> = Map<ChunksExact<'a, u8>, fn(&'a [u8]) -> (u16, &'a Key)>;
+/// the (key, digest) pairs of a SyncKeys
+pub type SyncPairs<'a> = Map<ChunksExact<'a, u8>, fn(&'a [u8]) -> (&'a Key, &'a Key)>;
+
+fn contact_at (bytes: &[u8]) -> NodeContact<&Key> {
+    let (id, addr) = bytes.split_at(KEYSIZE);
+    NodeContact {
+        id: id.try_into().unwrap_or(&[0; KEYSIZE]),
+        ip: [addr[0], addr[1], addr[2], addr[3]],
+        port: u8_2_to_u16(&addr[4..6])
+    }
+}
+
+fn sync_node_at (bytes: &[u8]) -> (u16, &Key) {
+    let (index, hash) = bytes.split_at(2);
+    (u8_2_to_u16(index), hash.try_into().unwrap_or(&[0; KEYSIZE]))
+}
+
+fn sync_pair_at (bytes: &[u8]) -> (&Key, &Key) {
+    let (key, digest) = bytes.split_at(KEYSIZE);
+    (key.try_into().unwrap_or(&[0; KEYSIZE]), digest.try_into().unwrap_or(&[0; KEYSIZE]))
+}
+
+/// The siblings of a FindValResp, read as they are iterated over
+#[derive(Clone)]
+pub struct Siblings<'a> {
+    bytes: &'a [u8],
+    left: usize
+}
+
+impl <'a> Siblings<'a> {
+    /// reads the siblings once to check they are all there
+    fn parse (r: &mut Reader<'a>) -> Decoded<Siblings<'a>> {
+        //each sibling takes at least an empty clock and a value length
+        let start = r.offset;
+        let count = r.count(2 + 4, MAX_MESSAGE_LEN)?;
+        for _ in 0..count {
+            r.clock()?;
+            let len = r.u32()? as usize;
+            r.take(len)?;
+        }
+        Ok(Siblings {bytes: &r.bytes[start + 2..r.offset], left: count})
+    }
+}
+
+impl <'a> Iterator for Siblings<'a> {
+    type Item = (ClockView<'a>, &'a [u8]);
+
+    fn next (&mut self) -> Option<(ClockView<'a>, &'a [u8])> {
+        if self.left == 0 {
+            return None
+        }
+        let mut r = Reader::new(self.bytes);
+        let clock = r.clock().ok()?;
+        let len = r.u32().ok()? as usize;
+        let val = r.take(len).ok()?;
+        self.bytes = r.rest();
+        self.left -= 1;
+        Some((clock, val))
+    }
+
+    fn size_hint (&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl <'a> Debug for Siblings<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_list().entries(self.clone()).finish()
+    }
+}
+
+/// A message read in place over the bytes it arrived in: keys, values and lists borrow from
+/// them instead of being copied out. Lists are read as they are iterated over.
+/// to_message gives the owned Message for handing the message on
+#[derive(Clone, Debug)]
+pub enum MessageView <'a> {
+    Ping,
+    PingResp,
+    Store(&'a Key, ClockView<'a>, &'a [u8], &'a Token),
+    FindNode(&'a Key),
+    FindVal(&'a Key),
+    Delete(&'a Key, ClockView<'a>, &'a Token),
+    FindNodeResp(&'a Key, Contacts<'a>, &'a Token),
+    FindValResp(&'a Key, Siblings<'a>, &'a Token),
+    StoreResp(&'a Key, StoreStatus),
+    //the reason is the bytes sent, which may not be utf-8
+    Error(u8, &'a [u8]),
+    SyncTree(SyncNodes<'a>),
+    SyncKeys(u16, bool, SyncPairs<'a>),
+    Leave,
+    Handshake(u32, &'a [u8; 32], &'a [u8; 32]),
+    HandshakeResp(u32, u32, &'a [u8; 32], &'a [u8; 32])
+}
+
+impl <'a> MessageView<'a> {
+    /// Reads a message and the id of its sender over bytes. Every length and count is checked
+    /// against the bytes actually there, so no input makes this panic
+    pub fn parse (bytes: &'a [u8]) -> Decoded<(MessageView<'a>, &'a Key)> {
+        if bytes.len() > MAX_MESSAGE_LEN {
+            return Err(DecodeError::Oversize(bytes.len()))
+        }
+        let mut header = Reader::new(bytes);
+        let opcode = header.u8()?;
+        if message_name(opcode).is_none() {
+            return Err(DecodeError::UnknownOpcode(opcode))
+        }
+        let node_id = header.array::<Key>()?;
+        //pings are only an opcode and an id
+        if opcode == OP_PING || opcode == OP_PING_RESP {
+            return match header.remaining() {
+                0 => Ok((if opcode == OP_PING { MessageView::Ping } else { MessageView::PingResp }, node_id)),
+                extra => Err(DecodeError::LengthMismatch {declared: 0, actual: extra})
             }
         }
-    };
+        let declared = header.u32()? as usize;
+        if declared > MAX_MESSAGE_LEN - HEADER_LEN {
+            return Err(DecodeError::Oversize(declared))
+        }
+        let payload = header.rest();
+        if payload.len() != declared {
+            return Err(DecodeError::LengthMismatch {declared, actual: payload.len()})
+        }
+
+        let mut r = Reader::new(payload);
+        let view = match opcode {
+            OP_STORE => {
+                let key = r.array::<Key>()?;
+                let token = r.array::<Token>()?;
+                let clock = r.clock()?;
+                MessageView::Store(key, clock, r.rest(), token)
+            },
+            OP_FIND_NODE => MessageView::FindNode(r.array::<Key>()?),
+            OP_FIND_VAL => MessageView::FindVal(r.array::<Key>()?),
+            OP_FIND_NODE_RESP => {
+                let key = r.array::<Key>()?;
+                let token = r.array::<Token>()?;
+                let contacts = r.rest();
+                if !contacts.len().is_multiple_of(CONTACT_LEN) {
+                    return Err(DecodeError::LengthMismatch {declared, actual: declared - contacts.len() % CONTACT_LEN})
+                }
+                MessageView::FindNodeResp(key, contacts.chunks_exact(CONTACT_LEN).map(contact_at as fn(&'a [u8]) -> NodeContact<&'a Key>), token)
+            },
+            OP_FIND_VAL_RESP => {
+                let key = r.array::<Key>()?;
+                let token = r.array::<Token>()?;
+                MessageView::FindValResp(key, Siblings::parse(&mut r)?, token)
+            },
+            OP_STORE_RESP => {
+                let key = r.array::<Key>()?;
+                let status = StoreStatus::from_code(r.u8()?).ok_or(DecodeError::Malformed("store status"))?;
+                MessageView::StoreResp(key, status)
+            },
+            OP_ERROR => {
+                let failed = r.u8()?;
+                MessageView::Error(failed, r.rest())
+            },
+            OP_DELETE => {
+                let key = r.array::<Key>()?;
+                let token = r.array::<Token>()?;
+                MessageView::Delete(key, r.clock()?, token)
+            },
+            OP_SYNC_TREE => {
+                let nodes = r.items(2 + KEYSIZE, MAX_SYNC_NODES)?;
+                MessageView::SyncTree(nodes.chunks_exact(2 + KEYSIZE).map(sync_node_at as fn(&'a [u8]) -> (u16, &'a Key)))
+            },
+            OP_SYNC_KEYS => {
+                let leaf = r.u16()?;
+                let reply = r.u8()? != 0;
+                let pairs = r.items(2 * KEYSIZE, MAX_SYNC_KEYS)?;
+                MessageView::SyncKeys(leaf, reply, pairs.chunks_exact(2 * KEYSIZE).map(sync_pair_at as fn(&'a [u8]) -> (&'a Key, &'a Key)))
+            },
+            OP_LEAVE => MessageView::Leave,
+            OP_HANDSHAKE => MessageView::Handshake(r.u32()?, r.array()?, r.array()?),
+            OP_HANDSHAKE_RESP => MessageView::HandshakeResp(r.u32()?, r.u32()?, r.array()?, r.array()?),
+            _ => return Err(DecodeError::UnknownOpcode(opcode))
+        };
+        if r.remaining() != 0 {
+            return Err(DecodeError::LengthMismatch {declared, actual: declared - r.remaining()})
+        }
+
+        Ok((view, node_id))
+    }
 
-    Some((some_msg, node_id))
+    pub fn opcode (&self) -> u8 {
+        match *self {
+            MessageView::Ping => OP_PING,
+            MessageView::PingResp => OP_PING_RESP,
+            MessageView::Store(..) => OP_STORE,
+            MessageView::FindNode(..) => OP_FIND_NODE,
+            MessageView::FindVal(..) => OP_FIND_VAL,
+            MessageView::FindNodeResp(..) => OP_FIND_NODE_RESP,
+            MessageView::FindValResp(..) => OP_FIND_VAL_RESP,
+            MessageView::StoreResp(..) => OP_STORE_RESP,
+            MessageView::Error(..) => OP_ERROR,
+            MessageView::Delete(..) => OP_DELETE,
+            MessageView::SyncTree(..) => OP_SYNC_TREE,
+            MessageView::SyncKeys(..) => OP_SYNC_KEYS,
+            MessageView::Leave => OP_LEAVE,
+            MessageView::Handshake(..) => OP_HANDSHAKE,
+            MessageView::HandshakeResp(..) => OP_HANDSHAKE_RESP
+        }
+    }
+
+    /// the message with its fields copied out of the bytes it was read over
+    pub fn to_message (&self) -> Message<Key, Value> {
+        match *self {
+            MessageView::Ping => Message::Ping,
+            MessageView::PingResp => Message::PingResp,
+            MessageView::Store(key, ref clock, val, token) => Message::Store(*key, clock.to_clock(), val.to_vec(), *token),
+            MessageView::FindNode(key) => Message::FindNode(*key),
+            MessageView::FindVal(key) => Message::FindVal(*key),
+            MessageView::Delete(key, ref clock, token) => Message::Delete(*key, clock.to_clock(), *token),
+            MessageView::FindNodeResp(key, ref contacts, token) => {
+                let contacts = contacts.clone().map(|c| NodeContact {id: *c.id, ip: c.ip, port: c.port}).collect();
+                Message::FindNodeResp(*key, contacts, *token)
+            },
+            MessageView::FindValResp(key, ref siblings, token) => {
+                let siblings = siblings.clone().map(|(clock, val)| (clock.to_clock(), val.to_vec())).collect();
+                Message::FindValResp(*key, siblings, *token)
+            },
+            MessageView::StoreResp(key, status) => Message::StoreResp(*key, status),
+            MessageView::Error(failed, reason) => Message::Error(failed, String::from_utf8_lossy(reason).into_owned()),
+            MessageView::SyncTree(ref nodes) => Message::SyncTree(nodes.clone().map(|(index, hash)| (index, *hash)).collect()),
+            MessageView::SyncKeys(leaf, reply, ref pairs) => {
+                Message::SyncKeys(leaf, reply, pairs.clone().map(|(key, digest)| (*key, *digest)).collect())
+            },
+            MessageView::Leave => Message::Leave,
+            MessageView::Handshake(index, ephemeral, static_key) => Message::Handshake(index, *ephemeral, *static_key),
+            MessageView::HandshakeResp(initiator, responder, ephemeral, static_key) => {
+                Message::HandshakeResp(initiator, responder, *ephemeral, *static_key)
+            }
+        }
+    }
+}
+
+/// Decodes a message and the id of its sender into an owned Message, see MessageView::parse.
+/// Keys are KEYSIZE bytes, the only keysize there is
+pub fn try_decode (bytes: &[u8], keysize: &usize) -> Decoded<(Message<Key, Value>, Key)> {
+    if *keysize != KEYSIZE {
+        return Err(DecodeError::Malformed("key size"))
+    }
+    MessageView::parse(bytes).map(|(view, id)| (view.to_message(), *id))
 }
 
 pub trait DSocket {
-    fn wait_for_message (&mut self) -> Result<(Message<Key, Value>, Key, SocketAddr)>;
+    /// the next message to arrive, read in place over buf
+    fn wait_for_view <'a> (&mut self, buf: &'a mut [u8], auth: Option<&ClusterAuth>, sessions: Option<&Sessions>) -> Result<(MessageView<'a>, &'a Key, SocketAddr)>;
+
+    /// the next message to arrive, copied out of the datagram it came in
+    fn wait_for_message (&mut self, auth: Option<&ClusterAuth>, sessions: Option<&Sessions>) -> Result<(Message<Key, Value>, Key, SocketAddr)> {
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let (view, from_id, addr) = self.wait_for_view(&mut buf, auth, sessions)?;
+        Ok((view.to_message(), *from_id, addr))
+    }
 }
 
 impl DSocket for UdpSocket {
-    fn wait_for_message (&mut self) -> Result<(Message<Key, Value>, Key, SocketAddr)> {
-        loop {
-            let mut ibuf:[u8; 4096] = unsafe {mem::uninitialized()};
-            match self.recv_from(&mut ibuf) {
-                Ok((0, _)) => return Err(Error::new(ErrorKind::Other, "graceful disconnect")),
-                Ok((num_read, addr)) => {
-                    match try_decode(&ibuf[0..num_read], &KEYSIZE) {
-                        None => continue,
-                        Some((msg, from_id)) => return Ok((msg, from_id, addr))
-                    }
+    /// A datagram that is not a valid message is an InvalidData error. With a cluster key, one
+    /// without a valid HMAC is a PermissionDenied error. With sessions, sealed messages are
+    /// opened into buf, and ones that fail to open (or plaintext ones when encryption is
+    /// required) are PermissionDenied errors as well
+    fn wait_for_view <'a> (&mut self, buf: &'a mut [u8], auth: Option<&ClusterAuth>, sessions: Option<&Sessions>) -> Result<(MessageView<'a>, &'a Key, SocketAddr)> {
+        let (num_read, addr) = self.recv_from(buf)?;
+        if num_read == 0 {
+            return Err(Error::new(ErrorKind::Other, "graceful disconnect"))
+        }
+        //the message is always at the start of buf, only its length changes
+        let mut len = match auth {
+            Some(auth) => match auth.open(&buf[..num_read], get_time().sec) {
+                Some(msg) => msg.len(),
+                None => return Err(Error::new(ErrorKind::PermissionDenied, "message failed authentication"))
+            },
+            None => num_read
+        };
+        match sessions {
+            Some(sessions) if buf[..len].first() == Some(&OP_SEALED) => match sessions.open(&buf[..len], addr) {
+                //the plaintext is shorter than the sealed message it replaces
+                Some(inner) => {
+                    buf[..inner.len()].copy_from_slice(&inner);
+                    len = inner.len();
                 },
-                Err(err) => return Err(err)
-            }
+                None => return Err(Error::new(ErrorKind::PermissionDenied, "sealed message failed to open"))
+            },
+            Some(sessions) if !sessions.accepts_plaintext(buf[..len].first().cloned()) => {
+                return Err(Error::new(ErrorKind::PermissionDenied, "plaintext message while encryption is required"))
+            },
+            _ => ()
+        }
+        let buf: &'a [u8] = buf;
+        match MessageView::parse(&buf[..len]) {
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+            Ok((view, from_id)) => Ok((view, from_id, addr))
         }
     }
 }
@@ -0,0 +1,261 @@
+use std::fmt::Write as FmtWrite;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use message_protocol::message_name;
+use api_layer::REQUEST_NAMES;
+use node::leave::Shutdown;
+use node::state::BucketArray;
+use utils::loggerator::Loggerator;
+use utils::networking::{read_http_get, write_http_response};
+
+/// number of opcodes messages are counted by
+const OPCODES: usize = 13;
+/// number of k-buckets, one per possible k_bucket_index
+const BUCKETS: usize = 161;
+
+const LATENCY_BOUNDS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const HOP_BOUNDS: [f64; 9] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 15.0];
+
+/// Counts of observations that fell at or below each bound, as Prometheus histograms keep them
+pub struct Histogram {
+    bounds: &'static [f64],
+    //per bound (not cumulative), then the sum and count of every observation
+    counts: Mutex<(Vec<u64>, f64, u64)>
+}
+
+impl Histogram {
+    fn new (bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: Mutex::new((vec![0; bounds.len()], 0.0, 0))
+        }
+    }
+
+    pub fn observe (&self, value: f64) {
+        if let Ok(mut counts) = self.counts.lock() {
+            if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+                counts.0[i] += 1;
+            }
+            counts.1 += value;
+            counts.2 += 1;
+        }
+    }
+
+    fn render (&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        if let Ok(counts) = self.counts.lock() {
+            let mut cumulative = 0;
+            for (bound, count) in self.bounds.iter().zip(counts.0.iter()) {
+                cumulative += count;
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+            }
+            let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, counts.2);
+            let _ = writeln!(out, "{}_sum {}", name, counts.1);
+            let _ = writeln!(out, "{}_count {}", name, counts.2);
+        }
+    }
+}
+
+/// Counters and gauges of a node, shared by its threads. Gauges of the state thread (k-buckets
+/// and the store) are refreshed every poll interval
+pub struct Metrics {
+    received: Vec<AtomicUsize>,
+    sent: Vec<AtomicUsize>,
+    decode_failures: AtomicUsize,
+    contacts_rejected: AtomicUsize,
+    auth_failures: AtomicUsize,
+    api_malformed: AtomicUsize,
+    bucket_sizes: Vec<AtomicUsize>,
+    stored_keys: AtomicUsize,
+    stored_bytes: AtomicUsize,
+    lookups_in_flight: AtomicUsize,
+    api_requests: Vec<AtomicUsize>,
+    rate_limited: Vec<AtomicUsize>,
+    api_rate_limited: Vec<AtomicUsize>,
+    bans: AtomicUsize,
+    pub lookup_latency: Histogram,
+    pub lookup_hops: Histogram
+}
+
+impl Default for Metrics {
+    fn default () -> Metrics {
+        Metrics::new()
+    }
+}
+
+fn zeroes (n: usize) -> Vec<AtomicUsize> {
+    (0..n).map(|_| AtomicUsize::new(0)).collect()
+}
+
+impl Metrics {
+    pub fn new () -> Metrics {
+        Metrics {
+            received: zeroes(OPCODES),
+            sent: zeroes(OPCODES),
+            decode_failures: AtomicUsize::new(0),
+            contacts_rejected: AtomicUsize::new(0),
+            auth_failures: AtomicUsize::new(0),
+            api_malformed: AtomicUsize::new(0),
+            bucket_sizes: zeroes(BUCKETS),
+            stored_keys: AtomicUsize::new(0),
+            stored_bytes: AtomicUsize::new(0),
+            lookups_in_flight: AtomicUsize::new(0),
+            api_requests: zeroes(REQUEST_NAMES.len()),
+            rate_limited: zeroes(OPCODES),
+            api_rate_limited: zeroes(REQUEST_NAMES.len()),
+            bans: AtomicUsize::new(0),
+            lookup_latency: Histogram::new(&LATENCY_BOUNDS),
+            lookup_hops: Histogram::new(&HOP_BOUNDS)
+        }
+    }
+
+    /// counts a message by the opcode it starts with
+    pub fn received (&self, opcode: u8) {
+        if let Some(c) = self.received.get(opcode as usize) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sent (&self, msg: &[u8]) {
+        if let Some(c) = msg.first().and_then(|op| self.sent.get(*op as usize)) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn decode_failed (&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a datagram dropped for not carrying a valid HMAC under the cluster key
+    pub fn auth_failed (&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a contact kept out of the k-buckets by the diversity limits
+    pub fn contact_rejected (&self) {
+        self.contacts_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a client request by the opcode it starts with
+    pub fn api_request (&self, opcode: u8) {
+        if let Some(c) = self.api_requests.get(opcode as usize) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// counts a client request answered with REQUEST_MALFORMED
+    pub fn api_malformed (&self) {
+        self.api_malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a message dropped by the rate limits of the protocol socket
+    pub fn rate_limited (&self, opcode: u8) {
+        if let Some(c) = self.rate_limited.get(opcode as usize) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// counts a client request dropped by the rate limits of the api port
+    pub fn api_rate_limited (&self, opcode: u8) {
+        if let Some(c) = self.api_rate_limited.get(opcode as usize) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn banned (&self) {
+        self.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_buckets (&self, buckets: &BucketArray) {
+        for (gauge, bucket) in self.bucket_sizes.iter().zip(buckets.iter()) {
+            gauge.store(bucket.len(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_store (&self, keys: usize, bytes: usize) {
+        self.stored_keys.store(keys, Ordering::Relaxed);
+        self.stored_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_lookups_in_flight (&self, n: usize) {
+        self.lookups_in_flight.store(n, Ordering::Relaxed);
+    }
+
+    /// the Prometheus text exposition of every metric
+    pub fn render (&self) -> String {
+        let mut out = String::new();
+        render_by(&mut out, "ailmedak_messages_received_total", "Protocol messages received, by type", "counter",
+                  &self.received, |i| ("type", message_name(i as u8).unwrap_or("?").to_string()));
+        render_by(&mut out, "ailmedak_messages_sent_total", "Protocol messages sent, by type", "counter",
+                  &self.sent, |i| ("type", message_name(i as u8).unwrap_or("?").to_string()));
+        render_one(&mut out, "ailmedak_decode_failures_total", "Datagrams that were not a valid message", "counter",
+                   &self.decode_failures);
+        render_one(&mut out, "ailmedak_auth_failures_total", "Datagrams without a valid HMAC under the cluster key", "counter",
+                   &self.auth_failures);
+        render_one(&mut out, "ailmedak_contacts_rejected_total", "Contacts kept out of the k-buckets by the ip diversity limits", "counter",
+                   &self.contacts_rejected);
+        render_by(&mut out, "ailmedak_bucket_contacts", "Contacts held in each k-bucket", "gauge",
+                  &self.bucket_sizes, |i| ("bucket", i.to_string()));
+        render_one(&mut out, "ailmedak_stored_keys", "Keys held in the local store", "gauge", &self.stored_keys);
+        render_one(&mut out, "ailmedak_stored_bytes", "Bytes of values held in the local store", "gauge", &self.stored_bytes);
+        render_one(&mut out, "ailmedak_lookups_in_flight", "Lookups the alpha thread is carrying out", "gauge",
+                   &self.lookups_in_flight);
+        render_by(&mut out, "ailmedak_api_requests_total", "Client requests on the api port, by operation", "counter",
+                  &self.api_requests, |i| ("op", REQUEST_NAMES[i].to_string()));
+        render_one(&mut out, "ailmedak_api_requests_malformed_total", "Client requests that could not be parsed", "counter",
+                   &self.api_malformed);
+        render_by(&mut out, "ailmedak_messages_rate_limited_total", "Protocol messages dropped by the rate limits, by type", "counter",
+                  &self.rate_limited, |i| ("type", message_name(i as u8).unwrap_or("?").to_string()));
+        render_by(&mut out, "ailmedak_api_requests_rate_limited_total", "Client requests dropped by the rate limits, by operation", "counter",
+                  &self.api_rate_limited, |i| ("op", REQUEST_NAMES[i].to_string()));
+        render_one(&mut out, "ailmedak_sources_banned_total", "Source ips banned for going over the rate limits", "counter",
+                   &self.bans);
+        self.lookup_latency.render(&mut out, "ailmedak_lookup_duration_seconds", "Time lookups took to finish");
+        self.lookup_hops.render(&mut out, "ailmedak_lookup_hops", "Hops from the node to the closest node a lookup reached");
+        out
+    }
+}
+
+fn render_one (out: &mut String, name: &str, help: &str, kind: &str, value: &AtomicUsize) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn render_by <F> (out: &mut String, name: &str, help: &str, kind: &str, values: &[AtomicUsize], label: F)
+        where F: Fn(usize) -> (&'static str, String) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    for (i, value) in values.iter().enumerate() {
+        let (label_name, label_value) = label(i);
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label_name, label_value, value.load(Ordering::Relaxed));
+    }
+}
+
+///Serves the metrics over HTTP: a GET of /metrics gets the Prometheus text format, anything else
+///a 404. The listener is polled so the thread notices when it is stopped
+pub fn spawn_metrics_thread (port: u16, metrics: Arc<Metrics>, logger: &Loggerator, poll: Duration, stop: Shutdown) -> JoinHandle<()> {
+    let logger = logger.for_module(module_path!());
+    let listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+    let _ = listener.set_nonblocking(true);
+    thread::spawn(move || {
+        logger.info("metrics listening", &[("port", &port)]);
+        while !stop.is_triggered() {
+            match listener.accept() {
+                Ok((stream, _)) => serve(stream, &metrics, poll),
+                Err(_) => thread::sleep(poll)
+            }
+        }
+    })
+}
+
+fn serve (mut stream: TcpStream, metrics: &Metrics, timeout: Duration) {
+    let (status, body) = match read_http_get(&mut stream, timeout).as_deref() {
+        Some("/metrics") => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new())
+    };
+    write_http_response(&mut stream, status, "text/plain; version=0.0.4", &body);
+}
@@ -1,38 +1,38 @@
 extern crate ailmedak;
 
 use ailmedak::node::machine::{AilmedakMachine};
+use ailmedak::node::leave::Shutdown;
 use ailmedak::config::Config;
 
 use std::thread;
+use std::time::Duration;
 // executable entry point for development purposes.
 // spins multiple nodes up within a single process
 
 fn main () {
     let num_slave_nodes = 3;
     let api_port = 4000;
+    let admin_port = 7000;
     let port_range_start = 3000;
 
-    thread::spawn(move|| {
-        AilmedakMachine::start(Config {
-            network_port: 3000,
-            api_port: Some(api_port),
-            k_val: 8,
-            async_poll_interval: 300,
-            initial_neighbors: vec![]
-        }, None);
-    });
+    let mut config = Config::default_with_port(3000);
+    config.api_port = Some(api_port);
+    config.admin_port = Some(admin_port);
+    let mut nodes = vec![AilmedakMachine::spawn(config, None)];
 
     for i in 1..num_slave_nodes+1 {
-        thread::spawn(move|| {
-            AilmedakMachine::start(Config {
-                network_port: port_range_start + i,
-                api_port: None,
-                k_val: 8,
-                async_poll_interval: 300,
-                initial_neighbors: vec!["0.0.0.0:3000".to_string()]
-            }, None);
-        });
+        let mut config = Config::default_with_port(port_range_start + i);
+        config.initial_neighbors = vec!["0.0.0.0:3000".to_string()];
+        nodes.push(AilmedakMachine::spawn(config, None));
     }
 
-    thread::park();
+    //on SIGTERM the nodes leave one after the other, each handing its values to those still up
+    let sigterm = Shutdown::new();
+    sigterm.on_sigterm().unwrap();
+    while !sigterm.is_triggered() {
+        thread::sleep(Duration::from_millis(100));
+    }
+    for node in nodes.into_iter().rev() {
+        node.shutdown();
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use time::get_time;
+use message_protocol::{Key, ProtoMessage, NO_TOKEN, MAX_SYNC_NODES, MAX_SYNC_KEYS};
+use node::state::{KademliaNode, NodeAddr, ASizedNode};
+use storage::merkle::{MerkleTree, ROOT};
+
+/// seconds a tree shared with a peer is used for, enough for the round trips of one exchange
+pub const SYNC_TREE_TTL: i64 = 10;
+/// most peers a shared tree is kept for at once
+const MAX_SYNC_TREES: usize = 16;
+
+/// The trees shared with the peers this node is in an exchange with. Filtering the store down to
+/// the keys shared with a peer goes through every key, so it is done once per exchange rather
+/// than for every message of it
+#[derive(Default)]
+pub struct SyncTrees {
+    //by peer, with the unix time they were built
+    trees: HashMap<NodeAddr, (MerkleTree, i64)>
+}
+
+impl SyncTrees {
+    /// takes out the tree shared with peer, if it was built for the exchange in progress
+    pub fn take (&mut self, peer: &NodeAddr, now: i64) -> Option<(MerkleTree, i64)> {
+        self.trees.retain(|_, &mut (_, built)| now - built < SYNC_TREE_TTL);
+        self.trees.remove(peer)
+    }
+
+    /// keeps the tree shared with peer for the rest of the exchange, making room by dropping
+    /// the oldest tree if need be
+    pub fn keep (&mut self, peer: NodeAddr, tree: MerkleTree, built: i64) {
+        if self.trees.len() >= MAX_SYNC_TREES && !self.trees.contains_key(&peer) {
+            let oldest = self.trees.iter().min_by_key(|(_, &(_, built))| built).map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.trees.remove(&oldest);
+            }
+        }
+        self.trees.insert(peer, (tree, built));
+    }
+
+    pub fn len (&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty (&self) -> bool {
+        self.trees.is_empty()
+    }
+}
+
+/// Anti-entropy: replicas of the same key range compare the Merkle trees of their stores top
+/// down, only descending into subtrees whose hashes differ. Once a differing leaf is reached both
+/// sides exchange the digests of its keys and push each other (as plain Stores and Deletes) every
+/// key the other has a different version of or lacks altogether. The trees compared only hold
+/// the keys both sides are replicas of, so keys do not spread past their N closest nodes
+impl KademliaNode {
+    /// the peers most likely to share responsibility for keys with this node: its n closest
+    /// contacts
+    pub fn sync_peers (&self, n: usize) -> Vec<(NodeAddr, SocketAddr)> {
+        let mut contacts = self.contacts();
+        contacts.sort_by_key(|(id, _)| Self::dist_as_bytes(id, &self.addr_id));
+        contacts.truncate(n);
+        contacts
+    }
+
+    /// whether this node and peer are both among the N closest known nodes to key
+    pub fn shares_key (&self, key: &Key, peer: &NodeAddr, contacts: &[NodeAddr]) -> bool {
+        let farther = Self::dist_as_bytes(&self.addr_id, key).max(Self::dist_as_bytes(peer, key));
+        //the nearer of the two is one of the nodes closer than the farther
+        let closer = 1 + contacts.iter()
+                                 .filter(|id| *id != peer && **id != self.addr_id)
+                                 .filter(|id| Self::dist_as_bytes(id, key) < farther)
+                                 .count();
+        closer < self.handoff.replicas()
+    }
+
+    /// the Merkle tree over the stored keys this node and peer are both replicas of
+    pub fn shared_tree (&self, peer: &NodeAddr) -> MerkleTree {
+        let contacts = self.contacts().into_iter().map(|(id, _)| id).collect::<Vec<NodeAddr>>();
+        self.data.merkle().filter(|key| self.shares_key(key, peer, &contacts))
+    }
+
+    /// the tree shared with peer in the exchange in progress, built if the exchange is new
+    fn exchange_tree (&mut self, peer: &NodeAddr) -> (MerkleTree, i64) {
+        let now = get_time().sec;
+        match self.sync_trees.take(peer, now) {
+            Some(kept) => kept,
+            None => (self.shared_tree(peer), now)
+        }
+    }
+
+    /// starts an exchange by sending peer the root of the tree shared with it
+    pub fn start_sync (&mut self, peer: (NodeAddr, SocketAddr)) {
+        let tree = self.shared_tree(&peer.0);
+        self.send_msg(&self.sync_tree_msg(&[(ROOT, tree.root())]), peer.1);
+        self.sync_trees.keep(peer.0, tree, get_time().sec);
+    }
+
+    /// Compares Merkle tree nodes of a peer against the tree shared with it. Inner nodes that
+    /// differ are answered with the local hashes of their children, leaves that differ with the
+    /// local keys of the leaf
+    pub fn on_sync_tree (&mut self, nodes: &[(u16, Key)], src: SocketAddr, from: &NodeAddr) {
+        let (tree, built) = self.exchange_tree(from);
+        let mut children = Vec::new();
+        for &(index, ref hash) in nodes.iter() {
+            match tree.hash(index) {
+                Some(ref mine) if mine == hash => (),
+                None => (),
+                Some(_) if MerkleTree::is_leaf(index) => self.send_leaf(&tree, index, true, src),
+                Some(_) => {
+                    let (left, right) = MerkleTree::children(index);
+                    children.extend(tree.hash(left).map(|h| (left, h)));
+                    children.extend(tree.hash(right).map(|h| (right, h)));
+                }
+            }
+        }
+        for chunk in children.chunks(MAX_SYNC_NODES) {
+            self.send_msg(&self.sync_tree_msg(chunk), src);
+        }
+        self.sync_trees.keep(*from, tree, built);
+    }
+
+    /// Pushes every shared key of a leaf whose digest differs from the peer's or that the peer
+    /// does not list, then answers with the shared keys of the leaf if the peer asked for them
+    pub fn on_sync_keys (&mut self, leaf: u16, reply: bool, keys: &[(Key, Key)], src: SocketAddr, from: &NodeAddr) {
+        let (tree, built) = self.exchange_tree(from);
+        for (key, digest) in tree.bucket(leaf).iter() {
+            if !keys.iter().any(|(k, d)| k == key && d == digest) {
+                self.push_key(key, src);
+            }
+        }
+        if reply {
+            self.send_leaf(&tree, leaf, false, src);
+        }
+        self.sync_trees.keep(*from, tree, built);
+    }
+
+    fn send_leaf (&self, tree: &MerkleTree, leaf: u16, reply: bool, dst: SocketAddr) {
+        let keys = tree.bucket(leaf);
+        let listed = &keys[..keys.len().min(MAX_SYNC_KEYS)];
+        self.send_msg(&self.sync_keys_msg(leaf, reply, listed), dst);
+    }
+
+    /// sends dst every version and the tombstone held for key
+    pub fn push_key (&self, key: &Key, dst: SocketAddr) {
+        for version in self.data.versions(key).iter() {
+            self.send_write(self.store_msg(key, &version.clock, &version.data, &NO_TOKEN), key, dst);
+        }
+        if let Some(tombstone) = self.data.tombstone(key) {
+            self.send_write(self.delete_msg(key, &tombstone.clock, &NO_TOKEN), key, dst);
+        }
+    }
+}
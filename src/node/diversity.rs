@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use node::state::{KademliaNode, NodeAddr};
+use node::events::Event;
+
+/// How many contacts of the k-buckets may share something, None if any number may
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cap {
+    pub per_bucket: Option<usize>,
+    pub per_table: Option<usize>
+}
+
+/// Caps on the contacts the k-buckets hold from the same ip and from the same /24 (IPv4) or /64
+/// (IPv6) prefix, so that an attacker on one network cannot fill the routing table (an eclipse
+/// attack). Contacts over a cap wait in the replacement cache of their bucket instead
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiversityLimits {
+    pub same_ip: Cap,
+    pub same_prefix: Cap,
+    //whether loopback and private addresses are exempt, so local clusters and clusters on one
+    //LAN are not capped
+    pub exempt_local: bool
+}
+
+/// parses PER_BUCKET:PER_TABLE, where either may be none
+impl FromStr for Cap {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Cap, String> {
+        let count = |p: &str| match p.trim() {
+            "none" => Ok(None),
+            p => p.parse::<usize>().map(Some).map_err(|_| format!("invalid cap {}, expected PER_BUCKET:PER_TABLE", s))
+        };
+        match s.split(':').collect::<Vec<&str>>().as_slice() {
+            [bucket, table] => Ok(Cap {per_bucket: count(bucket)?, per_table: count(table)?}),
+            _ => Err(format!("invalid cap {}, expected PER_BUCKET:PER_TABLE", s))
+        }
+    }
+}
+
+impl fmt::Display for Cap {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = |c: Option<usize>| c.map_or("none".to_string(), |c| c.to_string());
+        write!(f, "{}:{}", count(self.per_bucket), count(self.per_table))
+    }
+}
+
+impl Default for DiversityLimits {
+    fn default () -> DiversityLimits {
+        DiversityLimits {
+            same_ip: Cap {per_bucket: Some(1), per_table: Some(3)},
+            same_prefix: Cap {per_bucket: Some(2), per_table: Some(10)},
+            exempt_local: true
+        }
+    }
+}
+
+impl DiversityLimits {
+    /// no caps at all
+    pub fn unlimited () -> DiversityLimits {
+        let none = Cap {per_bucket: None, per_table: None};
+        DiversityLimits {same_ip: none, same_prefix: none, exempt_local: true}
+    }
+
+    /// whether a contact at ip may join bucket, which already holds the given contacts, in a
+    /// routing table holding table
+    pub fn admits (&self, ip: IpAddr, bucket: &[(NodeAddr, SocketAddr)], table: &[(NodeAddr, SocketAddr)]) -> bool {
+        let ip = canonical(ip);
+        if self.exempt_local && is_local(ip) {
+            return true
+        }
+        let prefix = prefix_of(ip);
+        let same_ip = |c: &&(NodeAddr, SocketAddr)| canonical(c.1.ip()) == ip;
+        let same_prefix = |c: &&(NodeAddr, SocketAddr)| prefix_of(canonical(c.1.ip())) == prefix;
+        under(self.same_ip.per_bucket, bucket.iter().filter(same_ip).count())
+            && under(self.same_ip.per_table, table.iter().filter(same_ip).count())
+            && under(self.same_prefix.per_bucket, bucket.iter().filter(same_prefix).count())
+            && under(self.same_prefix.per_table, table.iter().filter(same_prefix).count())
+    }
+}
+
+fn under (cap: Option<usize>, count: usize) -> bool {
+    cap.is_none_or(|cap| count < cap)
+}
+
+/// IPv4 addresses mapped into IPv6 count as the IPv4 address
+fn canonical (ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4
+    }
+}
+
+/// the /24 of an IPv4 address or the /64 of an IPv6 one
+pub fn prefix_of (ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        },
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+fn is_local (ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        //fc00::/7 (unique local) and fe80::/10 (link local)
+        IpAddr::V6(v6) => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00 || (v6.segments()[0] & 0xffc0) == 0xfe80
+    }
+}
+
+/// Contacts of a k-bucket that were turned away for going over the diversity limits, most
+/// recently heard from last. They take the place of contacts that leave the bucket
+#[derive(Default)]
+pub struct Replacements {
+    cache: Vec<VecDeque<(NodeAddr, SocketAddr)>>
+}
+
+impl Replacements {
+    pub fn new () -> Replacements {
+        Replacements::default()
+    }
+
+    /// keeps contact as a replacement in the bucket at k_index, forgetting the one heard from the
+    /// longest ago if the cache already holds max
+    pub fn push (&mut self, k_index: usize, contact: (NodeAddr, SocketAddr), max: usize) {
+        if self.cache.len() <= k_index {
+            self.cache.resize(k_index + 1, VecDeque::new());
+        }
+        let cache = &mut self.cache[k_index];
+        cache.retain(|&(id, _)| id != contact.0);
+        if cache.len() >= max {
+            cache.pop_front();
+        }
+        cache.push_back(contact);
+    }
+
+    /// the replacements of the bucket at k_index
+    pub fn of (&self, k_index: usize) -> Vec<(NodeAddr, SocketAddr)> {
+        self.cache.get(k_index).map_or(Vec::new(), |c| c.iter().cloned().collect())
+    }
+
+    fn remove (&mut self, k_index: usize, id: &NodeAddr) {
+        if let Some(cache) = self.cache.get_mut(k_index) {
+            cache.retain(|(n, _)| n != id);
+        }
+    }
+
+    /// drops a contact that left the network from every cache
+    pub fn forget (&mut self, id: &NodeAddr) {
+        for cache in self.cache.iter_mut() {
+            cache.retain(|(n, _)| n != id);
+        }
+    }
+}
+
+impl KademliaNode {
+    /// whether a contact at addr may join the bucket at k_index without going over the diversity
+    /// limits
+    pub fn admits (&self, k_index: usize, addr: SocketAddr) -> bool {
+        self.diversity.admits(addr.ip(), &self.buckets[k_index], &self.contacts())
+    }
+
+    /// Moves the most recently heard from replacement that fits within the diversity limits into
+    /// the bucket at k_index, which a contact just left
+    pub fn promote_replacement (&mut self, k_index: usize) {
+        let promoted = self.replacements.of(k_index).into_iter().rev().find(|&(_, addr)| self.admits(k_index, addr));
+        if let Some((id, addr)) = promoted {
+            self.replacements.remove(k_index, &id);
+            self.buckets[k_index].insert(0, (id, addr));
+            self.events.emit(Event::ContactAdded(id, addr));
+            self.queue_handoff((id, addr));
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver, channel};
+use message_protocol::{Key, NodeContact};
+use node::state::NodeAddr;
+
+/// Something that changed in the node or in its view of the network
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    //a contact that was not in the k-buckets before
+    ContactAdded(NodeAddr, SocketAddr),
+    //a known contact was heard from and moved to the tail of its k-bucket
+    ContactRefreshed(NodeAddr, SocketAddr),
+    //a contact was dropped from the k-buckets
+    ContactEvicted(NodeAddr, SocketAddr),
+    //a new version of the value was stored
+    ValueStored(Key),
+    //the last version of the value expired
+    ValueExpired(Key),
+    //versions of the value were deleted
+    ValueDeleted(Key),
+    LookupStarted(Key),
+    //the closest nodes the lookup found
+    LookupFinished(Key, Vec<NodeContact<Key>>),
+    //the lookup of the node's own id, done once it has its first contact, finished with these
+    //closest nodes
+    JoinCompleted(Vec<NodeContact<Key>>)
+}
+
+struct Subscriber {
+    filter: Box<dyn Fn(&Event) -> bool + Send>,
+    tx: Sender<Event>
+}
+
+/// Hands the events of a node to whoever subscribed to them. Clones share the subscribers, so
+/// every thread of the node can hold one
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>
+}
+
+impl Events {
+    pub fn new () -> Events {
+        Events::default()
+    }
+
+    /// Returns a receiver of every event the filter accepts from now on. Dropping the receiver
+    /// ends the subscription
+    pub fn subscribe <F> (&self, filter: F) -> Receiver<Event> where F: Fn(&Event) -> bool + Send + 'static {
+        let (tx, rx) = channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(Subscriber {filter: Box::new(filter), tx});
+        }
+        rx
+    }
+
+    /// sends event to the subscribers that want it, forgetting those that are gone
+    pub fn emit (&self, event: Event) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|s| !(s.filter)(&event) || s.tx.send(event.clone()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use api_layer::{ClientMessage, Callback, Requester, ReadResult, WriteResult, hash_key, next_request_id};
+use message_protocol::{Key, NodeContact};
+use node::machine::MessageType;
+use node::leave::Shutdown;
+use node::state::{NodeAddr, RoutingTable};
+use node::events::{Events, Event};
+
+/// A reply that has yet to arrive
+pub struct Pending<T> {
+    rx: Receiver<T>
+}
+
+impl <T> Pending<T> {
+    /// blocks until the reply arrives. None if the node stopped before answering
+    pub fn wait (self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+
+    /// blocks for at most timeout. None if the reply did not arrive by then
+    pub fn wait_timeout (&self, timeout: Duration) -> Option<T> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+
+    /// the reply if it has arrived, without blocking
+    pub fn try_take (&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// A node running in the background of this process, as returned by AilmedakMachine::spawn.
+/// Requests go straight to the state thread over channels rather than through the api port. Keys
+/// are hashed the same way the api hashes them, so both see the same values
+pub struct NodeHandle {
+    id: NodeAddr,
+    node: Sender<MessageType>,
+    callbacks: Sender<Callback>,
+    events: Events,
+    shutdown: Shutdown,
+    thread: JoinHandle<()>
+}
+
+impl NodeHandle {
+    pub fn new (id: NodeAddr, node: Sender<MessageType>, callbacks: Sender<Callback>, events: Events, shutdown: Shutdown, thread: JoinHandle<()>) -> NodeHandle {
+        NodeHandle {id, node, callbacks, events, shutdown, thread}
+    }
+
+    pub fn id (&self) -> &NodeAddr {
+        &self.id
+    }
+
+    /// reads key with the node's default read quorum
+    pub fn get (&self, key: &[u8]) -> Pending<ReadResult> {
+        let key = hash_key(key);
+        let (tx, rx) = channel();
+        let id = next_request_id();
+        let _ = self.callbacks.send(Callback::Register(id, Requester::Local(tx)));
+        let _ = self.node.send(MessageType::FromClient(ClientMessage::Get(id, key, None)));
+        Pending {rx}
+    }
+
+    /// writes val under key with the node's default write quorum. it supersedes every version
+    /// the node has seen
+    pub fn set (&self, key: &[u8], val: &[u8]) -> Pending<WriteResult> {
+        let key = hash_key(key);
+        let (tx, rx) = channel();
+        let id = next_request_id();
+        let _ = self.callbacks.send(Callback::RegisterWrite(id, Requester::Local(tx)));
+        let _ = self.node.send(MessageType::FromClient(ClientMessage::Set(id, key, val.to_vec(), None, None)));
+        Pending {rx}
+    }
+
+    /// the closest nodes to id across the network
+    pub fn lookup (&self, id: Key) -> Pending<Vec<NodeContact<Key>>> {
+        let (tx, rx) = channel();
+        let _ = self.node.send(MessageType::FromClient(ClientMessage::FindNode(id, tx)));
+        Pending {rx}
+    }
+
+    /// a snapshot of the k-buckets
+    pub fn routing_table (&self) -> Pending<RoutingTable> {
+        let (tx, rx) = channel();
+        let _ = self.node.send(MessageType::FromClient(ClientMessage::RoutingTable(tx)));
+        Pending {rx}
+    }
+
+    /// the events of the node that filter accepts, from now on
+    pub fn subscribe <F> (&self, filter: F) -> Receiver<Event> where F: Fn(&Event) -> bool + Send + 'static {
+        self.events.subscribe(filter)
+    }
+
+    /// asks the node to leave the network without waiting for it
+    pub fn request_shutdown (&self) {
+        self.shutdown.trigger();
+    }
+
+    /// leaves the network and waits until all of the node's threads are gone
+    pub fn shutdown (self) {
+        self.shutdown.trigger();
+        self.join();
+    }
+
+    /// waits until the node stopped, which only happens once a shutdown is requested
+    pub fn join (self) {
+        let _ = self.thread.join();
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use message_protocol::Key;
+use node::state::{KademliaNode, NodeAddr, ASizedNode};
+
+/// default number of keys handed off per poll interval of the node
+pub const DEFAULT_HANDOFF_BATCH: usize = 20;
+/// stored keys checked per poll interval for the contacts that joined
+pub const HANDOFF_SCAN: usize = 1000;
+
+/// a contact whose keys are being looked for, and how many stored keys are left to check for it
+struct Joined {
+    id: NodeAddr,
+    addr: SocketAddr,
+    left: usize
+}
+
+/// Keys waiting to be stored on nodes that joined closer to them (section 2.5 of the Kademlia
+/// paper). Transfers are spread over time so a join does not flood the new node, and so is the
+/// search for the keys to transfer, so a join does not stall a node that stores many keys
+pub struct Handoff {
+    queue: VecDeque<(Key, SocketAddr)>,
+    //the stored keys when the contacts in joined were added, checked in turn from cursor on
+    keys: Vec<Key>,
+    cursor: usize,
+    joined: Vec<Joined>,
+    //number of nodes a key is replicated to (N)
+    replicas: usize,
+    //keys sent per drain
+    batch: usize
+}
+
+impl Handoff {
+    pub fn new (replicas: usize, batch: usize) -> Handoff {
+        Handoff {
+            queue: VecDeque::new(),
+            keys: Vec::new(),
+            cursor: 0,
+            joined: Vec::new(),
+            replicas,
+            batch
+        }
+    }
+
+    /// number of nodes a key is replicated to (N)
+    pub fn replicas (&self) -> usize {
+        self.replicas
+    }
+
+    /// number of transfers still waiting
+    pub fn len (&self) -> usize {
+        self.queue.len()
+    }
+
+    /// whether nothing is left to transfer or to look for
+    pub fn is_empty (&self) -> bool {
+        self.queue.is_empty() && self.joined.is_empty()
+    }
+
+    /// drops the transfers to a node that is gone
+    pub fn forget (&mut self, dst: SocketAddr) {
+        self.queue.retain(|&(_, addr)| addr != dst);
+        self.joined.retain(|joined| joined.addr != dst);
+    }
+}
+
+impl KademliaNode {
+    /// Looks for the stored keys the new contact should hold over the next drains. The keys are
+    /// taken as they are now. A contact that joins while they are checked for others starts
+    /// where that pass is and goes around once
+    pub fn queue_handoff (&mut self, new: (NodeAddr, SocketAddr)) {
+        let (id, addr) = new;
+        if self.handoff.joined.is_empty() {
+            self.handoff.keys = self.data.keys();
+            self.handoff.cursor = 0;
+        }
+        let left = self.handoff.keys.len();
+        self.handoff.joined.push(Joined {id, addr, left});
+    }
+
+    /// Checks the next HANDOFF_SCAN stored keys for the contacts that joined, queueing those a
+    /// contact should hold: the keys it is among the N closest known nodes to. To keep the other
+    /// replicas from sending the same keys, only keys this node is the closest known node to
+    /// (not counting the new one) are queued
+    pub fn scan_handoff (&mut self) {
+        let contacts = self.contacts();
+        let mut checked = 0;
+        while checked < HANDOFF_SCAN && !self.handoff.joined.is_empty() {
+            self.handoff.joined.retain(|joined| joined.left > 0);
+            if self.handoff.joined.is_empty() {
+                break
+            }
+            let key = self.handoff.keys[self.handoff.cursor];
+            self.handoff.cursor = (self.handoff.cursor + 1) % self.handoff.keys.len();
+            let own_dist = Self::dist_as_bytes(&self.addr_id, &key);
+            for joined in self.handoff.joined.iter_mut() {
+                joined.left -= 1;
+                checked += 1;
+                let new_dist = Self::dist_as_bytes(&joined.id, &key);
+                let mut closer_than_new = if own_dist < new_dist { 1 } else { 0 };
+                let mut closest = true;
+                for (id, _) in contacts.iter().filter(|(id, _)| *id != joined.id) {
+                    let dist = Self::dist_as_bytes(id, &key);
+                    closest &= dist > own_dist;
+                    if dist < new_dist {
+                        closer_than_new += 1;
+                    }
+                }
+                if closest && closer_than_new < self.handoff.replicas {
+                    self.handoff.queue.push_back((key, joined.addr));
+                }
+            }
+        }
+        self.handoff.joined.retain(|joined| joined.left > 0);
+    }
+
+    /// Queues every stored key for the N closest contacts to it, which are left holding its
+    /// replicas once this node is gone. Those that hold the key already ignore it. Contacts that
+    /// joined are not looked for keys any more, every key is queued anyway
+    pub fn queue_leave (&mut self) {
+        self.handoff.joined.clear();
+        let contacts = self.contacts();
+        let mut queued = Vec::new();
+        for key in self.data.keys() {
+            let mut closest = contacts.clone();
+            closest.sort_by_key(|(id, _)| Self::dist_as_bytes(id, &key));
+            queued.extend(closest.into_iter().take(self.handoff.replicas).map(|(_, addr)| (key, addr)));
+        }
+        self.handoff.queue.extend(queued);
+    }
+
+    /// Sends off the next batch of queued keys. A leaving node sends each key to N nodes, so it
+    /// gets through N times as many transfers per batch
+    pub fn drain_handoff (&mut self) {
+        self.scan_handoff();
+        let batch = if self.leaving {
+            self.handoff.batch * self.handoff.replicas
+        } else {
+            self.handoff.batch
+        };
+        let batch = batch.min(self.handoff.queue.len());
+        let due = self.handoff.queue.drain(..batch).collect::<Vec<_>>();
+        for (key, dst) in due.iter() {
+            self.push_key(key, *dst);
+        }
+    }
+}
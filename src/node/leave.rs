@@ -0,0 +1,79 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use signal_hook::consts::SIGTERM;
+use signal_hook::flag;
+use message_protocol::ProtoMessage;
+use node::state::{KademliaNode, NodeAddr};
+use node::events::Event;
+
+/// Asks a running node to leave the network. Clones share the same request, so a handle kept by
+/// the caller can stop a node running on another thread
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>
+}
+
+impl Shutdown {
+    pub fn new () -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger (&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered (&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// triggers the shutdown once the process receives SIGTERM
+    pub fn on_sigterm (&self) -> io::Result<()> {
+        flag::register(SIGTERM, self.requested.clone()).map(|_| ())
+    }
+}
+
+/// Leaving the network: a leaving node refuses writes, hands every stored key off to the nodes
+/// that take over its replicas and finally tells its contacts to drop it from their k-buckets
+impl KademliaNode {
+    /// stops accepting writes and queues every stored key for the N closest contacts to it
+    pub fn begin_leave (&mut self) {
+        self.leaving = true;
+        self.queue_leave();
+    }
+
+    /// tells every contact this node is gone
+    pub fn announce_leave (&self) {
+        let msg = self.leave_msg();
+        for (_, addr) in self.contacts() {
+            self.send_msg(&msg, addr);
+        }
+    }
+
+    /// Drops the contact that announced it left, if the announcement came from the address known
+    /// for it. Anyone can send a Leave with any id in it, a mismatched one is ignored
+    pub fn on_leave (&mut self, id: &NodeAddr, from: SocketAddr) -> bool {
+        let known = self.buckets.iter().flat_map(|bucket| bucket.iter()).any(|&(n, addr)| n == *id && addr == from);
+        known && self.drop_contact(id)
+    }
+
+    /// Removes a contact that left the network, along with the keys waiting to be handed off to
+    /// it. A replacement takes its place if its bucket has one. Returns false if it was not known
+    pub fn drop_contact (&mut self, id: &NodeAddr) -> bool {
+        self.replacements.forget(id);
+        let found = self.buckets.iter_mut().enumerate().find_map(|(k_index, bucket)| {
+            bucket.iter().position(|(n, _)| n == id).map(|i| (k_index, bucket.remove(i)))
+        });
+        match found {
+            Some((k_index, (_, addr))) => {
+                self.handoff.forget(addr);
+                self.last_seen.remove(id);
+                self.events.emit(Event::ContactEvicted(*id, addr));
+                self.promote_replacement(k_index);
+                true
+            },
+            None => false
+        }
+    }
+}
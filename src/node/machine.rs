@@ -1,18 +1,37 @@
-use std::net::{UdpSocket, SocketAddr};
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::io::ErrorKind;
+use std::path::PathBuf;
 use time::get_time;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::cmp::Ordering;
-use message_protocol::{DSocket, Message, Key, Value, ProtoMessage, NodeContact};
-use api_layer::{spawn_api_thread, ClientMessage, Callback};
+use message_protocol::{DSocket, Message, Key, Value, Sibling, ProtoMessage, NodeContact, StoreStatus, Token, NO_TOKEN, OP_STORE, OP_DELETE, MAX_MESSAGE_LEN};
+use api_layer::{spawn_api_thread, spawn_callback_thread, ClientMessage, Callback, ReadResult, RequestId};
 use utils::fmt::{as_hex_string};
 use utils::networking::{ip_port_pair};
-use utils::loggerator::Loggerator;
-use config::Config;
+use utils::loggerator::{Loggerator, LogSink, Level};
+use config::{Config, Replication};
 use node::state::{NodeAddr, KademliaNode, ASizedNode};
+use storage::{ValueStore, StoreError};
+use storage::version::VectorClock;
+use node::handoff::Handoff;
+use node::leave::Shutdown;
+use node::handle::NodeHandle;
+use node::events::{Events, Event};
+use metrics::{Metrics, spawn_metrics_thread};
+use ratelimit::{RateLimiter, Verdict};
+use admin::{AdminQuery, LookupStatus, spawn_admin_thread, config_json, node_json, buckets_json, keys_json, lookups_json, traces_json};
+use node::trace::{LookupTrace, Traces};
+use node::tokens::{WriteTokens, PeerTokens, Stamped};
+use transport::{Encryption, Identity, PeerKeys, Sessions, Wire};
+use storage::routing::{load_node_id, save_node_id, load_contacts, save_contacts, load_identity_key, save_identity_key};
 
 const DEFAULT_TTL:i64 = 3; //timeout in seconds for a request
+const ALPHA_FACTOR:usize = 4; //number of concurrent queries a lookup keeps in flight
 
 #[derive(Debug)]
 pub struct EvictionCandidate {
@@ -20,20 +39,68 @@ pub struct EvictionCandidate {
     pub new: (NodeAddr, SocketAddr)
 }
 
+/// What to do once a lookup has converged on the closest nodes to its key. Writes go to the
+/// replication factor (N) closest nodes and carry the number of acknowledgements they need (W)
+#[derive(Debug)]
+pub enum LookupPurpose {
+    /// nothing beyond learning about the nodes along the way
+    Refresh,
+    /// the lookup of the node's own id that joins it to the network
+    Join,
+    /// hand the closest nodes found to whoever asked
+    Find(Sender<Vec<NodeContact<Key>>>),
+    /// read the value for the request, finishing as soon as the given number of nodes (R) have
+    /// answered with it. the local siblings, if there are any, count as an answer
+    Get(RequestId, usize, Option<Vec<Sibling>>),
+    /// store a version of the value on the closest nodes. the status is the outcome of storing it
+    /// locally (None if that failed without a reason that can be given to a client)
+    Store(RequestId, VectorClock, Value, Option<StoreStatus>, usize),
+    /// delete the versions the clock descends from on the closest nodes. the status is the outcome
+    /// of deleting them locally
+    Delete(RequestId, VectorClock, Option<StoreStatus>, usize)
+}
+
+impl LookupPurpose {
+    pub fn name (&self) -> &'static str {
+        match *self {
+            LookupPurpose::Refresh => "refresh",
+            LookupPurpose::Join => "join",
+            LookupPurpose::Find(_) => "find",
+            LookupPurpose::Get(..) => "get",
+            LookupPurpose::Store(..) => "store",
+            LookupPurpose::Delete(..) => "delete"
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum AsyncAction {
     Awake,
     SetEvictTimeout(EvictionCandidate),
+    // starts a lookup of a key from the closest contacts known locally
+    StartLookup(Key, Vec<NodeContact<Key>>, LookupPurpose),
     // the key producing these results, contact information for these results, and the nodeid from
     // the source (none if the source is the resident node)
-    LookupResults(Key, Vec<NodeContact<Key>>, Option<Key>)
+    LookupResults(Key, Vec<NodeContact<Key>>, Option<Key>),
+    // the key stored, the nodeid of the node that stored it and how that went
+    StoreAck(Key, NodeAddr, StoreStatus),
+    // the siblings found while looking up their key, and the nodeid of the node that had them
+    ValueResult(Key, Vec<Sibling>, NodeAddr),
+    // traces the next lookup of the key
+    Trace(Key),
+    // a report on the lookups in progress or traced, as JSON
+    Admin(AdminQuery, Sender<String>)
     //PingResp(),
 
 }
 
 pub enum MessageType {
     FromClient(ClientMessage),
-    FromNode(Message<Key, Value>, NodeAddr, SocketAddr)
+    FromNode(Message<Key, Value>, NodeAddr, SocketAddr),
+    //sent every poll interval so the state thread can do housekeeping
+    Tick,
+    //asks the state thread to hand off its values and leave the network
+    Leave
 }
 
 
@@ -45,49 +112,140 @@ impl ReceiveMessage <Message<Key, Value>, AsyncAction> for KademliaNode {
     fn receive (&mut self, msg: Message<Key, Value>, src_addr: SocketAddr, a_sender: &Sender<AsyncAction>, node_id: NodeAddr) {
         match msg {
             Message::Ping => {
-                let _ = self.socket.send_to(&self.ping_ack(), src_addr);
+                self.send_msg(&self.ping_ack(), src_addr);
             },
             Message::FindNode(key) => {
                 let kclosest = self.find_k_closest(&key);
-                let response = self.find_node_resp(&kclosest, &key);
-                let _ = self.socket.send_to(&response, src_addr);
+                let response = self.find_node_resp(&kclosest, &key, &self.write_tokens.issue(src_addr.ip()));
+                self.send_msg(&response, src_addr);
             },
             Message::FindVal(key) => {
-                let _ = self.socket.send_to(&(match self.data.get(&key) {
-                    None => self.find_node_resp(&self.find_k_closest(&key), &key),
-                    Some(data) => self.find_val_resp(&key, data)
+                let siblings = local_siblings(&self.data, &key);
+                let token = self.write_tokens.issue(src_addr.ip());
+                self.send_msg(&(if siblings.is_empty() {
+                    self.find_node_resp(&self.find_k_closest(&key), &key, &token)
+                } else {
+                    self.find_val_resp(&key, &siblings, &token)
                 }), src_addr);
             },
-            Message::Store(key, val) => {self.data.insert(key, val);},
+            //a leaving node would only take the value with it
+            Message::Store(..) if self.leaving => {
+                self.send_msg(&self.error_msg(OP_STORE, "node is leaving"), src_addr);
+            },
+            Message::Delete(..) if self.leaving => {
+                self.send_msg(&self.error_msg(OP_DELETE, "node is leaving"), src_addr);
+            },
+            //a source that never saw a response of this node, such as a spoofed one, has no token
+            Message::Store(key, _, _, token) if !self.write_tokens.accepts(src_addr.ip(), &token) => {
+                self.logger.debug("rejected store without a valid token", &[("key", &as_hex_string(&key)), ("from", &src_addr)]);
+                self.send_msg(&self.store_resp_msg(&key, StoreStatus::InvalidToken), src_addr);
+            },
+            Message::Store(key, clock, val, _) => {
+                let response = match self.put_version(key, val, clock, Some(src_addr.ip())) {
+                    Ok(_) => self.store_resp_msg(&key, StoreStatus::Ok),
+                    Err(e) => {
+                        self.logger.warn("rejected store", &[("key", &as_hex_string(&key)), ("from", &src_addr), ("reason", &e)]);
+                        match store_status(&e) {
+                            Some(status) => self.store_resp_msg(&key, status),
+                            None => self.error_msg(OP_STORE, &e.to_string())
+                        }
+                    }
+                };
+                self.send_msg(&response, src_addr);
+            },
+            Message::Delete(key, _, token) if !self.write_tokens.accepts(src_addr.ip(), &token) => {
+                self.logger.debug("rejected delete without a valid token", &[("key", &as_hex_string(&key)), ("from", &src_addr)]);
+                self.send_msg(&self.store_resp_msg(&key, StoreStatus::InvalidToken), src_addr);
+            },
+            Message::Delete(key, clock, _) => {
+                let response = match self.delete_version(&key, clock) {
+                    Ok(_) => self.store_resp_msg(&key, StoreStatus::Ok),
+                    Err(e) => self.error_msg(OP_DELETE, &e.to_string())
+                };
+                self.send_msg(&response, src_addr);
+            },
             //Responses
-            Message::FindNodeResp(key, node_vec) => {
+            Message::FindNodeResp(key, node_vec, token) => {
+                self.token_received(src_addr, token);
                 let _ = a_sender.send(AsyncAction::LookupResults(key, node_vec, Some(node_id)));
             },
+            Message::FindValResp(key, val, token) => {
+                self.token_received(src_addr, token);
+                let _ = a_sender.send(AsyncAction::ValueResult(key, val, node_id));
+            },
+            Message::StoreResp(key, status) => {
+                let _ = a_sender.send(AsyncAction::StoreAck(key, node_id, status));
+            },
+            Message::Error(opcode, reason) => {
+                self.logger.warn("request failed", &[("opcode", &opcode), ("on", &src_addr), ("reason", &reason)]);
+            },
+            Message::SyncTree(nodes) => {
+                self.on_sync_tree(&nodes, src_addr, &node_id);
+            },
+            Message::SyncKeys(leaf, reply, keys) => {
+                self.on_sync_keys(leaf, reply, &keys, src_addr, &node_id);
+            },
             Message::PingResp => {
                 //TODO: if this is an eviction candidate, do stuff such that it isn't evicted
+            },
+            Message::Leave => {
+                self.on_leave(&node_id, src_addr);
+            },
+            Message::Handshake(index, ephemeral, static_key) => {
+                if let Some(ref sessions) = self.wire.sessions {
+                    let datagrams = sessions.respond(src_addr, &node_id, index, &PeerKeys {ephemeral, static_key}, Instant::now());
+                    self.wire.send_prepared(&self.socket, datagrams, src_addr);
+                }
+            },
+            Message::HandshakeResp(initiator, responder, ephemeral, static_key) => {
+                if let Some(ref sessions) = self.wire.sessions {
+                    let datagrams = sessions.complete(src_addr, &node_id, initiator, responder, &PeerKeys {ephemeral, static_key}, Instant::now());
+                    self.wire.send_prepared(&self.socket, datagrams, src_addr);
+                }
             }
-            _ => {}
         }
     }
 }
 
+impl KademliaNode {
+    /// keeps a write token src handed out and sends it the stores that waited on it
+    fn token_received (&self, src: SocketAddr, token: Token) {
+        for store in self.peer_tokens.received(src, token, get_time().sec) {
+            self.send_msg(&store, src);
+        }
+    }
+}
+
+/// the versions of key held locally, as they are sent to other nodes and clients
+fn local_siblings (data: &ValueStore, key: &Key) -> Vec<Sibling> {
+    data.get(key).into_iter().map(|v| (v.clock.clone(), v.data.clone())).collect()
+}
+
+/// the StoreStatus to report for a rejected write, None if it failed for a reason that is internal
+/// to this node
+fn store_status (e: &StoreError) -> Option<StoreStatus> {
+    match *e {
+        StoreError::TooLarge => Some(StoreStatus::TooLarge),
+        StoreError::QuotaExceeded => Some(StoreStatus::QuotaExceeded),
+        StoreError::Deleted => Some(StoreStatus::Deleted),
+        StoreError::Io(_) => None
+    }
+}
+
 pub struct AilmedakMachine;
 
 pub trait Machine {
     fn start (&mut self, port: u16);
 }
 
-///Returns true if a lookup can be considered finished
-///TODO: need to also compensate for Yellow nodes (timedout ones)
+///Returns true if a lookup can be considered finished, that is when the k closest candidates that
+///have not timed out (Yellow) have all responded
 macro_rules! is_lookup_finished {
     ($k_val: expr, $cand_vec: expr) => {{
-        let visited = $cand_vec.iter().take_while(|&&(_, ref color)| *color == Color::Black).count();
-        if visited >= $k_val || visited >= $cand_vec.len() {
-            println!("lookup is done");
-            true
-        } else {
-            false
-        }
+        $cand_vec.iter()
+                 .filter(|&&(_, ref color)| *color != Color::Yellow)
+                 .take($k_val)
+                 .all(|&(_, ref color)| *color == Color::Black)
     }}
 }
 
@@ -114,119 +272,382 @@ impl AilmedakMachine {
     /// alpha, crucial for maintaining async state in operations such as lookup node. Additionally
     /// it also worries about timing out contact information (and updating the state lists) back up
     /// in the state thread
+    ///
+    /// start returns once the node has left the network, after receiving SIGTERM
     pub fn start (config: Config, id_opt: Option<NodeAddr>) {
+        Self::launch(config, id_opt, Shutdown::new(), true).join();
+    }
+
+    /// Like start, but the node leaves when shutdown is triggered. Leaving stops the node from
+    /// taking writes, hands its values off to the next closest nodes, tells its contacts and
+    /// finally joins all of its threads
+    pub fn run (config: Config, id_opt: Option<NodeAddr>, shutdown: Shutdown) {
+        Self::launch(config, id_opt, shutdown, false).join();
+    }
+
+    /// Starts a node in the background and returns right away. The node is used and stopped
+    /// through the returned handle
+    pub fn spawn (config: Config, id_opt: Option<NodeAddr>) -> NodeHandle {
+        Self::launch(config, id_opt, Shutdown::new(), false)
+    }
+
+    /// starts the threads of a node. with sigterm, SIGTERM triggers shutdown
+    fn launch (config: Config, id_opt: Option<NodeAddr>, shutdown: Shutdown, sigterm: bool) -> NodeHandle {
         let network_socket = match UdpSocket::bind(("0.0.0.0", config.network_port)) {
             Ok(a) => a,
             _ => panic!("unable to bind")
         };
 
-        let state = KademliaNode::new(
-            id_opt.unwrap_or_else(||KademliaNode::gen_new_id()),
+        let mut store = match config.data_dir {
+            Some(ref dir) => match ValueStore::open(dir, config.fsync_policy, config.value_ttl) {
+                Ok(s) => s,
+                Err(e) => panic!("unable to open data directory {}: {}", dir, e)
+            },
+            None => ValueStore::in_memory(config.value_ttl)
+        };
+
+        let data_dir = config.data_dir.as_ref().map(PathBuf::from);
+
+        //a node with a data directory keeps its identity across restarts
+        let saved_id = match data_dir {
+            Some(ref dir) => load_node_id(dir).unwrap_or_else(|e| panic!("unable to read node id: {}", e)),
+            None => None
+        };
+        let node_id = id_opt.or(saved_id).unwrap_or_else(KademliaNode::gen_new_id);
+        if let Some(ref dir) = data_dir {
+            save_node_id(dir, &node_id).unwrap_or_else(|e| panic!("unable to save node id: {}", e));
+        }
+
+        store.set_limits(config.store_limits.clone(), config.eviction_policy, node_id);
+        store.set_tombstone_ttl(config.tombstone_ttl);
+
+        let mut state = KademliaNode::new(
+            node_id,
             config.k_val.clone(),
+            store,
             network_socket.try_clone().unwrap());
+        state.handoff = Handoff::new(config.replication().n, config.handoff_batch);
+        state.diversity = config.diversity.clone();
+        state.write_tokens = WriteTokens::new(config.token_rotation, get_time().sec);
+        let peer_tokens = PeerTokens::new(config.token_rotation);
+        state.peer_tokens = peer_tokens.clone();
+        let sessions = match config.encryption {
+            Encryption::Off => None,
+            mode => Some(Sessions::new(node_id, Self::load_identity(&data_dir), mode))
+        };
+        state.wire = Wire {auth: config.cluster_auth.clone(), sessions: sessions.clone()};
 
-        for i_neighbor in config.initial_neighbors.iter() {
-            let as_ref:&str = i_neighbor.as_ref();
-            state.send_msg(&state.ping_msg(), as_ref);
+        let sink = LogSink::open(config.log.clone()).unwrap_or_else(|e| panic!("unable to open log file: {}", e));
+        let logger = Loggerator::new(&node_id, module_path!(), Arc::new(sink));
+        state.logger = logger.clone();
+        logger.info("node bound to cluster port", &[("port", &config.network_port)]);
+        if state.data.is_durable() {
+            logger.info("recovered stored values", &[("values", &state.data.len())]);
         }
+        if sigterm {
+            if let Err(e) = shutdown.on_sigterm() {
+                logger.error("unable to handle SIGTERM", &[("reason", &e)]);
+            }
+        }
+
+        //contacts from the last run are only pinged. they get back into the k-buckets the usual
+        //way, by answering. the initial neighbors are used if none of them do
+        let saved_contacts = match data_dir {
+            Some(ref dir) => load_contacts(dir).unwrap_or_else(|e| {
+                logger.warn("ignoring saved contacts", &[("reason", &e)]);
+                vec![]
+            }),
+            None => vec![]
+        };
+        let bootstrap = if saved_contacts.is_empty() {
+            Self::ping_all(&state, &config.initial_neighbors);
+            None
+        } else {
+            logger.info("revalidating saved contacts", &[("contacts", &saved_contacts.len())]);
+            for &(_, addr) in saved_contacts.iter() {
+                state.send_msg(&state.ping_msg(), addr);
+            }
+            Some(Bootstrap {
+                deadline: get_time().sec + DEFAULT_TTL,
+                neighbors: config.initial_neighbors.clone()
+            })
+        };
 
-        let logger = Loggerator::new(state.id());
-        logger.log(&format!("NODE BIND CLUSTER PORT {}", config.network_port));
+        let replication = config.replication();
+        let housekeeping = Housekeeping {
+            data_dir,
+            routing_interval: config.routing_snapshot_interval,
+            last_routing_save: get_time().sec,
+            bootstrap,
+            sync_interval: config.anti_entropy_interval,
+            last_sync: get_time().sec,
+            sync_peers: replication.n,
+            next_peer: 0,
+            leave_timeout: config.leave_timeout
+        };
 
-        let ap = AlphaProcessor {id: state.id().clone(), k_val: state.k_val.clone()};
+        let events = Events::new();
+        state.events = events.clone();
+        let metrics = Arc::new(Metrics::new());
+        state.metrics = metrics.clone();
+        let ap = AlphaProcessor {
+            id: state.id().clone(),
+            k_val: state.k_val.clone(),
+            replication: replication.n,
+            events: events.clone(),
+            metrics: metrics.clone(),
+            logger: logger.clone(),
+            peer_tokens,
+            wire: state.wire.clone()
+        };
 
         let (m_tx, m_rx) = channel();
         let (a_tx, a_rx) = channel();
 
-        let _ = Self::spawn_proto_thread(network_socket.try_clone().unwrap(), m_tx.clone());
-        let cb_tx = match config {
-            Config {api_port: Some(port_val), ..} => {
-                let (_, s) = spawn_api_thread(port_val, m_tx.clone());
-                s //this is a channel that might do something with a callback
-            },
-            _ => {
-                let (c, _) = channel();
-                c //this is a channel that does nothing
-            }
+        //stops the threads reading from sockets, once the node has left
+        let stop_io = Shutdown::new();
+        let poll = Duration::from_millis(config.async_poll_interval as u64);
+
+        let proto_thread = Self::spawn_proto_thread(network_socket.try_clone().unwrap(), m_tx.clone(), metrics.clone(), &config, sessions, logger.clone(), stop_io.clone());
+        //without an api port, only NodeHandle requests get answers
+        let (api_thread, cb_tx) = match config {
+            Config {api_port: Some(port_val), ..} => spawn_api_thread(port_val, &config, m_tx.clone(), metrics.clone(), &logger, stop_io.clone()),
+            _ => spawn_callback_thread(None, poll, stop_io.clone())
         };
+        let handle_callbacks = cb_tx.clone();
+        let metrics_thread = config.metrics_port.map(|port| spawn_metrics_thread(port, metrics, &logger, poll, stop_io.clone()));
+        let admin_thread = config.admin_port.map(|port| spawn_admin_thread(port, m_tx.clone(), config_json(&config), &logger, poll, stop_io.clone()));
 
-        let _ = Self::spawn_state_thread(state, m_rx, cb_tx, a_tx.clone());
+        let state_thread = Self::spawn_state_thread(state, housekeeping, replication, m_rx, cb_tx.clone(), a_tx.clone());
 
         //alpha processor processes events that may be waiting on a future condition. performance
         //requirements are less stringent within this thread
-        let _ = Self::spawn_alpha_thread(ap, a_rx, a_tx.clone(), network_socket.try_clone().unwrap());
+        let alpha_thread = Self::spawn_alpha_thread(ap, a_rx, cb_tx, network_socket.try_clone().unwrap());
+
+        let handle_tx = m_tx.clone();
+        let trigger = shutdown.clone();
+        //the ticker drives the other threads. the state thread keeps getting ticks while it hands
+        //off its values, it exits once it has left the network
+        let ticker = thread::spawn(move|| {
+            let mut leaving = false;
+            while !state_thread.is_finished() {
+                thread::sleep(poll);
+                if !leaving && trigger.is_triggered() {
+                    let _ = m_tx.send(MessageType::Leave);
+                    leaving = true;
+                }
+                let _ = a_tx.send(AsyncAction::Awake);
+                let _ = m_tx.send(MessageType::Tick);
+            }
+            let _ = state_thread.join();
+            //with the state thread gone, the alpha thread runs out of senders once this one is dropped
+            drop(a_tx);
+            let _ = alpha_thread.join();
+            stop_io.trigger();
+            let _ = proto_thread.join();
+            let _ = api_thread.join();
+            for t in metrics_thread.into_iter().chain(admin_thread) {
+                let _ = t.join();
+            }
+            logger.info("node stopped", &[]);
+        });
+
+        NodeHandle::new(node_id, handle_tx, handle_callbacks, events, shutdown, ticker)
+    }
 
-        loop {
-            thread::sleep_ms(config.async_poll_interval);
-            let _ = a_tx.send(AsyncAction::Awake);
+    /// the identity key sessions are keyed by. a node with a data directory keeps it across
+    /// restarts, so its peers recognize it
+    fn load_identity (data_dir: &Option<PathBuf>) -> Identity {
+        let dir = match *data_dir {
+            Some(ref dir) => dir,
+            None => return Identity::generate()
+        };
+        match load_identity_key(dir).unwrap_or_else(|e| panic!("unable to read identity key: {}", e)) {
+            Some(secret) => Identity::from_secret(secret),
+            None => {
+                let identity = Identity::generate();
+                save_identity_key(dir, identity.secret()).unwrap_or_else(|e| panic!("unable to save identity key: {}", e));
+                identity
+            }
+        }
+    }
+
+    fn ping_all (state: &KademliaNode, neighbors: &[String]) {
+        for i_neighbor in neighbors.iter() {
+            let as_ref:&str = i_neighbor.as_ref();
+            state.send_msg(&state.ping_msg(), as_ref);
         }
     }
 
     /// state thread manages the k-lists staying mostly true to Kademlia's description
-    fn spawn_state_thread (mut state: KademliaNode,  rx: Receiver<MessageType>,  to_api: Sender<Callback>, to_async: Sender<AsyncAction>) -> JoinHandle<()> {
+    fn spawn_state_thread (mut state: KademliaNode, mut housekeeping: Housekeeping, replication: Replication, rx: Receiver<MessageType>,  to_api: Sender<Callback>, to_async: Sender<AsyncAction>) -> JoinHandle<()> {
 
         thread::spawn(move|| {
-            let logger = Loggerator::new(state.id());
-            loop {
-                match rx.recv().unwrap() {
+            let mut leave_deadline = None;
+            while let Ok(event) = rx.recv() {
+                match event {
                     MessageType::FromClient(message) => {
                         match message {
-                            ClientMessage::Get(key) => {
-                                match state.data.get(&key) {
-                                    None => {
-                                        state.find_k_closest_global(key, &to_async);
-                                        logger.log(&"starting node lookup".to_string());
-                                    },
-                                    Some(data) => {
+                            ClientMessage::Set(id, ..) | ClientMessage::Delete(id, ..) if state.leaving => {
+                                let _ = to_api.send(Callback::Written(id, None, 0));
+                            },
+                            ClientMessage::Get(id, key, quorum) => {
+                                let read_quorum = replication.read_quorum(quorum);
+                                let local = Some(local_siblings(&state.data, &key)).filter(|s| !s.is_empty());
+                                match local {
+                                    Some(siblings) if read_quorum <= 1 => {
                                         //if this node has an client api side, it will send the resolved key back to the api layer.
                                         //otherwise it will send a message down the channel that will just be discarded
-                                        let _ = to_api.send(Callback::Resolve(key, data.clone()));
+                                        let _ = to_api.send(Callback::Resolve(id, ReadResult::Found(siblings)));
+                                    },
+                                    local => {
+                                        state.find_k_closest_global(key, LookupPurpose::Get(id, read_quorum, local), &to_async);
+                                        state.logger.debug("starting value lookup", &[("key", &as_hex_string(&key))]);
                                     }
                                 }
                             },
-                            ClientMessage::Set(key, val) => {
-                                //TODO: remove me and use nodelookup
-                                state.data.insert(key, val);
+                            ClientMessage::Set(id, key, val, quorum, context) => {
+                                //the value is kept locally as well as replicated to the closest
+                                //nodes found by a lookup. it supersedes the versions the client
+                                //has seen, or all of those known here if it gave no context
+                                let clock = state.data.next_version(&key, context.as_ref());
+                                let local_status = match state.put_version(key, val.clone(), clock.clone(), None) {
+                                    Ok(_) => Some(StoreStatus::Ok),
+                                    Err(e) => {
+                                        state.logger.warn("unable to store locally", &[("key", &as_hex_string(&key)), ("reason", &e)]);
+                                        store_status(&e)
+                                    }
+                                };
+                                let write_quorum = replication.write_quorum(quorum);
+                                state.find_k_closest_global(key, LookupPurpose::Store(id, clock, val, local_status, write_quorum), &to_async);
+                            },
+                            ClientMessage::Delete(id, key, quorum, context) => {
+                                let clock = state.data.next_version(&key, context.as_ref());
+                                let local_status = match state.delete_version(&key, clock.clone()) {
+                                    Ok(_) => Some(StoreStatus::Ok),
+                                    Err(e) => {
+                                        state.logger.warn("unable to delete locally", &[("key", &as_hex_string(&key)), ("reason", &e)]);
+                                        None
+                                    }
+                                };
+                                let write_quorum = replication.write_quorum(quorum);
+                                state.find_k_closest_global(key, LookupPurpose::Delete(id, clock, local_status, write_quorum), &to_async);
+                            },
+                            ClientMessage::FindNode(id, reply) => {
+                                state.find_k_closest_global(id, LookupPurpose::Find(reply), &to_async);
+                            },
+                            ClientMessage::RoutingTable(reply) => {
+                                let _ = reply.send(state.routing_table());
+                            },
+                            ClientMessage::Trace(key) => {
+                                let _ = to_async.send(AsyncAction::Trace(key));
+                            },
+                            ClientMessage::Admin(query, reply) => {
+                                let report = match query {
+                                    AdminQuery::Node => node_json(&state),
+                                    AdminQuery::Buckets => buckets_json(&state),
+                                    AdminQuery::Keys => keys_json(&state.data),
+                                    //only the alpha thread knows its lookups
+                                    AdminQuery::Lookups | AdminQuery::Traces(_) => {
+                                        let _ = to_async.send(AsyncAction::Admin(query, reply));
+                                        continue
+                                    }
+                                };
+                                let _ = reply.send(report);
                             }
                         };
                     }
                     MessageType::FromNode(message, node_id, ip_addr) => {
-                        let diff = state.distance_to(&node_id);
-                        let k_index = KademliaNode::k_bucket_index(&diff);
-                        let e_cand = state.update_k_bucket(k_index, (node_id, ip_addr));
-                        match e_cand {
-                            None => (),
-                            Some(e_c) => {
-                                let _ = (&to_async).send(AsyncAction::SetEvictTimeout(e_c));
-                                state.send_msg(&state.ping_msg(), ip_addr);
+                        //a node saying goodbye is not (re)added to the k-buckets, nor is one that
+                        //is only setting up a session
+                        if !matches!(message, Message::Leave | Message::Handshake(..) | Message::HandshakeResp(..)) {
+                            let alone = state.buckets.iter().all(|bucket| bucket.is_empty());
+                            let diff = state.distance_to(&node_id);
+                            let k_index = KademliaNode::k_bucket_index(&diff);
+                            let e_cand = state.update_k_bucket(k_index, (node_id, ip_addr));
+                            match e_cand {
+                                None => (),
+                                Some(e_c) => {
+                                    let _ = (&to_async).send(AsyncAction::SetEvictTimeout(e_c));
+                                    state.send_msg(&state.ping_msg(), ip_addr);
+                                }
+                            };
+                            //with its first contact the node joins: looking itself up fills the
+                            //k-buckets with its neighbors and introduces it to them
+                            if alone {
+                                let id = *state.id();
+                                state.find_k_closest_global(id, LookupPurpose::Join, &to_async);
                             }
-                        };
-                        
-                        logger.logs(&message, &node_id);
+                        }
 
-                        let action = state.receive(message, ip_addr, &to_async, node_id);
-                        let _ = action;
-                        //println!("action: {:?}", action);
-                    }
+                        if state.logger.enabled(Level::Trace) {
+                            state.logger.trace("received", &[("message", &format!("{:?}", message)), ("from", &as_hex_string(&node_id))]);
+                        }
 
+                        state.receive(message, ip_addr, &to_async, node_id);
+                    },
+                    MessageType::Tick => {
+                        let now = get_time().sec;
+                        match state.data.tick(now) {
+                            Ok(expired) => {
+                                for key in expired {
+                                    state.events.emit(Event::ValueExpired(key));
+                                }
+                            },
+                            Err(e) => state.logger.error("store maintenance failed", &[("reason", &e)])
+                        }
+                        housekeeping.tick(&mut state, now);
+                        state.write_tokens.rotate_if_due(now);
+                        state.peer_tokens.expire(now);
+                        if let Some(ref sessions) = state.wire.sessions {
+                            for (addr, datagram) in sessions.expire(Instant::now()) {
+                                state.wire.send_prepared(&state.socket, vec![datagram], addr);
+                            }
+                        }
+                        state.drain_handoff();
+                        state.metrics.set_buckets(&state.buckets);
+                        state.metrics.set_store(state.data.len(), state.data.bytes());
+                        let left = match leave_deadline {
+                            Some(deadline) => state.handoff.is_empty() || now >= deadline,
+                            None => false
+                        };
+                        if left {
+                            if !state.handoff.is_empty() {
+                                state.logger.warn("gave up on handoffs", &[("handoffs", &state.handoff.len())]);
+                            }
+                            state.announce_leave();
+                            housekeeping.persist_routing(&state);
+                            if let Err(e) = state.data.sync() {
+                                state.logger.error("unable to flush the store", &[("reason", &e)]);
+                            }
+                            state.logger.info("left the network", &[]);
+                            break
+                        }
+                    },
+                    MessageType::Leave => {
+                        state.begin_leave();
+                        state.logger.info("leaving, handing off keys", &[("keys", &state.data.keys().len())]);
+                        leave_deadline = Some(get_time().sec + housekeeping.leave_timeout);
+                    }
                 }
-
             }
         })
     }
 
     /// alpha thread attempts to asynchronous responses from other nodes and timeouts
     // TODO: make alpha_sock a Sender
-    fn spawn_alpha_thread (ap: AlphaProcessor, a_rx: Receiver<AsyncAction>, a_tx_self: Sender<AsyncAction>, alpha_sock: UdpSocket) -> JoinHandle<()> {
-        const ALPHA_FACTOR:usize = 4;
+    fn spawn_alpha_thread (ap: AlphaProcessor, a_rx: Receiver<AsyncAction>, to_api: Sender<Callback>, alpha_sock: UdpSocket) -> JoinHandle<()> {
         thread::spawn(move|| {
             let mut timeoutbuf:Vec<(EvictionCandidate, i64)> = Vec::new();
             //It would probably be better to use a HashMap for highly concurrent api requests
             //but for now focus on lower latency in small batches. maybe make this configurable
-            let mut lookup_qi: Vec<(Key, Vec<(NodeContact<Key>, Color)>)> = Vec::new();
-            let mut find_out:Vec<(NodeContact<Key>, i64)> = Vec::new();
-            loop {
-                match a_rx.recv().unwrap() {
+            let mut lookup_qi: Vec<Lookup> = Vec::new();
+            let mut pending_writes: Vec<PendingWrite> = Vec::new();
+            //keys whose next lookup is traced, and the traces of lookups that finished
+            let mut trace_next: HashSet<Key> = HashSet::new();
+            let mut traces = Traces::new();
+            while let Ok(action) = a_rx.recv() {
+                match action {
                     AsyncAction::Awake => {
                         //these are orthogonal. can be handled in an isolated thread
                         if !timeoutbuf.is_empty() {
@@ -234,76 +655,180 @@ impl AilmedakMachine {
                             let (exp, rem):(Vec<_>, Vec<_>) = timeoutbuf.into_iter().partition(|&(ref ec, ref expire)| expire >= &now_secs);
                             timeoutbuf = rem;
                             //TODO: this needs to signal back to the k-buckets owner to update
-                            println!("UNHANDLED");
+                            ap.logger.debug("eviction timeouts are not handled yet", &[("expired", &exp.len())]);
+                        }
+
+                        //nodes that did not answer in time are quarantined, which may let a lookup
+                        //move on (or finish)
+                        let now = get_time().sec;
+                        for lookup in lookup_qi.iter_mut() {
+                            for &mut (ref contact, ref mut color) in lookup.candidates.iter_mut() {
+                                if let Color::Grey(valid_until) = *color {
+                                    if valid_until < now {
+                                        *color = Color::Yellow;
+                                        if let Some(ref mut trace) = lookup.trace {
+                                            trace.colored(&contact.id, "yellow");
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        let mut i = 0;
+                        while i < lookup_qi.len() {
+                            if ap.advance(&mut lookup_qi[i], &alpha_sock) {
+                                let lookup = lookup_qi.remove(i);
+                                ap.complete(lookup, &alpha_sock, &to_api, &mut pending_writes, &mut traces);
+                            } else {
+                                i += 1;
+                            }
                         }
-                        /*for f in find_out.iter() {
-                            
-                        }*/
 
+                        let (expired, waiting): (Vec<_>, Vec<_>) = pending_writes.into_iter().partition(|p| p.expires_at < now);
+                        pending_writes = waiting;
+                        for pending in expired {
+                            pending.resolve(&to_api);
+                        }
                     },
                     AsyncAction::SetEvictTimeout(ec) => {
                         let expire_at = get_time().sec + DEFAULT_TTL;
                         timeoutbuf.push((ec, expire_at));
                     },
-                    AsyncAction::LookupResults(key, mut close_nodes, from_id) => {
-                        let is_new = {
-                            let f_res = lookup_qi.iter_mut().find(|&&mut(k, _)| k == key);
-                            match f_res {
-                                None => true,
-                                Some(&mut (ref a, ref mut k_vec)) => {
-                                    Self::merge_into(k_vec, &mut close_nodes, &key);
-                                    //unoptimized... set the from_id to black (visited)
-                                    if let Some(fid) = from_id {
-                                        if let Some(&mut( _, ref mut color)) = k_vec.iter_mut().find(|&&mut(NodeContact{id: key,..}, _)| key == fid) {
-                                            *color = Color::Black;
-                                        } // probably should have gone with a HM
-                                        find_out.retain(|&(NodeContact{id: fe, ..}, _)| fe != fid);
-                                    }
-                                    match is_lookup_finished!(ap.k_val, k_vec) {
-                                        false => {
-                                            if find_out.len() < ALPHA_FACTOR {
-                                                Self::color(k_vec, ALPHA_FACTOR - find_out.len(), |&mut find_entry| {
-                                                    let NodeContact{ref ip, ref port, ..} = find_entry;
-                                                    let _ = alpha_sock.send_to(&ap.find_node_msg(&key), ip_port_pair(ip, port));
-                                                    find_out.push((find_entry, get_time().sec+1));
-                                                });
-                                            }
-                                        },
-                                        true => {
-                                            println!("DONE");
-                                            let _ = a_tx_self.send(AsyncAction::Awake);
-                                            //signal that we're done
-                                        }
-                                    };
-                                    false
+                    AsyncAction::StartLookup(key, mut close_nodes, purpose) => {
+                        let answers = match purpose {
+                            LookupPurpose::Get(_, _, Some(ref local)) => vec![(ap.id, local.clone())],
+                            _ => vec![]
+                        };
+                        let hops = close_nodes.iter().map(|c| (c.id, 1)).collect();
+                        let trace = if trace_next.remove(&key) { Some(LookupTrace::new(key, purpose.name())) } else { None };
+                        let mut lookup = Lookup {key, candidates: Vec::new(), purpose, answers, started: Instant::now(), hops, trace};
+                        if let Some(ref mut trace) = lookup.trace {
+                            trace.learned(&close_nodes);
+                        }
+                        Self::merge_into(&mut lookup.candidates, &mut close_nodes, &key);
+                        if ap.advance(&mut lookup, &alpha_sock) {
+                            //there was no one to ask
+                            ap.complete(lookup, &alpha_sock, &to_api, &mut pending_writes, &mut traces);
+                        } else {
+                            lookup_qi.push(lookup);
+                        }
+                    },
+                    AsyncAction::LookupResults(key, close_nodes, from_id) => {
+                        //every lookup of the key learns from the answer, a set and a get of the
+                        //same key may be looking it up at once
+                        let mut finished = Vec::new();
+                        for (index, lookup) in lookup_qi.iter_mut().enumerate().filter(|(_, l)| l.key == key) {
+                            let mut close_nodes = close_nodes.iter().filter(|c| c.id != ap.id).cloned().collect::<Vec<NodeContact<Key>>>();
+                            //the nodes learned of are one hop further away than the node that told
+                            let depth = from_id.and_then(|fid| lookup.hops.get(&fid)).map_or(1, |d| d + 1);
+                            for c in close_nodes.iter() {
+                                lookup.hops.entry(c.id).or_insert(depth);
+                            }
+                            if let Some(ref mut trace) = lookup.trace {
+                                if let Some(fid) = from_id {
+                                    trace.answered(&fid, &close_nodes, false);
                                 }
+                                trace.learned(&close_nodes);
+                            }
+                            Self::merge_into(&mut lookup.candidates, &mut close_nodes, &key);
+                            //unoptimized... set the from_id to black (visited)
+                            if let Some(fid) = from_id {
+                                if let Some(&mut( _, ref mut color)) = lookup.candidates.iter_mut().find(|&&mut(NodeContact{id: key,..}, _)| key == fid) {
+                                    *color = Color::Black;
+                                } // probably should have gone with a HM
+                            }
+                            if ap.advance(lookup, &alpha_sock) {
+                                finished.push(index);
+                            }
+                        }
+                        for index in finished.into_iter().rev() {
+                            let lookup = lookup_qi.remove(index);
+                            ap.complete(lookup, &alpha_sock, &to_api, &mut pending_writes, &mut traces);
+                        }
+                    },
+                    AsyncAction::ValueResult(key, val, from_id) => {
+                        let mut finished = Vec::new();
+                        for (index, lookup) in lookup_qi.iter_mut().enumerate().filter(|(_, l)| l.key == key) {
+                            if let Some(&mut( _, ref mut color)) = lookup.candidates.iter_mut().find(|&&mut(NodeContact{id,..}, _)| id == from_id) {
+                                *color = Color::Black;
+                            }
+                            if !lookup.answers.iter().any(|&(id, _)| id == from_id) {
+                                lookup.answers.push((from_id, val.clone()));
+                            }
+                            if let Some(ref mut trace) = lookup.trace {
+                                trace.answered(&from_id, &[], true);
+                            }
+                            if ap.advance(lookup, &alpha_sock) {
+                                finished.push(index);
                             }
+                        }
+                        for index in finished.into_iter().rev() {
+                            let lookup = lookup_qi.remove(index);
+                            ap.complete(lookup, &alpha_sock, &to_api, &mut pending_writes, &mut traces);
+                        }
+                    },
+                    AsyncAction::StoreAck(key, from_id, status) => {
+                        let done = match pending_writes.iter_mut().position(|p| p.key == key && p.awaiting.contains(&from_id)) {
+                            Some(index) => {
+                                pending_writes[index].acknowledge(&from_id, status);
+                                if pending_writes[index].is_done() { Some(index) } else { None }
+                            },
+                            None => None
                         };
-                        if is_new { //assumption here is that new entries cannot be in a finished state
-                            let mut new_entry = Vec::new();
-                            Self::merge_into(&mut new_entry, &mut close_nodes, &key);
-                            Self::color(&mut new_entry, ALPHA_FACTOR - find_out.len(), |&mut find_entry| {
-                                let NodeContact{ref ip, ref port, ..} = find_entry;
-                                let _ = alpha_sock.send_to(&ap.find_node_msg(&key), ip_port_pair(ip, port));
-                                find_out.push((find_entry, get_time().sec+1));
-                            });
-                            lookup_qi.push((key, new_entry));
+                        if let Some(index) = done {
+                            pending_writes.remove(index).resolve(&to_api);
                         }
+                    },
+                    AsyncAction::Trace(key) => {
+                        trace_next.insert(key);
+                    },
+                    AsyncAction::Admin(query, reply) => {
+                        let _ = reply.send(match query {
+                            AdminQuery::Traces(key) => traces_json(&traces.of(key)),
+                            _ => lookups_json(&lookup_qi.iter().map(Lookup::status).collect::<Vec<LookupStatus>>())
+                        });
                     }
                 }
+                ap.metrics.set_lookups_in_flight(lookup_qi.len());
+            }
+            //the node is shutting down, the writes still waiting get the acks they have so far
+            for pending in pending_writes {
+                pending.resolve(&to_api);
             }
         })
     }
 
     ///proto thread waits for messages from other nodes to come in over a designated UdpSocket.
-    ///Valid protocol messages are passed onto the state thread
-    fn spawn_proto_thread(mut receiver: UdpSocket, m_tx: Sender<MessageType>) -> JoinHandle<()> {
+    ///Valid protocol messages are passed onto the state thread. The socket times out every poll
+    ///so the thread notices when it is stopped. Messages over the rate limits of their source are
+    ///dropped before they reach the state thread, as are those without a valid HMAC if the cluster
+    ///has a key. Sealed messages are opened here
+    fn spawn_proto_thread(mut receiver: UdpSocket, m_tx: Sender<MessageType>, metrics: Arc<Metrics>, config: &Config, sessions: Option<Sessions>, logger: Loggerator, stop: Shutdown) -> JoinHandle<()> {
+        let _ = receiver.set_read_timeout(Some(Duration::from_millis(config.async_poll_interval as u64)));
+        let ban_secs = config.protocol_limits.ban_secs;
+        let mut limiter = RateLimiter::new(config.protocol_limits.clone());
+        let auth = config.cluster_auth.clone();
         thread::spawn(move|| {
-            loop {
-                match receiver.wait_for_message() {
-                    Ok((message, node_id, address)) => {
-                        let _ = m_tx.send(MessageType::FromNode(message, node_id, address));
+            let mut buf = [0; MAX_MESSAGE_LEN];
+            while !stop.is_triggered() {
+                match receiver.wait_for_view(&mut buf, auth.as_ref(), sessions.as_ref()) {
+                    Ok((view, node_id, address)) => {
+                        metrics.received(view.opcode());
+                        match limiter.check(address.ip(), view.opcode(), Instant::now()) {
+                            Verdict::Allow => (),
+                            verdict => {
+                                metrics.rate_limited(view.opcode());
+                                if verdict == Verdict::Ban {
+                                    metrics.banned();
+                                    logger.warn("banned flooding source", &[("ip", &address.ip()), ("secs", &ban_secs)]);
+                                }
+                                continue
+                            }
+                        }
+                        //only messages the state thread gets are copied out of the buffer
+                        let _ = m_tx.send(MessageType::FromNode(view.to_message(), *node_id, address));
                     },
+                    Err(ref e) if e.kind() == ErrorKind::InvalidData => metrics.decode_failed(),
+                    Err(ref e) if e.kind() == ErrorKind::PermissionDenied => metrics.auth_failed(),
                     _ => ()
                 };
             }
@@ -311,20 +836,17 @@ impl AilmedakMachine {
     }
 
     /// 'Colors' at most num_to_color elements Grey and runs a function accepting a generic T
-    fn color <F, T>(field: &mut[(T, Color)], num_to_color: usize, mut func: F) where F:FnMut(&mut T) -> (){
+    fn color <F, T>(field: &mut[(T, Color)], num_to_color: usize, mut func: F) where F:FnMut(&mut T) {
         //i wonder how the FP facilities in rust compare
         let mut num_left = num_to_color;
         for &mut(ref mut t, ref mut c) in field.iter_mut() {
-            match c {
-                &mut Color::White => {
-                    *c = Color::Grey(get_time().sec + 1);
-                    func(t);
-                    if num_left == 0 {
-                        break
-                    }
-                    num_left -= 1;
-                },
-                _ => ()
+            if num_left == 0 {
+                break
+            }
+            if *c == Color::White {
+                *c = Color::Grey(get_time().sec + 1);
+                func(t);
+                num_left -= 1;
             }
         }
     }
@@ -402,9 +924,77 @@ impl AilmedakMachine {
 
 }
 
+/// the initial neighbors to fall back on if no contact restored from disk answers by the deadline
+struct Bootstrap {
+    deadline: i64,
+    neighbors: Vec<String>
+}
+
+/// periodic chores of the state thread that are not part of the protocol itself
+struct Housekeeping {
+    data_dir: Option<PathBuf>,
+    routing_interval: i64,
+    last_routing_save: i64,
+    bootstrap: Option<Bootstrap>,
+    sync_interval: i64,
+    last_sync: i64,
+    //how many of the closest contacts share replicas with this node, and which of them is next
+    sync_peers: usize,
+    next_peer: usize,
+    leave_timeout: i64
+}
+
+impl Housekeeping {
+    fn tick (&mut self, state: &mut KademliaNode, now: i64) {
+        let fall_back = match self.bootstrap {
+            Some(ref b) => now >= b.deadline,
+            None => false
+        };
+        if fall_back {
+            if let Some(b) = self.bootstrap.take() {
+                if state.buckets.iter().all(|bucket| bucket.is_empty()) {
+                    state.logger.info("no saved contacts responded, pinging initial neighbors", &[]);
+                    AilmedakMachine::ping_all(state, &b.neighbors);
+                }
+            }
+        }
+        if now - self.last_routing_save >= self.routing_interval {
+            self.persist_routing(state);
+            self.last_routing_save = now;
+        }
+        if now - self.last_sync >= self.sync_interval {
+            //one neighbor per round, taking turns
+            let peers = state.sync_peers(self.sync_peers);
+            if !peers.is_empty() {
+                let peer = peers[self.next_peer % peers.len()];
+                self.next_peer = self.next_peer.wrapping_add(1);
+                state.start_sync(peer);
+            }
+            self.last_sync = now;
+        }
+    }
+
+    /// saves the k-bucket contacts to the data directory, if there is one
+    fn persist_routing (&self, state: &KademliaNode) {
+        if let Some(ref dir) = self.data_dir {
+            if let Err(e) = save_contacts(dir, &state.contacts()) {
+                state.logger.error("unable to save contacts", &[("reason", &e)]);
+            }
+        }
+    }
+}
+
 struct AlphaProcessor {
     id: NodeAddr,
-    k_val: usize
+    k_val: usize,
+    //number of nodes (N) writes are replicated to
+    replication: usize,
+    events: Events,
+    metrics: Arc<Metrics>,
+    logger: Loggerator,
+    //write tokens other nodes handed out, shared with the state thread
+    peer_tokens: PeerTokens,
+    wire: Wire
 }
 
 impl ProtoMessage for AlphaProcessor {
@@ -413,7 +1003,255 @@ impl ProtoMessage for AlphaProcessor {
     }
 }
 
-type FindEntry = (NodeAddr, [u8; 4], u16);
+impl AlphaProcessor {
+    fn send <A: ToSocketAddrs> (&self, sock: &UdpSocket, msg: &[u8], addr: A) {
+        self.metrics.sent(msg);
+        self.wire.send(sock, msg, addr);
+    }
+
+    /// sends a store or delete of key to dst, asking dst for a write token with a FindNode of key
+    /// if it has to wait for one
+    fn send_write (&self, sock: &UdpSocket, msg: &[u8], key: &Key, dst: SocketAddr) {
+        match self.peer_tokens.stamp(msg.to_vec(), dst, get_time().sec) {
+            Stamped::Ready(msg) => self.send(sock, &msg, dst),
+            Stamped::Waiting {ask: true} => self.send(sock, &self.find_node_msg(key), dst),
+            Stamped::Waiting {ask: false} => ()
+        }
+    }
+
+    /// Queries more candidates of a lookup if fewer than ALPHA_FACTOR are in flight. Returns true
+    /// once the lookup is finished, either because it converged or because no one is left to ask
+    fn advance (&self, lookup: &mut Lookup, sock: &UdpSocket) -> bool {
+        if let LookupPurpose::Get(_, quorum, _) = lookup.purpose {
+            if lookup.answers.len() >= quorum {
+                return true
+            }
+        }
+        if is_lookup_finished!(self.k_val, lookup.candidates) {
+            return true
+        }
+        let in_flight = lookup.candidates.iter().filter(|(_, c)| c.is_grey()).count();
+        if in_flight < ALPHA_FACTOR {
+            let msg = match lookup.purpose {
+                LookupPurpose::Get(..) => self.find_val_msg(&lookup.key).to_vec(),
+                _ => self.find_node_msg(&lookup.key).to_vec()
+            };
+            let trace = &mut lookup.trace;
+            AilmedakMachine::color(&mut lookup.candidates, ALPHA_FACTOR - in_flight, |find_entry| {
+                let NodeContact{ref id, ref ip, ref port} = *find_entry;
+                self.send(sock, &msg, ip_port_pair(ip, port));
+                if let Some(ref mut trace) = *trace {
+                    trace.colored(id, "grey");
+                }
+            });
+        }
+        !lookup.candidates.iter().any(|(_, c)| c.is_grey())
+    }
+
+    /// Carries out the purpose of a finished lookup
+    fn complete (&self, lookup: Lookup, sock: &UdpSocket, to_api: &Sender<Callback>, pending_writes: &mut Vec<PendingWrite>, traces: &mut Traces) {
+        let Lookup {key, candidates, purpose, answers, started, hops, trace} = lookup;
+        //kept before anyone hears back, so a client that asked for the trace finds it
+        if let Some(mut trace) = trace {
+            trace.finish();
+            traces.push(trace);
+        }
+        self.metrics.lookup_latency.observe(started.elapsed().as_secs_f64());
+        let reached = candidates.iter()
+                                .find(|(_, color)| *color == Color::Black)
+                                .and_then(|(contact, _)| hops.get(&contact.id));
+        if let Some(&depth) = reached {
+            self.metrics.lookup_hops.observe(depth as f64);
+        }
+        let closest = candidates.into_iter()
+                                .filter(|(_, color)| *color == Color::Black)
+                                .map(|(contact, _)| contact)
+                                .take(self.k_val)
+                                .collect::<Vec<NodeContact<Key>>>();
+        self.events.emit(Event::LookupFinished(key, closest.clone()));
+        match purpose {
+            LookupPurpose::Refresh => {
+                self.logger.debug("lookup finished", &[("key", &as_hex_string(&key)), ("nodes", &closest.len())]);
+            },
+            LookupPurpose::Join => {
+                self.logger.info("joined the network", &[("nodes", &closest.len())]);
+                self.events.emit(Event::JoinCompleted(closest));
+            },
+            LookupPurpose::Find(reply) => {
+                let _ = reply.send(closest);
+            },
+            LookupPurpose::Get(request, quorum, _) => {
+                let latest = reconcile(&answers);
+                let result = if answers.is_empty() {
+                    ReadResult::NotFound
+                } else if answers.len() < quorum {
+                    ReadResult::QuorumFailed
+                } else {
+                    ReadResult::Found(latest.clone())
+                };
+                let _ = to_api.send(Callback::Resolve(request, result));
+                self.repair(&key, &latest, &answers, &closest, sock);
+            },
+            LookupPurpose::Store(request, clock, val, local_status, quorum) => {
+                let msg = self.store_msg(&key, &clock, &val, &NO_TOKEN);
+                let pending = self.replicate(PendingWrite::new(request, key, quorum), &closest, &msg, local_status, sock);
+                pending.settle(to_api, pending_writes);
+            },
+            LookupPurpose::Delete(request, clock, local_status, quorum) => {
+                let msg = self.delete_msg(&key, &clock, &NO_TOKEN);
+                let pending = self.replicate(PendingWrite::new(request, key, quorum), &closest, &msg, local_status, sock);
+                pending.settle(to_api, pending_writes);
+            }
+        }
+    }
+
+    /// Read repair, done once the client has its answer. Every node found by a Get is sent the
+    /// latest versions it is missing: those that answered with older versions, and those among
+    /// the N closest that had no value at all. Their StoreResps are not waited on. The local copy
+    /// is not repaired
+    fn repair (&self, key: &Key, latest: &[Sibling], answers: &[(NodeAddr, Vec<Sibling>)], closest: &[NodeContact<Key>], sock: &UdpSocket) {
+        let mut repaired = 0;
+        for (i, contact) in closest.iter().enumerate() {
+            let held = match answers.iter().find(|(id, _)| *id == contact.id) {
+                Some((_, siblings)) => siblings.as_slice(),
+                None if i < self.replication => &[],
+                None => continue
+            };
+            let missing = latest.iter().filter(|(clock, _)| !held.iter().any(|(c, _)| c.descends(clock))).collect::<Vec<_>>();
+            for (clock, val) in missing.iter() {
+                self.send_write(sock, &self.store_msg(key, clock, val, &NO_TOKEN), key, SocketAddr::from(ip_port_pair(&contact.ip, &contact.port)));
+            }
+            if !missing.is_empty() {
+                repaired += 1;
+            }
+        }
+        if repaired > 0 {
+            self.logger.debug("read repair sent", &[("key", &as_hex_string(key)), ("nodes", &repaired)]);
+        }
+    }
+
+    /// Sends a store or delete to the N closest nodes of the key of a write, returning it waiting
+    /// on their acknowledgements. local_status counts as one if this node is among those N itself
+    fn replicate (&self, mut pending: PendingWrite, closest: &[NodeContact<Key>], msg: &[u8], local_status: Option<StoreStatus>, sock: &UdpSocket) -> PendingWrite {
+        let key = pending.key;
+        //this node holds one of the N replicas if fewer than N of the nodes found are closer
+        let own_dist = KademliaNode::dist_as_bytes(&self.id, &key);
+        let closer = closest.iter().filter(|c| KademliaNode::dist_as_bytes(&c.id, &key) < own_dist).count();
+        let is_replica = closer < self.replication;
+        let remote = if is_replica { self.replication - 1 } else { self.replication };
+
+        let targets = closest.iter().take(remote).collect::<Vec<_>>();
+        for NodeContact{ip, port, ..} in targets.iter() {
+            self.send_write(sock, msg, &key, SocketAddr::from(ip_port_pair(ip, port)));
+        }
+        pending.awaiting = targets.iter().map(|c| c.id).collect();
+        match local_status {
+            Some(status) if is_replica => pending.acknowledge(&self.id, status),
+            _ => ()
+        };
+        pending
+    }
+}
+
+/// a lookup in progress in the alpha thread
+struct Lookup {
+    key: Key,
+    candidates: Vec<(NodeContact<Key>, Color)>,
+    purpose: LookupPurpose,
+    //siblings found so far, with the id of the node that had them
+    answers: Vec<(NodeAddr, Vec<Sibling>)>,
+    started: Instant,
+    //how many hops away from this node each candidate was learned of
+    hops: HashMap<NodeAddr, usize>,
+    //the record of every hop, if the lookup is traced
+    trace: Option<LookupTrace>
+}
+
+impl Lookup {
+    fn status (&self) -> LookupStatus {
+        let count = |f: &dyn Fn(&Color) -> bool| self.candidates.iter().filter(|(_, c)| f(c)).count();
+        LookupStatus {
+            key: self.key,
+            purpose: self.purpose.name(),
+            age: self.started.elapsed(),
+            waiting: count(&|c| *c == Color::White),
+            in_flight: count(&|c| c.is_grey()),
+            answered: count(&|c| *c == Color::Black),
+            timed_out: count(&|c| *c == Color::Yellow)
+        }
+    }
+}
+
+/// Combines the siblings returned by the replicas. Versions that another version supersedes are
+/// dropped, leaving the latest versions, which are concurrent with each other
+fn reconcile (answers: &[(NodeAddr, Vec<Sibling>)]) -> Vec<Sibling> {
+    let mut latest: Vec<Sibling> = Vec::new();
+    for (clock, val) in answers.iter().flat_map(|(_, siblings)| siblings.iter()) {
+        if latest.iter().any(|(c, _)| c.descends(clock)) {
+            continue
+        }
+        latest.retain(|(c, _)| !clock.descends(c));
+        latest.push((clock.clone(), val.clone()));
+    }
+    latest
+}
+
+/// a store or delete sent out to the closest nodes of a key, waiting on their StoreResps
+struct PendingWrite {
+    //the client request it is answered to
+    request: RequestId,
+    key: Key,
+    awaiting: Vec<NodeAddr>,
+    acks: usize,
+    //acks needed for the write to succeed
+    quorum: usize,
+    //the first rejection, reported if no replica accepts the value
+    status: Option<StoreStatus>,
+    expires_at: i64
+}
+
+impl PendingWrite {
+    fn new (request: RequestId, key: Key, quorum: usize) -> PendingWrite {
+        PendingWrite {
+            request,
+            key,
+            awaiting: Vec::new(),
+            acks: 0,
+            quorum,
+            status: None,
+            expires_at: get_time().sec + DEFAULT_TTL
+        }
+    }
+
+    fn acknowledge (&mut self, from_id: &NodeAddr, status: StoreStatus) {
+        self.awaiting.retain(|id| id != from_id);
+        if status == StoreStatus::Ok {
+            self.acks += 1;
+        } else if self.status.is_none() {
+            self.status = Some(status);
+        }
+    }
+
+    /// true once the quorum is met or there is no one left to hear from
+    fn is_done (&self) -> bool {
+        self.acks >= self.quorum || self.awaiting.is_empty()
+    }
+
+    /// reports back to the client how many replicas acknowledged the write
+    fn resolve (self, to_api: &Sender<Callback>) {
+        let status = if self.acks >= self.quorum { Some(StoreStatus::Ok) } else { self.status };
+        let _ = to_api.send(Callback::Written(self.request, status, self.acks));
+    }
+
+    /// resolves the write right away if it is already done, otherwise queues it up to wait
+    fn settle (self, to_api: &Sender<Callback>, pending_writes: &mut Vec<PendingWrite>) {
+        if self.is_done() {
+            self.resolve(to_api);
+        } else {
+            pending_writes.push(self);
+        }
+    }
+}
 
 #[derive(Clone, PartialEq, Debug)]
 ///Colors a (kbucket) value representing its status in an arbitrary asynchronous lookup operation
@@ -423,3 +1261,9 @@ enum Color {
     White, // Unvisited
     Yellow // Quarantined (Timedout)
 }
+
+impl Color {
+    fn is_grey (&self) -> bool {
+        matches!(*self, Color::Grey(_))
+    }
+}
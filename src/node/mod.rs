@@ -1,2 +1,10 @@
 pub mod machine;
 pub mod state;
+pub mod anti_entropy;
+pub mod handoff;
+pub mod leave;
+pub mod handle;
+pub mod events;
+pub mod trace;
+pub mod diversity;
+pub mod tokens;
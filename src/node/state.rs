@@ -1,12 +1,26 @@
 use rand::{thread_rng, Rng, Rand};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket, IpAddr};
+use std::io;
+use std::sync::Arc;
 use std::collections::HashMap;
+use time::get_time;
+use std::mem;
 use std::sync::mpsc::{Sender};
-use message_protocol::{Key, ProtoMessage, NodeContact};
-use node::machine::{EvictionCandidate, AsyncAction};
+use message_protocol::{Key, Value, ProtoMessage, NodeContact};
+use node::machine::{EvictionCandidate, AsyncAction, LookupPurpose};
 use utils::networking::{ip_port_pair_bytes};
 use utils::{u8_2_to_u16};
+use storage::{ValueStore, StoredValue, StoreError};
+use storage::version::VectorClock;
+use node::handoff::{Handoff, DEFAULT_HANDOFF_BATCH};
+use node::anti_entropy::SyncTrees;
+use node::events::{Events, Event};
+use node::diversity::{DiversityLimits, Replacements};
+use node::tokens::{WriteTokens, PeerTokens, DEFAULT_TOKEN_ROTATION};
+use transport::Wire;
+use metrics::Metrics;
+use utils::loggerator::{Loggerator, LogSink};
+use utils::fmt::as_hex_string;
 
 //the size of address space, in bytes
 macro_rules! addr_spc { () => { 20 } }
@@ -92,6 +106,8 @@ macro_rules! meta_k_bucket {
 
 pub type NodeAddr = [u8; addr_spc!()];
 pub type BucketArray = [Vec<(NodeAddr, SocketAddr)>; addr_spc!() * 8 + 1];
+///the k-buckets that hold contacts, by index
+pub type RoutingTable = Vec<(usize, Vec<(NodeAddr, SocketAddr)>)>;
 
 ///Defines a trait called ASizedNode (using the meta_node template) using a fixed length array of
 ///an arbitrary type as id (currently set to 20)
@@ -100,9 +116,27 @@ meta_node!(ASizedNode (id_len = addr_spc!()));
 pub struct KademliaNode {
     pub addr_id: NodeAddr,
     pub buckets: BucketArray,
+    //unix time in seconds each contact in the k-buckets was last heard from
+    pub last_seen: HashMap<NodeAddr, i64>,
+    //caps on contacts from the same ip or prefix, and the contacts turned away for them
+    pub diversity: DiversityLimits,
+    pub replacements: Replacements,
+    //the write tokens this node hands out, and those other nodes handed it
+    pub write_tokens: WriteTokens,
+    pub peer_tokens: PeerTokens,
+    //the pre-shared keys datagrams are authenticated with and the sessions they are encrypted in
+    pub wire: Wire,
     pub k_val: usize,
-    pub data: HashMap<Key, Vec<u8>>,
-    pub socket: UdpSocket
+    pub data: ValueStore,
+    pub socket: UdpSocket,
+    pub handoff: Handoff,
+    //the trees shared with peers in anti-entropy exchanges in progress
+    pub sync_trees: SyncTrees,
+    //set once the node started leaving the network, it takes no more writes
+    pub leaving: bool,
+    pub events: Events,
+    pub metrics: Arc<Metrics>,
+    pub logger: Loggerator
 }
 
 ///Implements ProtoMessage so we can create Message envelopes
@@ -130,7 +164,7 @@ impl ASizedNode<u8> for KademliaNode {
 /// provides facilities for retrieving and putting into k-buckets (governed by distance)
 /// and returning the k closest known nodes (that are considered active) to a given id
 impl KademliaNode {
-    pub fn new (id: NodeAddr, k_val: usize, write_socket: UdpSocket) -> KademliaNode {
+    pub fn new (id: NodeAddr, k_val: usize, data: ValueStore, write_socket: UdpSocket) -> KademliaNode {
         let mut buckets:BucketArray = unsafe {mem::uninitialized()};
         for i in buckets.iter_mut() {
             unsafe {::std::ptr::write(i, Vec::with_capacity(k_val)) };
@@ -138,9 +172,21 @@ impl KademliaNode {
         KademliaNode {
             addr_id: id,
             buckets: buckets,
+            last_seen: HashMap::new(),
+            diversity: DiversityLimits::default(),
+            replacements: Replacements::new(),
+            write_tokens: WriteTokens::new(DEFAULT_TOKEN_ROTATION, get_time().sec),
+            peer_tokens: PeerTokens::new(DEFAULT_TOKEN_ROTATION),
+            wire: Wire::default(),
             k_val: k_val,
-            data: HashMap::new(),
-            socket: write_socket
+            data: data,
+            socket: write_socket,
+            handoff: Handoff::new(k_val, DEFAULT_HANDOFF_BATCH),
+            sync_trees: SyncTrees::default(),
+            leaving: false,
+            events: Events::new(),
+            metrics: Arc::new(Metrics::new()),
+            logger: Loggerator::new(&id, module_path!(), Arc::new(LogSink::stdout()))
         }
     }
 
@@ -159,14 +205,31 @@ impl KademliaNode {
         }
     }
 
-    ///updates the k buckets to enforce least recently seen ordering
+    ///updates the k buckets to enforce least recently seen ordering. a contact that was not known
+    ///before is handed off the stored values it is now one of the closest nodes to, unless it is
+    ///over the diversity limits, which puts it in the replacement cache of the bucket instead
     pub fn update_k_bucket (&mut self, k_index: usize, tup: (NodeAddr, SocketAddr)) -> Option<EvictionCandidate> {
-        let (node_id, _) = tup;
-        let mut k_bucket = &mut self.buckets[k_index];
-        let _ = k_bucket.retain(|&(n, _)| node_id != n);
+        let (node_id, addr) = tup;
+        if !self.buckets[k_index].iter().any(|&(n, _)| n == node_id) && !self.admits(k_index, addr) {
+            self.replacements.push(k_index, tup, self.k_val);
+            self.metrics.contact_rejected();
+            self.logger.debug("contact over the diversity limits", &[("id", &as_hex_string(&node_id)), ("addr", &addr)]);
+            return None
+        }
+        let k_bucket = &mut self.buckets[k_index];
+        let known = k_bucket.len();
+        k_bucket.retain(|&(n, _)| node_id != n);
+        let is_new = k_bucket.len() == known;
         if k_bucket.len() < self.k_val {
             //add contact info if below threshold
             k_bucket.push(tup);
+            self.last_seen.insert(node_id, get_time().sec);
+            if is_new {
+                self.events.emit(Event::ContactAdded(tup.0, tup.1));
+                self.queue_handoff(tup);
+            } else {
+                self.events.emit(Event::ContactRefreshed(tup.0, tup.1));
+            }
             None
         } else {
             //TODO: need to ping
@@ -182,12 +245,13 @@ impl KademliaNode {
     ///Ailmedak's (naive) version of locate node
     ///A vector of closest addresses of length {K factor} or {the total number of contacts} (whichever is smaller is) is
     ///returned
-    pub fn find_k_closest_global(&self, target_node_id: Key, alpha_channel: &Sender<AsyncAction>) {
+    ///The lookup is carried out by the alpha thread, which acts on purpose once it is finished
+    pub fn find_k_closest_global(&self, target_node_id: Key, purpose: LookupPurpose, alpha_channel: &Sender<AsyncAction>) {
         let local_closest = self.find_k_closest(&target_node_id).iter().map(|&(_, (node_id, (ip, port)))| {
             NodeContact{id: node_id, ip: ip, port: u8_2_to_u16(&port)}
         }).collect::<Vec<NodeContact<Key>>>();
-        //TODO: consider case where there are no contacts
-        let _ = alpha_channel.send(AsyncAction::LookupResults(target_node_id, local_closest, None));
+        self.events.emit(Event::LookupStarted(target_node_id));
+        let _ = alpha_channel.send(AsyncAction::StartLookup(target_node_id, local_closest, purpose));
     }
 
     /// finds locally, the k closest nodes to the target_node_id
@@ -233,8 +297,42 @@ impl KademliaNode {
         })
     }
 
+    /// a copy of the k-buckets that are not empty, contacts least recently seen first
+    pub fn routing_table (&self) -> RoutingTable {
+        self.buckets.iter()
+                    .enumerate()
+                    .filter(|(_, bucket)| !bucket.is_empty())
+                    .map(|(i, bucket)| (i, bucket.clone()))
+                    .collect()
+    }
+
+    /// ValueStore::put_version, telling subscribers if it changed what is stored under key
+    pub fn put_version (&mut self, key: Key, val: Value, clock: VectorClock, source: Option<IpAddr>) -> Result<Vec<Key>, StoreError> {
+        let before = self.data.merkle().digest(&key);
+        let result = self.data.put_version(key, val, clock, source);
+        if result.is_ok() && self.data.merkle().digest(&key) != before {
+            self.events.emit(Event::ValueStored(key));
+        }
+        result
+    }
+
+    /// ValueStore::delete_version, telling subscribers if versions were deleted
+    pub fn delete_version (&mut self, key: &Key, clock: VectorClock) -> io::Result<Option<Vec<StoredValue>>> {
+        let result = self.data.delete_version(key, clock);
+        if let Ok(Some(_)) = result {
+            self.events.emit(Event::ValueDeleted(*key));
+        }
+        result
+    }
+
+    /// every contact across all k-buckets
+    pub fn contacts (&self) -> Vec<(NodeAddr, SocketAddr)> {
+        self.buckets.iter().flat_map(|bucket| bucket.iter().cloned()).collect()
+    }
+
     pub fn send_msg <A:ToSocketAddrs> (&self, msg: &[u8], addr: A) {
-        let _ = self.socket.send_to(msg, addr);
+        self.metrics.sent(msg);
+        self.wire.send(&self.socket, msg, addr);
     }
 
 }
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use crypto::sha1::Sha1;
+use crypto::digest::Digest;
+use rand::{thread_rng, Rng};
+use time::get_time;
+use message_protocol::{Key, Token, TOKEN_LEN, STORE_TOKEN_OFFSET, OP_STORE, OP_DELETE, ProtoMessage};
+use node::state::KademliaNode;
+
+/// default seconds between rotations of the secret write tokens are derived from
+pub const DEFAULT_TOKEN_ROTATION: i64 = 300;
+/// most stores held for a node whose token has not arrived yet
+const MAX_WAITING: usize = 256;
+
+/// Hands out write tokens (BEP 5 style) in FindNode and FindVal responses and checks them on
+/// Stores. A token is a hash of the requester's ip and a secret that rotates every interval. The
+/// previous secret is still accepted, so a token is good for at least one interval, which keeps
+/// spoofed sources (that never see the responses) from storing anything
+pub struct WriteTokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: i64,
+    interval: i64
+}
+
+impl WriteTokens {
+    pub fn new (interval: i64, now: i64) -> WriteTokens {
+        let secret = new_secret();
+        WriteTokens {secret, previous: secret, rotated_at: now, interval}
+    }
+
+    /// the token for whoever sends from ip
+    pub fn issue (&self, ip: IpAddr) -> Token {
+        derive(&self.secret, ip)
+    }
+
+    /// whether token was issued to ip under the current or the previous secret
+    pub fn accepts (&self, ip: IpAddr, token: &Token) -> bool {
+        *token == derive(&self.secret, ip) || *token == derive(&self.previous, ip)
+    }
+
+    pub fn rotate_if_due (&mut self, now: i64) {
+        if now - self.rotated_at >= self.interval {
+            self.previous = self.secret;
+            self.secret = new_secret();
+            self.rotated_at = now;
+        }
+    }
+}
+
+fn new_secret () -> [u8; 20] {
+    let mut secret = [0; 20];
+    thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+fn derive (secret: &[u8; 20], ip: IpAddr) -> Token {
+    let mut hasher = Sha1::new();
+    hasher.input(secret);
+    match ip {
+        IpAddr::V4(v4) => hasher.input(&v4.octets()),
+        IpAddr::V6(v6) => hasher.input(&v6.octets())
+    }
+    let mut digest = [0; 20];
+    hasher.result(&mut digest);
+    let mut token = [0; TOKEN_LEN];
+    token.copy_from_slice(&digest[..TOKEN_LEN]);
+    token
+}
+
+#[derive(Default)]
+struct Known {
+    //the last token each node gave this node, and when
+    tokens: HashMap<SocketAddr, (Token, i64)>,
+    //stores waiting on the token of their destination, and when they were queued
+    waiting: HashMap<SocketAddr, Vec<(Vec<u8>, i64)>>
+}
+
+/// What to do with a store
+pub enum Stamped {
+    //it carries the token of its destination and can be sent
+    Ready(Vec<u8>),
+    //it waits for the destination to hand out a token. ask is true if nothing else was waiting
+    //for it, so a FindNode should be sent to get one
+    Waiting {ask: bool}
+}
+
+/// The write tokens other nodes gave this node, for stamping the stores sent to them. Shared by
+/// the threads of a node that send stores, clones share the tokens
+#[derive(Clone)]
+pub struct PeerTokens {
+    known: Arc<Mutex<Known>>,
+    //seconds a token is used for, one rotation interval
+    lifetime: i64
+}
+
+impl PeerTokens {
+    pub fn new (lifetime: i64) -> PeerTokens {
+        PeerTokens {known: Arc::new(Mutex::new(Known::default())), lifetime}
+    }
+
+    /// Stamps a store or delete (built with NO_TOKEN) with the token of dst, or queues it until
+    /// dst hands one out. Other messages need no token and are ready as they are
+    pub fn stamp (&self, mut store: Vec<u8>, dst: SocketAddr, now: i64) -> Stamped {
+        if store.first() != Some(&OP_STORE) && store.first() != Some(&OP_DELETE) {
+            return Stamped::Ready(store)
+        }
+        let mut known = self.known.lock().unwrap();
+        match known.tokens.get(&dst) {
+            Some(&(token, at)) if now - at < self.lifetime => {
+                set_token(&mut store, &token);
+                Stamped::Ready(store)
+            },
+            _ => {
+                let waiting = known.waiting.entry(dst).or_default();
+                let ask = waiting.is_empty();
+                if waiting.len() < MAX_WAITING {
+                    waiting.push((store, now));
+                }
+                Stamped::Waiting {ask}
+            }
+        }
+    }
+
+    /// keeps the token src handed out and returns the stores that waited on it, stamped
+    pub fn received (&self, src: SocketAddr, token: Token, now: i64) -> Vec<Vec<u8>> {
+        let mut known = self.known.lock().unwrap();
+        known.tokens.insert(src, (token, now));
+        known.waiting.remove(&src).unwrap_or_default().into_iter().map(|(mut store, _)| {
+            set_token(&mut store, &token);
+            store
+        }).collect()
+    }
+
+    /// forgets tokens too old to use and stores that waited too long for one
+    pub fn expire (&self, now: i64) {
+        let mut known = self.known.lock().unwrap();
+        let lifetime = self.lifetime;
+        known.tokens.retain(|_, &mut (_, at)| now - at < lifetime);
+        known.waiting.retain(|_, stores| {
+            stores.retain(|&(_, at)| now - at < lifetime);
+            !stores.is_empty()
+        });
+    }
+}
+
+fn set_token (store: &mut [u8], token: &Token) {
+    if let Some(slot) = store.get_mut(STORE_TOKEN_OFFSET..STORE_TOKEN_OFFSET + TOKEN_LEN) {
+        slot.copy_from_slice(token);
+    }
+}
+
+impl KademliaNode {
+    /// sends a store or delete of key to dst, asking dst for a write token with a FindNode of key
+    /// if it has to wait for one
+    pub fn send_write (&self, msg: Vec<u8>, key: &Key, dst: SocketAddr) {
+        match self.peer_tokens.stamp(msg, dst, get_time().sec) {
+            Stamped::Ready(msg) => self.send_msg(&msg, dst),
+            Stamped::Waiting {ask: true} => self.send_msg(&self.find_node_msg(key), dst),
+            Stamped::Waiting {ask: false} => ()
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use message_protocol::{Key, NodeContact};
+use node::state::NodeAddr;
+
+/// What happened with one contact of a traced lookup. Times are since the lookup started
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub contact: NodeContact<Key>,
+    //when it was queried and when it answered, if it was and did
+    pub sent: Option<Duration>,
+    pub received: Option<Duration>,
+    //the colors it went through (white, grey, black or yellow) and when
+    pub colors: Vec<(Duration, &'static str)>,
+    //the contacts it returned
+    pub returned: Vec<NodeContact<Key>>,
+    //whether it answered with the value rather than with contacts
+    pub had_value: bool
+}
+
+/// The record of a lookup the alpha thread was asked to trace: every contact it considered, in
+/// the order it learned of them
+#[derive(Clone, Debug)]
+pub struct LookupTrace {
+    pub key: Key,
+    pub purpose: &'static str,
+    started: Instant,
+    //how long the lookup took, None while it is in progress
+    pub duration: Option<Duration>,
+    pub hops: Vec<Hop>
+}
+
+impl LookupTrace {
+    pub fn new (key: Key, purpose: &'static str) -> LookupTrace {
+        LookupTrace {key, purpose, started: Instant::now(), duration: None, hops: Vec::new()}
+    }
+
+    fn hop (&mut self, id: &NodeAddr) -> Option<&mut Hop> {
+        self.hops.iter_mut().find(|h| h.contact.id == *id)
+    }
+
+    /// records the contacts the lookup has not seen before as candidates
+    pub fn learned (&mut self, contacts: &[NodeContact<Key>]) {
+        let now = self.started.elapsed();
+        for contact in contacts.iter() {
+            if self.hop(&contact.id).is_none() {
+                self.hops.push(Hop {
+                    contact: *contact,
+                    sent: None,
+                    received: None,
+                    colors: vec![(now, "white")],
+                    returned: Vec::new(),
+                    had_value: false
+                });
+            }
+        }
+    }
+
+    /// records a contact turning color. grey means it was queried
+    pub fn colored (&mut self, id: &NodeAddr, color: &'static str) {
+        let now = self.started.elapsed();
+        if let Some(hop) = self.hop(id) {
+            if color == "grey" {
+                hop.sent = Some(now);
+            }
+            hop.colors.push((now, color));
+        }
+    }
+
+    /// records the answer of a contact, which turns it black
+    pub fn answered (&mut self, id: &NodeAddr, returned: &[NodeContact<Key>], had_value: bool) {
+        let now = self.started.elapsed();
+        if let Some(hop) = self.hop(id) {
+            //answers to a lookup that asked before are only recorded once
+            if hop.received.is_none() {
+                hop.received = Some(now);
+                hop.returned = returned.to_vec();
+                hop.had_value = had_value;
+                hop.colors.push((now, "black"));
+            }
+        }
+    }
+
+    pub fn finish (&mut self) {
+        self.duration = Some(self.started.elapsed());
+    }
+}
+
+/// number of finished traces the alpha thread keeps
+pub const MAX_TRACES: usize = 32;
+
+/// The most recently finished traces, oldest first
+#[derive(Default)]
+pub struct Traces {
+    finished: VecDeque<LookupTrace>
+}
+
+impl Traces {
+    pub fn new () -> Traces {
+        Traces::default()
+    }
+
+    /// keeps trace, forgetting the oldest one if MAX_TRACES are kept already
+    pub fn push (&mut self, trace: LookupTrace) {
+        if self.finished.len() >= MAX_TRACES {
+            self.finished.pop_front();
+        }
+        self.finished.push_back(trace);
+    }
+
+    /// the traces of lookups of key, or all of them if key is None
+    pub fn of (&self, key: Option<Key>) -> Vec<&LookupTrace> {
+        self.finished.iter().filter(|t| key.is_none_or(|k| t.key == k)).collect()
+    }
+}
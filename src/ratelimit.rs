@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// how often idle buckets and expired bans are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A sustained rate of messages per second, and how many may arrive at once on top of it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64
+}
+
+impl Rate {
+    pub fn new (per_sec: f64, burst: f64) -> Rate {
+        Rate {per_sec, burst}
+    }
+}
+
+/// How many messages any one source ip may send a socket, by message type
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    //rates of message types that have their own, by opcode
+    pub per_type: HashMap<u8, Rate>,
+    //the rate of every other message type, unlimited if None
+    pub default: Option<Rate>,
+    //a source that had this many messages dropped is banned, never if None
+    pub ban_after: Option<u32>,
+    //seconds a ban lasts
+    pub ban_secs: u64
+}
+
+impl RateLimits {
+    /// no limits at all
+    pub fn unlimited () -> RateLimits {
+        RateLimits {per_type: HashMap::new(), default: None, ban_after: None, ban_secs: 0}
+    }
+
+    /// default limits of rate on every message type, banning for a minute after 1000 drops
+    pub fn with_default (rate: Rate) -> RateLimits {
+        RateLimits {per_type: HashMap::new(), default: Some(rate), ban_after: Some(1000), ban_secs: 60}
+    }
+
+    /// Sets rates from a comma separated list: a bare rate sets the default and type=rate the
+    /// rate of a message type, by its name in names (indexed by opcode). Rates are messages per
+    /// second, optionally followed by the burst, e.g. `200,store=20:50`. The burst is the rate if
+    /// it is left off
+    pub fn set_rates (&mut self, spec: &str, names: &[&str]) -> Result<(), String> {
+        for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match part.find('=') {
+                Some(i) => {
+                    let name = &part[..i];
+                    let opcode = names.iter().position(|n| n.eq_ignore_ascii_case(name))
+                                      .ok_or_else(|| format!("unknown message type {}, expected one of {}", name, names.join(", ")))?;
+                    self.per_type.insert(opcode as u8, parse_rate(&part[i + 1..])?);
+                },
+                None => self.default = Some(parse_rate(part)?)
+            }
+        }
+        Ok(())
+    }
+
+    fn rate_of (&self, opcode: u8) -> Option<Rate> {
+        self.per_type.get(&opcode).cloned().or(self.default)
+    }
+}
+
+fn parse_rate (s: &str) -> Result<Rate, String> {
+    let mut parts = s.splitn(2, ':');
+    let number = |p: Option<&str>| p.and_then(|p| p.parse::<f64>().ok()).filter(|n| *n > 0.0);
+    let per_sec = number(parts.next()).ok_or_else(|| format!("invalid rate {}, expected RATE[:BURST]", s))?;
+    let burst = match parts.next() {
+        Some(b) => number(Some(b)).ok_or_else(|| format!("invalid burst in {}", s))?,
+        None => per_sec
+    };
+    Ok(Rate {per_sec, burst})
+}
+
+/// What to do with a message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    //the source is over the rate of the message type
+    Drop,
+    //the source went over its rates so often it is banned for a while
+    Banned,
+    //the message that got the source banned
+    Ban
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant
+}
+
+#[derive(Default)]
+struct Offender {
+    drops: u32,
+    banned_until: Option<Instant>
+}
+
+/// Token buckets per source ip and message type. A bucket holds up to the burst of its rate in
+/// tokens and refills at the rate; every message takes a token and is dropped if there is none
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: HashMap<(IpAddr, u8), TokenBucket>,
+    offenders: HashMap<IpAddr, Offender>,
+    last_prune: Instant
+}
+
+impl RateLimiter {
+    pub fn new (limits: RateLimits) -> RateLimiter {
+        RateLimiter {limits, buckets: HashMap::new(), offenders: HashMap::new(), last_prune: Instant::now()}
+    }
+
+    /// whether a message of opcode from ip, arriving at now, may go through
+    pub fn check (&mut self, ip: IpAddr, opcode: u8, now: Instant) -> Verdict {
+        if now.duration_since(self.last_prune) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+        if let Some(offender) = self.offenders.get(&ip) {
+            if offender.banned_until.is_some_and(|until| now < until) {
+                return Verdict::Banned
+            }
+        }
+        let rate = match self.limits.rate_of(opcode) {
+            Some(rate) => rate,
+            None => return Verdict::Allow
+        };
+        let bucket = self.buckets.entry((ip, opcode)).or_insert(TokenBucket {tokens: rate.burst, last: now});
+        let refill = now.duration_since(bucket.last).as_secs_f64() * rate.per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(rate.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Verdict::Allow
+        }
+        let offender = self.offenders.entry(ip).or_default();
+        offender.drops += 1;
+        match self.limits.ban_after {
+            Some(n) if offender.drops >= n => {
+                offender.drops = 0;
+                offender.banned_until = Some(now + Duration::from_secs(self.limits.ban_secs));
+                Verdict::Ban
+            },
+            _ => Verdict::Drop
+        }
+    }
+
+    /// Forgets buckets that have refilled, which are no different from new ones, and offenders
+    /// that are neither banned nor dropped anything lately. Keeps a flood of sources from growing
+    /// the maps for good
+    fn prune (&mut self, now: Instant) {
+        let limits = &self.limits;
+        self.buckets.retain(|&(_, opcode), bucket| {
+            limits.rate_of(opcode).is_some_and(|rate| {
+                bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate.per_sec < rate.burst
+            })
+        });
+        let active = self.buckets.keys().map(|&(ip, _)| ip).collect::<HashSet<IpAddr>>();
+        self.offenders.retain(|ip, offender| {
+            offender.banned_until.is_some_and(|until| now < until) || active.contains(ip)
+        });
+        self.last_prune = now;
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use crypto::sha1::Sha1;
+use crypto::digest::Digest;
+use message_protocol::Key;
+
+pub type Hash = [u8; 20];
+
+/// number of leading key bits that pick the leaf (bucket) of a key
+pub const LEAF_BITS: usize = 12;
+pub const LEAVES: usize = 1 << LEAF_BITS;
+/// nodes are numbered like a binary heap: the root is 1, the children of i are 2i and 2i + 1 and
+/// the leaves are LEAVES..2 * LEAVES
+pub const ROOT: u16 = 1;
+
+/// hash of an empty subtree
+const EMPTY: Hash = [0; 20];
+
+/// A Merkle tree over the keys of a store, bucketed by key prefix. Every leaf hashes the digests
+/// of the keys that fall into it and every inner node hashes its two children, so two stores hold
+/// the same versions of every key exactly when their roots match. Where they don't, comparing
+/// children narrows the difference down to the leaves (and keys) that differ
+pub struct MerkleTree {
+    //per leaf: key -> digest of what is stored under it
+    buckets: Vec<BTreeMap<Key, Hash>>,
+    nodes: Vec<Hash>
+}
+
+impl Default for MerkleTree {
+    fn default() -> MerkleTree {
+        MerkleTree::new()
+    }
+}
+
+impl MerkleTree {
+    pub fn new() -> MerkleTree {
+        MerkleTree {
+            buckets: vec![BTreeMap::new(); LEAVES],
+            nodes: vec![EMPTY; 2 * LEAVES]
+        }
+    }
+
+    /// the leaf key falls into
+    pub fn leaf_of(key: &Key) -> u16 {
+        let prefix = ((key[0] as usize) << 8 | key[1] as usize) >> (16 - LEAF_BITS);
+        (LEAVES + prefix) as u16
+    }
+
+    pub fn is_leaf(index: u16) -> bool {
+        index as usize >= LEAVES
+    }
+
+    pub fn children(index: u16) -> (u16, u16) {
+        (2 * index, 2 * index + 1)
+    }
+
+    pub fn root(&self) -> Hash {
+        self.nodes[ROOT as usize]
+    }
+
+    /// hash of the node at index, None if there is no such node
+    pub fn hash(&self, index: u16) -> Option<Hash> {
+        match index as usize {
+            0 => None,
+            i => self.nodes.get(i).cloned()
+        }
+    }
+
+    /// the digest of what is stored under key, None if nothing is
+    pub fn digest(&self, key: &Key) -> Option<Hash> {
+        let leaf = MerkleTree::leaf_of(key) as usize;
+        self.buckets[leaf - LEAVES].get(key).cloned()
+    }
+
+    /// the keys of a leaf along with their digests, in key order
+    pub fn bucket(&self, leaf: u16) -> Vec<(Key, Hash)> {
+        match (leaf as usize).checked_sub(LEAVES).and_then(|i| self.buckets.get(i)) {
+            Some(bucket) => bucket.iter().map(|(k, h)| (*k, *h)).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// sets the digest of key (None once nothing is stored under it) and rehashes the path from
+    /// its leaf up to the root
+    pub fn update(&mut self, key: &Key, digest: Option<Hash>) {
+        let leaf = MerkleTree::leaf_of(key);
+        let bucket = &mut self.buckets[leaf as usize - LEAVES];
+        let changed = match digest {
+            Some(d) => bucket.insert(*key, d) != Some(d),
+            None => bucket.remove(key).is_some()
+        };
+        if !changed {
+            return
+        }
+        self.nodes[leaf as usize] = leaf_hash(bucket);
+        let mut index = leaf as usize / 2;
+        while index >= ROOT as usize {
+            self.nodes[index] = inner_hash(&self.nodes[2 * index], &self.nodes[2 * index + 1]);
+            index /= 2;
+        }
+    }
+
+    /// a tree over only the keys of this one that keep lets through
+    pub fn filter<F: Fn(&Key) -> bool>(&self, keep: F) -> MerkleTree {
+        let mut tree = MerkleTree::new();
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            let kept = &mut tree.buckets[i];
+            kept.extend(bucket.iter().filter(|(k, _)| keep(k)).map(|(k, h)| (*k, *h)));
+            tree.nodes[LEAVES + i] = leaf_hash(kept);
+        }
+        for index in (ROOT as usize..LEAVES).rev() {
+            tree.nodes[index] = inner_hash(&tree.nodes[2 * index], &tree.nodes[2 * index + 1]);
+        }
+        tree
+    }
+}
+
+fn leaf_hash(bucket: &BTreeMap<Key, Hash>) -> Hash {
+    if bucket.is_empty() {
+        return EMPTY
+    }
+    let mut sha = Sha1::new();
+    for (k, h) in bucket.iter() {
+        sha.input(k);
+        sha.input(h);
+    }
+    finish(sha)
+}
+
+fn inner_hash(left: &Hash, right: &Hash) -> Hash {
+    if *left == EMPTY && *right == EMPTY {
+        return EMPTY
+    }
+    let mut sha = Sha1::new();
+    sha.input(left);
+    sha.input(right);
+    finish(sha)
+}
+
+pub fn finish(mut sha: Sha1) -> Hash {
+    let mut hash: Hash = [0; 20];
+    sha.result(&mut hash);
+    hash
+}
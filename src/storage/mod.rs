@@ -0,0 +1,658 @@
+use std::collections::HashMap;
+use std::cell::Cell;
+use std::fs;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use time::get_time;
+use crypto::sha1::Sha1;
+use crypto::digest::Digest;
+use message_protocol::Key;
+
+pub mod wal;
+pub mod snapshot;
+pub mod routing;
+pub mod quota;
+pub mod version;
+pub mod merkle;
+
+use self::wal::{WriteAheadLog, Record};
+pub use self::quota::{StoreLimits, EvictionPolicy, StoreError};
+use self::version::VectorClock;
+use self::merkle::MerkleTree;
+
+/// default lifetime of a stored value in seconds (tExpire in the Kademlia paper)
+pub const DEFAULT_VALUE_TTL: i64 = 86400;
+
+/// default lifetime of a tombstone in seconds. it has to outlast any copy of the deleted value
+/// that might still be republished or cached elsewhere
+pub const DEFAULT_TOMBSTONE_TTL: i64 = DEFAULT_VALUE_TTL;
+
+/// the size in bytes the write ahead log may grow to before it is compacted into a snapshot
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+const WAL_FILE: &str = "values.wal";
+const SNAPSHOT_FILE: &str = "values.snapshot";
+
+/// Writes bytes to a temporary file next to path, syncs it and then renames it over path. A crash
+/// at any point leaves either the old file or the new one, never a partial one. The temporary
+/// file keeps the whole name of path, so files that only differ in extension do not share one
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let tmp_path = path.with_file_name(format!("{}.tmp", file_name.to_string_lossy()));
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(bytes)?;
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        //make the rename itself durable
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// How eagerly the write ahead log is flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync after every write. slowest, but nothing that was applied can be lost
+    Always,
+    /// fsync once per poll interval of the node
+    Periodic,
+    /// leave it up to the operating system
+    Never
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::Periodic => "periodic",
+            FsyncPolicy::Never => "never"
+        })
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FsyncPolicy, String> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "periodic" => Ok(FsyncPolicy::Periodic),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("unknown fsync policy '{}' (expected always, periodic or never)", s))
+        }
+    }
+}
+
+/// One version of a value along with the metadata needed to age it out
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredValue {
+    pub data: Vec<u8>,
+    /// unix time in seconds after which the value should no longer be served
+    pub expires_at: i64,
+    pub clock: VectorClock
+}
+
+impl StoredValue {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// What is left of a deleted key until it expires: the version of the delete, which keeps the
+/// versions it superseded from being stored again
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tombstone {
+    pub expires_at: i64,
+    pub clock: VectorClock
+}
+
+struct Durable {
+    dir: PathBuf,
+    wal: WriteAheadLog,
+    compaction_threshold: u64
+}
+
+//bookkeeping kept next to the versions of every key. none of it is persisted
+struct Entry {
+    versions: Vec<StoredValue>,
+    source: Option<IpAddr>,
+    last_access: Cell<u64>
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        size_of(&self.versions)
+    }
+}
+
+/// The hash table of a node. Values always live in memory; if the store was opened on a data
+/// directory every mutation is also appended to a write ahead log, which is periodically compacted
+/// into a snapshot. Opening the same directory again recovers the last state
+///
+/// Every value carries a vector clock. A version replaces the versions it descends from, while
+/// concurrent versions of a key are kept side by side as siblings until a write that has seen
+/// all of them resolves the conflict
+///
+/// Inserts are checked against StoreLimits. Values that are too large or that would put their
+/// source over its quota are rejected, while hitting the total byte or key limit evicts other keys
+/// according to the EvictionPolicy
+///
+/// Deleting a key leaves a tombstone behind for a while, which keeps other nodes from storing the
+/// versions it deleted again
+///
+/// A Merkle tree over the clocks of every key and tombstone is kept up to date with each mutation,
+/// for replicas to find out cheaply where they differ
+pub struct ValueStore {
+    values: HashMap<Key, Entry>,
+    tombstones: HashMap<Key, Tombstone>,
+    ttl: i64,
+    tombstone_ttl: i64,
+    durable: Option<Durable>,
+    limits: StoreLimits,
+    policy: EvictionPolicy,
+    origin: Key,
+    bytes: usize,
+    by_source: HashMap<IpAddr, usize>,
+    clock: Cell<u64>,
+    merkle: MerkleTree
+}
+
+impl ValueStore {
+    /// a store that forgets everything once the process exits
+    pub fn in_memory(ttl: i64) -> ValueStore {
+        ValueStore {
+            values: HashMap::new(),
+            tombstones: HashMap::new(),
+            ttl,
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+            durable: None,
+            limits: StoreLimits::default(),
+            policy: EvictionPolicy::Lru,
+            origin: [0; 20],
+            bytes: 0,
+            by_source: HashMap::new(),
+            clock: Cell::new(0),
+            merkle: MerkleTree::new()
+        }
+    }
+
+    /// Opens a store backed by dir, creating it if needed. The last snapshot is loaded and the
+    /// write ahead log is replayed over it. Values that expired while the node was down are dropped
+    pub fn open<P: AsRef<Path>>(dir: P, policy: FsyncPolicy, ttl: i64) -> io::Result<ValueStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut values: HashMap<Key, Vec<StoredValue>> = HashMap::new();
+        let mut tombstones = HashMap::new();
+        let snapshot = snapshot::read(&dir.join(SNAPSHOT_FILE))?;
+        let (wal, logged) = WriteAheadLog::open(dir.join(WAL_FILE), policy)?;
+        for record in snapshot.into_iter().chain(logged) {
+            match record {
+                Record::Put(key, val) => {
+                    lift_tombstone(&mut tombstones, &key, &val.clock);
+                    merge_version(values.entry(key).or_default(), val);
+                },
+                Record::Remove(key) => { values.remove(&key); },
+                Record::Tombstone(key, tombstone) => {
+                    if let Some(versions) = values.get_mut(&key) {
+                        versions.retain(|v| !tombstone.clock.descends(&v.clock));
+                    }
+                    bury(&mut tombstones, key, tombstone);
+                }
+            }
+        }
+        let now = get_time().sec;
+
+        let mut store = ValueStore::in_memory(ttl);
+        store.tombstones = tombstones;
+        store.tombstones.retain(|_, t| t.expires_at > now);
+        for (key, mut versions) in values.into_iter() {
+            versions.retain(|v| !v.is_expired(now));
+            if !versions.is_empty() {
+                store.bytes += size_of(&versions);
+                store.values.insert(key, Entry {versions, source: None, last_access: Cell::new(0)});
+            }
+        }
+        for key in store.keys().iter() {
+            store.rehash(key);
+        }
+        store.durable = Some(Durable {
+            dir,
+            wal,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD
+        });
+        Ok(store)
+    }
+
+    /// Sets the limits enforced on future inserts. origin is the id of the owning node, which
+    /// EvictionPolicy::FarthestFirst measures distance from and which versions the writes this
+    /// node coordinates
+    pub fn set_limits(&mut self, limits: StoreLimits, policy: EvictionPolicy, origin: Key) {
+        self.limits = limits;
+        self.policy = policy;
+        self.origin = origin;
+    }
+
+    /// sets how many seconds tombstones of deleted keys are kept for
+    pub fn set_tombstone_ttl(&mut self, ttl: i64) {
+        self.tombstone_ttl = ttl;
+    }
+
+    /// sets how large the write ahead log may grow before tick compacts it
+    pub fn set_compaction_threshold(&mut self, bytes: u64) {
+        if let Some(ref mut d) = self.durable {
+            d.compaction_threshold = bytes;
+        }
+    }
+
+    pub fn is_durable(&self) -> bool {
+        self.durable.is_some()
+    }
+
+    /// Returns the versions of key that have not expired. More than one means the key has
+    /// conflicting siblings
+    pub fn get(&self, key: &Key) -> Vec<&StoredValue> {
+        let now = get_time().sec;
+        match self.values.get(key) {
+            Some(entry) => {
+                entry.last_access.set(self.tick_clock());
+                entry.versions.iter().filter(|v| !v.is_expired(now)).collect()
+            },
+            None => Vec::new()
+        }
+    }
+
+    /// the versions of key, expired or not, without counting as an access
+    pub fn versions(&self, key: &Key) -> &[StoredValue] {
+        self.values.get(key).map(|e| e.versions.as_slice()).unwrap_or(&[])
+    }
+
+    /// the tombstone of key, if it was deleted
+    pub fn tombstone(&self, key: &Key) -> Option<&Tombstone> {
+        self.tombstones.get(key)
+    }
+
+    pub fn merkle(&self) -> &MerkleTree {
+        &self.merkle
+    }
+
+    /// every version of key this store knows of, merged into one clock. this includes the
+    /// tombstone if the key was deleted
+    pub fn seen(&self, key: &Key) -> VectorClock {
+        let mut clock = VectorClock::new();
+        if let Some(entry) = self.values.get(key) {
+            for v in entry.versions.iter() {
+                clock.merge(&v.clock);
+            }
+        }
+        if let Some(t) = self.tombstones.get(key) {
+            clock.merge(&t.clock);
+        }
+        clock
+    }
+
+    /// The clock for a new write of key coordinated by this node. It supersedes context, the
+    /// versions a client has seen, or everything this store has seen of key if there is none
+    pub fn next_version(&self, key: &Key, context: Option<&VectorClock>) -> VectorClock {
+        let seen = self.seen(key);
+        let mut clock = context.cloned().unwrap_or_else(|| seen.clone());
+        //never hand out a counter this node already used for another version
+        let issued = seen.get(&self.origin).max(clock.get(&self.origin));
+        clock.set(&self.origin, issued + 1);
+        clock
+    }
+
+    /// Stores data under key as a new version that supersedes everything this store has seen of
+    /// the key, with the default time to live. Returns the keys that were evicted to make room
+    pub fn put(&mut self, key: Key, data: Vec<u8>, source: Option<IpAddr>) -> Result<Vec<Key>, StoreError> {
+        let clock = self.next_version(&key, None);
+        self.put_version(key, data, clock, source)
+    }
+
+    /// Stores data under key as the version clock, with the default time to live. See insert
+    pub fn put_version(&mut self, key: Key, data: Vec<u8>, clock: VectorClock, source: Option<IpAddr>) -> Result<Vec<Key>, StoreError> {
+        let expires_at = get_time().sec + self.ttl;
+        self.insert(key, StoredValue {data, expires_at, clock}, source)
+    }
+
+    /// Stores a version of key on behalf of source (None if it did not come from another host).
+    /// It replaces the versions it descends from and becomes a sibling of those it is concurrent
+    /// with. A version that is already superseded is ignored. The mutation is logged before it is
+    /// applied
+    ///
+    /// A version superseded by the tombstone of the key is rejected until the tombstone expires
+    pub fn insert(&mut self, key: Key, val: StoredValue, source: Option<IpAddr>) -> Result<Vec<Key>, StoreError> {
+        if self.tombstones.get(&key).is_some_and(|t| t.clock.descends(&val.clock)) {
+            return Err(StoreError::Deleted)
+        }
+        let size = val.data.len();
+        if self.limits.max_value_size.is_some_and(|max| size > max) {
+            return Err(StoreError::TooLarge)
+        }
+
+        let (mut versions, replaced) = match self.values.get(&key) {
+            Some(e) => (e.versions.clone(), Some((e.size(), e.source))),
+            None => (Vec::new(), None)
+        };
+        if !merge_version(&mut versions, val.clone()) {
+            return Ok(Vec::new())
+        }
+        let new_size = size_of(&versions);
+        if self.limits.max_bytes.is_some_and(|max| new_size > max) {
+            return Err(StoreError::TooLarge)
+        }
+        if let (Some(quota), Some(ip)) = (self.limits.per_source_bytes, source) {
+            let used = self.by_source.get(&ip).cloned().unwrap_or(0);
+            let reclaimed = match replaced {
+                Some((old_size, Some(old_ip))) if old_ip == ip => old_size,
+                _ => 0
+            };
+            if used - reclaimed + new_size > quota {
+                return Err(StoreError::QuotaExceeded)
+            }
+        }
+
+        let evicted = self.make_room(&key, new_size, replaced.map(|(old_size, _)| old_size))?;
+
+        self.log(&Record::Put(key, val.clone()))?;
+        self.forget(&key);
+        self.bytes += new_size;
+        if let Some(ip) = source {
+            *self.by_source.entry(ip).or_insert(0) += new_size;
+        }
+        lift_tombstone(&mut self.tombstones, &key, &val.clock);
+        let last_access = Cell::new(self.tick_clock());
+        self.values.insert(key, Entry {versions, source, last_access});
+        self.rehash(&key);
+        Ok(evicted)
+    }
+
+    pub fn remove(&mut self, key: &Key) -> io::Result<Option<Vec<StoredValue>>> {
+        if !self.values.contains_key(key) {
+            return Ok(None)
+        }
+        self.log(&Record::Remove(*key))?;
+        let removed = self.forget(key);
+        self.rehash(key);
+        Ok(removed)
+    }
+
+    /// Deletes every version of key this store has seen and leaves a tombstone for them
+    pub fn delete(&mut self, key: &Key) -> io::Result<Option<Vec<StoredValue>>> {
+        let clock = self.next_version(key, None);
+        self.delete_version(key, clock)
+    }
+
+    /// Deletes the versions of key that clock descends from and leaves a tombstone for them.
+    /// Versions concurrent with the delete survive it. Returns the versions that were deleted
+    pub fn delete_version(&mut self, key: &Key, clock: VectorClock) -> io::Result<Option<Vec<StoredValue>>> {
+        let tombstone = Tombstone {expires_at: get_time().sec + self.tombstone_ttl, clock};
+        self.log(&Record::Tombstone(*key, tombstone.clone()))?;
+        let (deleted, kept) = match self.forget(key) {
+            Some(versions) => versions.into_iter().partition(|v| tombstone.clock.descends(&v.clock)),
+            None => (Vec::new(), Vec::new())
+        };
+        if !kept.is_empty() {
+            self.bytes += size_of(&kept);
+            let last_access = Cell::new(self.tick_clock());
+            self.values.insert(*key, Entry {versions: kept, source: None, last_access});
+        }
+        bury(&mut self.tombstones, *key, tombstone);
+        self.rehash(key);
+        Ok(if deleted.is_empty() { None } else { Some(deleted) })
+    }
+
+    /// true if key was deleted and its tombstone has not expired yet
+    pub fn is_deleted(&self, key: &Key) -> bool {
+        let now = get_time().sec;
+        self.tombstones.get(key).is_some_and(|t| t.expires_at > now)
+    }
+
+    /// Drops every version and tombstone that has expired by now, returning the keys left without
+    /// any version. Nothing is logged since expired entries are filtered out on recovery anyway
+    pub fn expire(&mut self, now: i64) -> Vec<Key> {
+        let buried = self.tombstones.iter()
+                                    .filter(|&(_, t)| t.expires_at <= now)
+                                    .map(|(k, _)| *k)
+                                    .collect::<Vec<Key>>();
+        for key in buried.iter() {
+            self.tombstones.remove(key);
+            self.rehash(key);
+        }
+        let stale = self.values.iter()
+                               .filter(|&(_, e)| e.versions.iter().any(|v| v.is_expired(now)))
+                               .map(|(k, _)| *k)
+                               .collect::<Vec<Key>>();
+        let mut expired = Vec::new();
+        for key in stale {
+            if let Some(entry) = self.values.get_mut(&key) {
+                let before = entry.size();
+                entry.versions.retain(|v| !v.is_expired(now));
+                let freed = before - entry.size();
+                if let Some(ip) = entry.source {
+                    if let Some(used) = self.by_source.get_mut(&ip) {
+                        *used -= freed;
+                    }
+                }
+                self.bytes -= freed;
+                if !entry.versions.is_empty() {
+                    self.rehash(&key);
+                    continue
+                }
+            }
+            self.forget(&key);
+            self.rehash(&key);
+            expired.push(key);
+        }
+        expired
+    }
+
+    /// Periodic maintenance: expires values, flushes the log under FsyncPolicy::Periodic and
+    /// compacts the log into a snapshot once it has grown past the compaction threshold
+    pub fn tick(&mut self, now: i64) -> io::Result<Vec<Key>> {
+        let expired = self.expire(now);
+        let compact = match self.durable {
+            Some(ref mut d) => {
+                if d.wal.policy() == FsyncPolicy::Periodic {
+                    d.wal.sync()?;
+                }
+                d.wal.len() >= d.compaction_threshold
+            },
+            None => false
+        };
+        if compact {
+            self.compact()?;
+        }
+        Ok(expired)
+    }
+
+    /// Writes every live version and tombstone into a fresh snapshot and empties the write ahead
+    /// log
+    pub fn compact(&mut self) -> io::Result<()> {
+        if let Some(ref mut d) = self.durable {
+            let records = self.values.iter()
+                                     .flat_map(|(k, e)| e.versions.iter().map(move |v| Record::Put(*k, v.clone())))
+                                     .chain(self.tombstones.iter().map(|(k, t)| Record::Tombstone(*k, t.clone())));
+            snapshot::write(&d.dir.join(SNAPSHOT_FILE), records)?;
+            d.wal.reset()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes anything outstanding in the log, regardless of the fsync policy
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self.durable {
+            Some(ref mut d) => d.wal.sync(),
+            None => Ok(())
+        }
+    }
+
+    /// every version held, along with its key
+    pub fn iter(&self) -> impl Iterator<Item=(&Key, &StoredValue)> {
+        self.values.iter().flat_map(|(k, e)| e.versions.iter().map(move |v| (k, v)))
+    }
+
+    /// every key with versions or a tombstone
+    pub fn keys(&self) -> Vec<Key> {
+        let mut keys = self.values.keys().cloned().collect::<Vec<Key>>();
+        keys.extend(self.tombstones.keys().filter(|k| !self.values.contains_key(*k)));
+        keys
+    }
+
+    /// number of keys with at least one version
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// total bytes of value data held
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Evicts keys other than key until size bytes for it fit within max_bytes and max_keys.
+    /// replacing is the size of what is already stored under key, if anything
+    fn make_room(&mut self, key: &Key, size: usize, replacing: Option<usize>) -> Result<Vec<Key>, StoreError> {
+        let mut evicted = Vec::new();
+        loop {
+            let bytes_after = self.bytes - replacing.unwrap_or(0) + size;
+            let keys_after = self.values.len() + if replacing.is_some() { 0 } else { 1 };
+            let over_bytes = self.limits.max_bytes.is_some_and(|max| bytes_after > max);
+            let over_keys = self.limits.max_keys.is_some_and(|max| keys_after > max);
+            if !over_bytes && !over_keys {
+                return Ok(evicted)
+            }
+            match self.eviction_candidate(key) {
+                Some(victim) => {
+                    self.remove(&victim)?;
+                    evicted.push(victim);
+                },
+                None => return Err(StoreError::TooLarge)
+            }
+        }
+    }
+
+    /// picks the key (other than exclude) to evict next under the eviction policy
+    fn eviction_candidate(&self, exclude: &Key) -> Option<Key> {
+        let candidates = self.values.iter().filter(|&(k, _)| k != exclude);
+        let chosen = match self.policy {
+            EvictionPolicy::Lru => candidates.min_by_key(|&(_, e)| e.last_access.get()),
+            EvictionPolicy::SoonestExpiry => {
+                candidates.min_by_key(|&(_, e)| e.versions.iter().map(|v| v.expires_at).max())
+            },
+            EvictionPolicy::FarthestFirst => {
+                let origin = self.origin;
+                candidates.max_by_key(|&(k, _)| xor(k, &origin))
+            }
+        };
+        chosen.map(|(k, _)| *k)
+    }
+
+    //removes key from memory and from all accounting
+    fn forget(&mut self, key: &Key) -> Option<Vec<StoredValue>> {
+        self.values.remove(key).map(|entry| {
+            let size = entry.size();
+            self.bytes -= size;
+            if let Some(ip) = entry.source {
+                let now_empty = match self.by_source.get_mut(&ip) {
+                    Some(used) => {
+                        *used -= size;
+                        *used == 0
+                    },
+                    None => false
+                };
+                if now_empty {
+                    self.by_source.remove(&ip);
+                }
+            }
+            entry.versions
+        })
+    }
+
+    /// brings the Merkle tree up to date with what is stored under key: a hash over the clocks
+    /// of its versions and of its tombstone
+    fn rehash(&mut self, key: &Key) {
+        let mut clocks = self.versions(key).iter().map(|v| v.clock.encode()).collect::<Vec<_>>();
+        clocks.sort();
+        let tombstone = self.tombstones.get(key);
+        let digest = if clocks.is_empty() && tombstone.is_none() {
+            None
+        } else {
+            let mut sha = Sha1::new();
+            for clock in clocks.iter() {
+                sha.input(clock);
+            }
+            if let Some(t) = tombstone {
+                sha.input(&[0xff]);
+                sha.input(&t.clock.encode());
+            }
+            Some(merkle::finish(sha))
+        };
+        self.merkle.update(key, digest);
+    }
+
+    fn tick_clock(&self) -> u64 {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        now
+    }
+
+    fn log(&mut self, record: &Record) -> io::Result<()> {
+        match self.durable {
+            Some(ref mut d) => d.wal.append(record),
+            None => Ok(())
+        }
+    }
+}
+
+/// Adds val to the versions of a key, dropping the versions it supersedes. Returns false (and
+/// leaves versions alone) if one of them already supersedes or equals val
+fn merge_version(versions: &mut Vec<StoredValue>, val: StoredValue) -> bool {
+    if versions.iter().any(|v| v.clock.descends(&val.clock)) {
+        return false
+    }
+    versions.retain(|v| !val.clock.descends(&v.clock));
+    versions.push(val);
+    true
+}
+
+/// records a delete, merging it with an earlier delete of the same key
+fn bury(tombstones: &mut HashMap<Key, Tombstone>, key: Key, tombstone: Tombstone) {
+    match tombstones.get_mut(&key) {
+        Some(t) => {
+            t.clock.merge(&tombstone.clock);
+            t.expires_at = t.expires_at.max(tombstone.expires_at);
+        },
+        None => { tombstones.insert(key, tombstone); }
+    }
+}
+
+/// a version written after the delete of its key (one that descends the tombstone) revives it
+fn lift_tombstone(tombstones: &mut HashMap<Key, Tombstone>, key: &Key, clock: &VectorClock) {
+    if tombstones.get(key).is_some_and(|t| clock.descends(&t.clock)) {
+        tombstones.remove(key);
+    }
+}
+
+fn size_of(versions: &[StoredValue]) -> usize {
+    versions.iter().map(|v| v.data.len()).sum()
+}
+
+fn xor(a: &Key, b: &Key) -> Key {
+    let mut dist: Key = [0; 20];
+    for (d, (x, y)) in dist.iter_mut().zip(a.iter().zip(b.iter())) {
+        *d = x ^ y;
+    }
+    dist
+}
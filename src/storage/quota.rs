@@ -0,0 +1,79 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Upper bounds on what a node is willing to hold. None means unbounded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreLimits {
+    /// total bytes of value data across all keys
+    pub max_bytes: Option<usize>,
+    pub max_keys: Option<usize>,
+    /// size in bytes of any single value
+    pub max_value_size: Option<usize>,
+    /// bytes of value data that any one source ip may have stored at once
+    pub per_source_bytes: Option<usize>
+}
+
+/// Which keys make room when max_bytes or max_keys is hit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// least recently stored or read
+    Lru,
+    /// farthest (by XOR) from the id of the node. these are the keys this node is least
+    /// responsible for
+    FarthestFirst,
+    /// the keys that would have expired first anyway
+    SoonestExpiry
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::FarthestFirst => "farthest",
+            EvictionPolicy::SoonestExpiry => "expiry"
+        })
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EvictionPolicy, String> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "farthest" => Ok(EvictionPolicy::FarthestFirst),
+            "expiry" => Ok(EvictionPolicy::SoonestExpiry),
+            _ => Err(format!("unknown eviction policy '{}' (expected lru, farthest or expiry)", s))
+        }
+    }
+}
+
+/// Why a value was not stored
+#[derive(Debug)]
+pub enum StoreError {
+    /// the value is larger than max_value_size (or max_bytes)
+    TooLarge,
+    /// the source has used up its per_source_bytes
+    QuotaExceeded,
+    /// the key was deleted and its tombstone is still live
+    Deleted,
+    Io(io::Error)
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> StoreError {
+        StoreError::Io(e)
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::TooLarge => write!(f, "value too large"),
+            StoreError::QuotaExceeded => write!(f, "quota exceeded"),
+            StoreError::Deleted => write!(f, "key was deleted"),
+            StoreError::Io(ref e) => write!(f, "io error: {}", e)
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, ErrorKind};
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::path::Path;
+use node::state::NodeAddr;
+use storage::write_atomic;
+
+const ID_FILE: &str = "node.id";
+const CONTACTS_FILE: &str = "contacts";
+const IDENTITY_KEY_FILE: &str = "identity.key";
+
+//id + ipv4 + port
+const CONTACT_LEN: usize = 20 + 4 + 2;
+
+/// Returns the node id saved in dir, if there is one
+pub fn load_node_id(dir: &Path) -> io::Result<Option<NodeAddr>> {
+    match read_file(&dir.join(ID_FILE))? {
+        None => Ok(None),
+        Some(ref bytes) if bytes.len() == 20 => {
+            let mut id: NodeAddr = [0; 20];
+            id.copy_from_slice(bytes);
+            Ok(Some(id))
+        },
+        Some(_) => Err(io::Error::new(ErrorKind::InvalidData, "node id file is corrupt"))
+    }
+}
+
+pub fn save_node_id(dir: &Path, id: &NodeAddr) -> io::Result<()> {
+    write_atomic(&dir.join(ID_FILE), id)
+}
+
+/// Returns the secret of the identity key encrypted sessions are keyed by, if one was saved in dir
+pub fn load_identity_key(dir: &Path) -> io::Result<Option<[u8; 32]>> {
+    match read_file(&dir.join(IDENTITY_KEY_FILE))? {
+        None => Ok(None),
+        Some(ref bytes) if bytes.len() == 32 => {
+            let mut secret = [0; 32];
+            secret.copy_from_slice(bytes);
+            Ok(Some(secret))
+        },
+        Some(_) => Err(io::Error::new(ErrorKind::InvalidData, "identity key file is corrupt"))
+    }
+}
+
+pub fn save_identity_key(dir: &Path, secret: &[u8; 32]) -> io::Result<()> {
+    write_atomic(&dir.join(IDENTITY_KEY_FILE), secret)
+}
+
+/// Saves a snapshot of the k-bucket contacts. Only ipv4 contacts are kept, as with the rest of the
+/// protocol
+pub fn save_contacts(dir: &Path, contacts: &[(NodeAddr, SocketAddr)]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(contacts.len() * CONTACT_LEN);
+    for (id, addr) in contacts.iter() {
+        if let SocketAddr::V4(ref v4) = *addr {
+            buf.extend_from_slice(id);
+            buf.extend_from_slice(&v4.ip().octets());
+            buf.extend_from_slice(&v4.port().to_be_bytes());
+        }
+    }
+    write_atomic(&dir.join(CONTACTS_FILE), &buf)
+}
+
+/// Loads the contacts saved by save_contacts. These have not been heard from since the last run so
+/// they should be pinged before they are trusted
+pub fn load_contacts(dir: &Path) -> io::Result<Vec<(NodeAddr, SocketAddr)>> {
+    let bytes = match read_file(&dir.join(CONTACTS_FILE))? {
+        None => return Ok(Vec::new()),
+        Some(b) => b
+    };
+    if bytes.len() % CONTACT_LEN != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "contacts file is corrupt"))
+    }
+    Ok(bytes.chunks(CONTACT_LEN).map(|c| {
+        let mut id: NodeAddr = [0; 20];
+        id.copy_from_slice(&c[..20]);
+        let ip = Ipv4Addr::new(c[20], c[21], c[22], c[23]);
+        let port = (c[24] as u16) << 8 | c[25] as u16;
+        (id, SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    }).collect())
+}
+
+fn read_file(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(Some(bytes))
+}
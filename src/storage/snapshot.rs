@@ -0,0 +1,31 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, ErrorKind};
+use std::path::Path;
+use storage::write_atomic;
+use storage::wal::{Record, decode_all};
+
+/// Writes every record into a new snapshot at path, replacing the old one atomically
+pub fn write<I>(path: &Path, records: I) -> io::Result<()> where I: Iterator<Item=Record> {
+    let mut buf = Vec::new();
+    for record in records {
+        record.encode_into(&mut buf);
+    }
+    write_atomic(path, &buf)
+}
+
+/// Reads back every record of a snapshot. A missing snapshot is treated as an empty one
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let (records, valid) = decode_all(&bytes);
+    if valid < bytes.len() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "corrupt snapshot"))
+    }
+    Ok(records)
+}
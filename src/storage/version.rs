@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::fmt::{Formatter, Debug};
+use message_protocol::Key;
+use utils::fmt::as_hex_string;
+
+//entry count + (actor + counter) per entry
+const COUNT_LEN: usize = 2;
+const ENTRY_LEN: usize = 20 + 8;
+
+/// How two versions of a value relate to each other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Causality {
+    /// the first version was superseded by the second
+    Before,
+    /// the first version supersedes the second
+    After,
+    Equal,
+    /// neither version has seen the other; both are kept as siblings
+    Concurrent
+}
+
+/// A vector clock: for every node (actor) that coordinated a write to a key, how many writes it
+/// has coordinated. Encoded as
+///
+/// ```text
+/// [entries: 2]([actor: 20][counter: 8])*
+/// ```
+///
+/// with big endian integers and the entries ordered by actor
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct VectorClock {
+    counters: BTreeMap<Key, u64>
+}
+
+impl VectorClock {
+    pub fn new() -> VectorClock {
+        VectorClock::default()
+    }
+
+    pub fn get(&self, actor: &Key) -> u64 {
+        self.counters.get(actor).cloned().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, actor: &Key, counter: u64) {
+        self.counters.insert(*actor, counter);
+    }
+
+    /// records one more write coordinated by actor
+    pub fn increment(&mut self, actor: &Key) {
+        *self.counters.entry(*actor).or_insert(0) += 1;
+    }
+
+    /// raises every counter to at least the one in other, so the result descends both
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (actor, counter) in other.counters.iter() {
+            let mine = self.counters.entry(*actor).or_insert(0);
+            if *counter > *mine {
+                *mine = *counter;
+            }
+        }
+    }
+
+    /// true if this clock has seen everything other has, i.e. it is equal or after it
+    pub fn descends(&self, other: &VectorClock) -> bool {
+        other.counters.iter().all(|(actor, counter)| self.get(actor) >= *counter)
+    }
+
+    pub fn compare(&self, other: &VectorClock) -> Causality {
+        match (self.descends(other), other.descends(self)) {
+            (true, true) => Causality::Equal,
+            (true, false) => Causality::After,
+            (false, true) => Causality::Before,
+            (false, false) => Causality::Concurrent
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        COUNT_LEN + ENTRY_LEN * self.counters.len()
+    }
+
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.counters.len() as u16).to_be_bytes());
+        for (actor, counter) in self.counters.iter() {
+            buf.extend_from_slice(actor);
+            buf.extend_from_slice(&counter.to_be_bytes());
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// Decodes the clock at the start of bytes, returning it with the number of bytes it took up.
+    /// None if bytes is too short to hold it
+    pub fn decode(bytes: &[u8]) -> Option<(VectorClock, usize)> {
+        ClockView::parse(bytes).map(|(view, used)| (view.to_clock(), used))
+    }
+}
+
+/// A vector clock read in place over its encoding, for looking at the clock of a message without
+/// building one
+#[derive(Clone, Copy, PartialEq)]
+pub struct ClockView<'a> {
+    //the entries, without the count ahead of them
+    entries: &'a [u8]
+}
+
+impl <'a> ClockView<'a> {
+    /// The clock at the start of bytes and the number of bytes it takes up. None if bytes is too
+    /// short to hold it
+    pub fn parse(bytes: &'a [u8]) -> Option<(ClockView<'a>, usize)> {
+        let count = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+        let total = COUNT_LEN + ENTRY_LEN * count;
+        let entries = bytes.get(COUNT_LEN..total)?;
+        Some((ClockView {entries}, total))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the (actor, counter) entries, in the order they were encoded
+    pub fn entries(&self) -> impl Iterator<Item = (&'a Key, u64)> + 'a {
+        self.entries.chunks_exact(ENTRY_LEN).filter_map(|entry| {
+            let (actor, counter) = entry.split_at(20);
+            Some((actor.try_into().ok()?, u64::from_be_bytes(counter.try_into().ok()?)))
+        })
+    }
+
+    pub fn to_clock(&self) -> VectorClock {
+        VectorClock {counters: self.entries().map(|(actor, counter)| (*actor, counter)).collect()}
+    }
+}
+
+impl <'a> Debug for ClockView<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_map()
+         .entries(self.entries().map(|(actor, counter)| (as_hex_string(actor), counter)))
+         .finish()
+    }
+}
+
+impl Debug for VectorClock {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_map()
+         .entries(self.counters.iter().map(|(actor, counter)| (as_hex_string(actor), counter)))
+         .finish()
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::Path;
+use message_protocol::Key;
+use storage::{StoredValue, Tombstone, FsyncPolicy};
+use storage::version::VectorClock;
+
+const OP_PUT: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const OP_TOMBSTONE: u8 = 2;
+
+//op + key + expires_at + data length
+const HEADER_LEN: usize = 1 + 20 + 8 + 4;
+const CHECKSUM_LEN: usize = 4;
+
+/// A single mutation of the value store, as it is laid out in the write ahead log and in
+/// snapshots:
+///
+/// ```text
+/// [op: 1][key: 20][expires_at: 8][len: 4][clock][data][checksum: 4]
+/// ```
+///
+/// all integers are big endian and len covers the clock and the data. the checksum covers every
+/// byte before it. a tombstone uses expires_at for when the tombstone itself expires and has no
+/// data. a remove has neither a clock nor data
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Put(Key, StoredValue),
+    Remove(Key),
+    Tombstone(Key, Tombstone)
+}
+
+impl Record {
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        let (op, key, expires_at, clock, data): (u8, &Key, i64, Option<&VectorClock>, &[u8]) = match *self {
+            Record::Put(ref key, ref val) => (OP_PUT, key, val.expires_at, Some(&val.clock), &val.data),
+            Record::Remove(ref key) => (OP_REMOVE, key, 0, None, &[]),
+            Record::Tombstone(ref key, ref t) => (OP_TOMBSTONE, key, t.expires_at, Some(&t.clock), &[])
+        };
+        let len = clock.map(|c| c.encoded_len()).unwrap_or(0) + data.len();
+        buf.push(op);
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&expires_at.to_be_bytes());
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+        if let Some(clock) = clock {
+            clock.encode_into(buf);
+        }
+        buf.extend_from_slice(data);
+        let sum = checksum(&buf[start..]);
+        buf.extend_from_slice(&sum.to_be_bytes());
+    }
+
+    /// Decodes the record at the start of bytes, returning it with the number of bytes it took
+    /// up. None is returned if the record is truncated or fails its checksum
+    pub fn decode(bytes: &[u8]) -> Option<(Record, usize)> {
+        if bytes.len() < HEADER_LEN {
+            return None
+        }
+        let data_len = be_u32(&bytes[29..33]) as usize;
+        let total = HEADER_LEN + data_len + CHECKSUM_LEN;
+        if bytes.len() < total {
+            return None
+        }
+        let body = &bytes[..HEADER_LEN + data_len];
+        if checksum(body) != be_u32(&bytes[HEADER_LEN + data_len..total]) {
+            return None
+        }
+        let mut key: Key = [0; 20];
+        key.copy_from_slice(&bytes[1..21]);
+        let mut expires_at = [0; 8];
+        expires_at.copy_from_slice(&bytes[21..29]);
+        let expires_at = i64::from_be_bytes(expires_at);
+        let record = match bytes[0] {
+            OP_PUT => {
+                let (clock, used) = VectorClock::decode(&body[HEADER_LEN..])?;
+                Record::Put(key, StoredValue {
+                    data: body[HEADER_LEN + used..].to_vec(),
+                    expires_at,
+                    clock
+                })
+            },
+            OP_REMOVE => Record::Remove(key),
+            OP_TOMBSTONE => {
+                let (clock, _) = VectorClock::decode(&body[HEADER_LEN..])?;
+                Record::Tombstone(key, Tombstone {expires_at, clock})
+            },
+            _ => return None
+        };
+        Some((record, total))
+    }
+}
+
+/// Decodes records from the front of bytes until the first one that is incomplete or corrupt.
+/// Returns the records along with the number of bytes that were valid
+pub fn decode_all(bytes: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some((record, used)) = Record::decode(&bytes[offset..]) {
+        records.push(record);
+        offset += used;
+    }
+    (records, offset)
+}
+
+/// An append only log of store mutations. Every write to the value store lands here before it is
+/// applied in memory so that a crashed node can replay it on startup
+pub struct WriteAheadLog {
+    file: File,
+    policy: FsyncPolicy,
+    len: u64,
+    dirty: bool
+}
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log at path and returns it along with every intact record in it.
+    /// A torn record at the tail (from a crash midway through an append) is cut off
+    pub fn open<P: AsRef<Path>>(path: P, policy: FsyncPolicy) -> io::Result<(WriteAheadLog, Vec<Record>)> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (records, valid) = decode_all(&bytes);
+        if valid < bytes.len() {
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid as u64))?;
+        Ok((WriteAheadLog {file, policy, len: valid as u64, dirty: false}, records))
+    }
+
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut buf = Vec::new();
+        record.encode_into(&mut buf);
+        self.file.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        self.dirty = true;
+        if self.policy == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes appended records to disk if there are any outstanding
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Empties the log. Only safe once everything in it is covered by a snapshot
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        self.len = 0;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.policy
+    }
+
+    /// the size of the log in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&bytes[..4]);
+    u32::from_be_bytes(b)
+}
+
+//32 bit FNV-1a. only meant to catch torn or garbled writes, not tampering
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, b| (hash ^ (*b as u32)).wrapping_mul(0x01000193))
+}
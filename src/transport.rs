@@ -0,0 +1,464 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hkdf::{hkdf_extract, hkdf_expand};
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use time::get_time;
+use auth::ClusterAuth;
+use message_protocol::{Key, ProtoMessage, OP_HANDSHAKE, OP_HANDSHAKE_RESP, SEALED_HEADER_LEN};
+use node::state::NodeAddr;
+use utils::u8_4_to_u32;
+
+pub const KEY_LEN: usize = 32;
+/// bytes of the Poly1305 tag ending every sealed datagram
+pub const TAG_LEN: usize = 16;
+/// how long a handshake is waited on before the peer is taken to not speak encryption
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// age after which a session is replaced by a new handshake. it is still used until that one is done
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(600);
+/// how long a peer that did not answer a handshake is talked to in plaintext before trying again
+const PLAINTEXT_RETRY: Duration = Duration::from_secs(600);
+/// most messages held for a peer while a handshake with it is in flight
+const MAX_QUEUED: usize = 256;
+const PROTOCOL_NAME: &[u8] = b"ailmedak transport 1";
+
+/// Whether messages between nodes are encrypted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encryption {
+    //plaintext only, handshakes are ignored
+    Off,
+    //encrypted with peers that answer a handshake, plaintext with those that do not (such as
+    //nodes of an older version)
+    Preferred,
+    //encrypted only, plaintext messages are dropped
+    Required
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Encryption, String> {
+        match s {
+            "off" => Ok(Encryption::Off),
+            "preferred" => Ok(Encryption::Preferred),
+            "required" => Ok(Encryption::Required),
+            _ => Err(format!("unknown encryption {}, expected off, preferred or required", s))
+        }
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Encryption::Off => "off",
+            Encryption::Preferred => "preferred",
+            Encryption::Required => "required"
+        })
+    }
+}
+
+/// The static X25519 key pair a node is known by to the peers it shares sessions with
+pub struct Identity {
+    secret: [u8; KEY_LEN],
+    pub public: [u8; KEY_LEN]
+}
+
+impl Identity {
+    pub fn generate () -> Identity {
+        Identity::from_secret(random_key())
+    }
+
+    pub fn from_secret (secret: [u8; KEY_LEN]) -> Identity {
+        Identity {secret, public: curve25519_base(&secret)}
+    }
+
+    pub fn secret (&self) -> &[u8; KEY_LEN] {
+        &self.secret
+    }
+}
+
+fn random_key () -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    OsRng::new().expect("no source of randomness").fill_bytes(&mut key);
+    key
+}
+
+/// X25519, None for a peer key of low order, which would make the shared secret known
+fn dh (secret: &[u8; KEY_LEN], public: &[u8; KEY_LEN]) -> Option<[u8; KEY_LEN]> {
+    let shared = curve25519(secret, public);
+    if shared.iter().all(|b| *b == 0) { None } else { Some(shared) }
+}
+
+/// Derives the keys of a session from the three Diffie-Hellmans of the handshake: ephemeral with
+/// ephemeral, initiator static with responder ephemeral and initiator ephemeral with responder
+/// static. Each side proves it holds its static key by being able to use the session at all.
+/// Returns the keys of the initiator to responder and responder to initiator directions
+fn session_keys (shared: &[[u8; KEY_LEN]; 3], transcript: &[&[u8; KEY_LEN]; 4]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let ikm = shared.concat();
+    let mut prk = [0; KEY_LEN];
+    hkdf_extract(Sha256::new(), PROTOCOL_NAME, &ikm, &mut prk);
+    let info = transcript.iter().flat_map(|k| k.iter().cloned()).collect::<Vec<u8>>();
+    let mut okm = [0; 2 * KEY_LEN];
+    hkdf_expand(Sha256::new(), &prk, &info, &mut okm);
+    let (mut i2r, mut r2i) = ([0; KEY_LEN], [0; KEY_LEN]);
+    i2r.copy_from_slice(&okm[..KEY_LEN]);
+    r2i.copy_from_slice(&okm[KEY_LEN..]);
+    (i2r, r2i)
+}
+
+/// The keys a peer shows in its half of a handshake
+pub struct PeerKeys {
+    pub ephemeral: [u8; KEY_LEN],
+    pub static_key: [u8; KEY_LEN]
+}
+
+/// the counters of the last 64 datagrams received, to drop replayed ones
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    seen: u64
+}
+
+impl ReplayWindow {
+    /// whether counter was not seen before, marking it seen
+    fn accept (&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+            true
+        } else {
+            let age = self.highest - counter;
+            if age >= 64 || self.seen & (1 << age) != 0 {
+                return false
+            }
+            self.seen |= 1 << age;
+            true
+        }
+    }
+}
+
+struct Session {
+    peer: SocketAddr,
+    //the node id the peer showed in its handshake. messages sealed in the session must carry it
+    node: NodeAddr,
+    //the index the peer puts on datagrams to this node, and the one this node puts on its own
+    remote_index: u32,
+    send_key: [u8; KEY_LEN],
+    recv_key: [u8; KEY_LEN],
+    //counters start at 1, the window takes 0 as seen
+    sent: u64,
+    received: ReplayWindow,
+    established: Instant
+}
+
+/// a handshake this node started, with the messages waiting on it
+struct Handshake {
+    local_index: u32,
+    ephemeral: [u8; KEY_LEN],
+    started: Instant,
+    queued: Vec<Vec<u8>>
+}
+
+#[derive(Default)]
+struct Table {
+    //by the index this node handed out for them
+    sessions: HashMap<u32, Session>,
+    //the session messages to each peer are sealed with
+    current: HashMap<SocketAddr, u32>,
+    //sessions this node answered a handshake for while it had one with the peer. a handshake
+    //can be replayed, so they only replace the current one once the peer is seen using them
+    pending: HashMap<SocketAddr, u32>,
+    handshakes: HashMap<SocketAddr, Handshake>,
+    //peers that did not answer a handshake, and since when
+    plaintext: HashMap<SocketAddr, Instant>,
+    //peers that sent datagrams for sessions this node does not know, such as after a restart
+    stale: HashSet<SocketAddr>,
+    //the static key each node id presented first. a node id is not let to change its key
+    peer_keys: HashMap<NodeAddr, [u8; KEY_LEN]>,
+    next_index: u32
+}
+
+impl Table {
+    fn new_index (&mut self) -> u32 {
+        self.next_index = self.next_index.wrapping_add(1);
+        self.next_index
+    }
+
+    /// whether id may use static, remembering it if id is new
+    fn pin (&mut self, id: &NodeAddr, static_key: &[u8; KEY_LEN]) -> bool {
+        *self.peer_keys.entry(*id).or_insert(*static_key) == *static_key
+    }
+}
+
+/// Encrypted sessions with other nodes, established by a two message handshake (Noise style)
+/// keyed by the static identity keys of the nodes and fresh ephemeral keys. Messages to a peer
+/// wait while a handshake with it is in flight. Clones share the sessions, so every thread of a
+/// node that sends or receives can hold one
+#[derive(Clone)]
+pub struct Sessions {
+    id: NodeAddr,
+    identity: Arc<Identity>,
+    mode: Encryption,
+    table: Arc<Mutex<Table>>
+}
+
+impl ProtoMessage for Sessions {
+    fn id (&self) -> &Key {
+        &self.id
+    }
+}
+
+impl Sessions {
+    pub fn new (id: NodeAddr, identity: Identity, mode: Encryption) -> Sessions {
+        Sessions {id, identity: Arc::new(identity), mode, table: Arc::new(Mutex::new(Table::default()))}
+    }
+
+    /// whether a plaintext message of opcode may be taken in
+    pub fn accepts_plaintext (&self, opcode: Option<u8>) -> bool {
+        self.mode != Encryption::Required || opcode == Some(OP_HANDSHAKE) || opcode == Some(OP_HANDSHAKE_RESP)
+    }
+
+    /// The datagrams to send for msg to dst: msg sealed in the session with dst, msg itself if
+    /// dst is talked to in plaintext, or nothing but maybe a handshake if msg has to wait for one
+    pub fn outgoing (&self, msg: &[u8], dst: SocketAddr, now: Instant) -> Vec<Vec<u8>> {
+        let opcode = msg.first().cloned();
+        if self.mode == Encryption::Off || opcode == Some(OP_HANDSHAKE) || opcode == Some(OP_HANDSHAKE_RESP) {
+            return vec![msg.to_vec()]
+        }
+        let mut table = self.table.lock().unwrap();
+        let mut out = Vec::new();
+        let current = table.current.get(&dst).cloned();
+        let fresh = current.and_then(|i| table.sessions.get(&i)).map(|s| now.duration_since(s.established) < SESSION_LIFETIME);
+        //a session that is due to be replaced is used until the new one is up
+        let plaintext = self.plaintext_with(&table, dst, now);
+        if fresh != Some(true) && !table.handshakes.contains_key(&dst) && !plaintext {
+            out.push(self.start_handshake(&mut table, dst, now));
+        }
+        match current.and_then(|i| table.sessions.get_mut(&i)) {
+            Some(session) => out.push(self.seal(session, msg)),
+            None if plaintext => out.push(msg.to_vec()),
+            None => if let Some(handshake) = table.handshakes.get_mut(&dst) {
+                if handshake.queued.len() < MAX_QUEUED {
+                    handshake.queued.push(msg.to_vec());
+                }
+            }
+        }
+        out
+    }
+
+    fn plaintext_with (&self, table: &Table, dst: SocketAddr, now: Instant) -> bool {
+        self.mode == Encryption::Preferred && table.plaintext.get(&dst).is_some_and(|since| now.duration_since(*since) < PLAINTEXT_RETRY)
+    }
+
+    fn start_handshake (&self, table: &mut Table, dst: SocketAddr, now: Instant) -> Vec<u8> {
+        let local_index = table.new_index();
+        let ephemeral = random_key();
+        table.handshakes.insert(dst, Handshake {local_index, ephemeral, started: now, queued: Vec::new()});
+        self.handshake_msg(local_index, &curve25519_base(&ephemeral), &self.identity.public)
+    }
+
+    /// [15][id][len][receiver index: 4][counter: 8][ciphertext][tag: 16]
+    fn seal (&self, session: &mut Session, msg: &[u8]) -> Vec<u8> {
+        session.sent += 1;
+        let mut datagram = self.sealed_header(session.remote_index, session.sent, msg.len() + TAG_LEN);
+        let mut cipher = ChaCha20Poly1305::new(&session.send_key, &session.sent.to_be_bytes(), &datagram);
+        let start = datagram.len();
+        datagram.resize(start + msg.len() + TAG_LEN, 0);
+        let (body, tag) = datagram[start..].split_at_mut(msg.len());
+        cipher.encrypt(msg, body, tag);
+        datagram
+    }
+
+    /// Answers the handshake of a peer. The datagrams returned are the answer, followed by the
+    /// messages that waited on a handshake this node started with the peer, sealed in the
+    /// current session. Nothing is returned if the handshake is not acceptable, or if it crossed
+    /// one this node started and this node has the lower id: that one is answered instead
+    pub fn respond (&self, src: SocketAddr, from: &NodeAddr, index: u32, peer: &PeerKeys, now: Instant) -> Vec<Vec<u8>> {
+        let (ephemeral, static_key) = (&peer.ephemeral, &peer.static_key);
+        if self.mode == Encryption::Off {
+            return Vec::new()
+        }
+        let mut table = self.table.lock().unwrap();
+        if table.handshakes.contains_key(&src) && self.id < *from {
+            return Vec::new()
+        }
+        if !table.pin(from, static_key) {
+            return Vec::new()
+        }
+        let mine = random_key();
+        let mine_public = curve25519_base(&mine);
+        let shared = match (dh(&mine, ephemeral), dh(&mine, static_key), dh(&self.identity.secret, ephemeral)) {
+            (Some(ee), Some(se), Some(es)) => [ee, se, es],
+            _ => return Vec::new()
+        };
+        let (i2r, r2i) = session_keys(&shared, &[ephemeral, static_key, &mine_public, &self.identity.public]);
+        let local_index = table.new_index();
+        table.sessions.insert(local_index, Session {
+            peer: src, node: *from, remote_index: index, send_key: r2i, recv_key: i2r, sent: 0, received: ReplayWindow::default(), established: now
+        });
+        if table.current.contains_key(&src) || table.plaintext.contains_key(&src) {
+            if let Some(replaced) = table.pending.insert(src, local_index) {
+                table.sessions.remove(&replaced);
+            }
+        } else {
+            table.current.insert(src, local_index);
+            table.stale.remove(&src);
+        }
+        let mut out = vec![self.handshake_resp_msg(index, local_index, &mine_public, &self.identity.public)];
+        //the handshake this node started stays, in case the peer answers it too
+        if table.current.contains_key(&src) {
+            let queued = table.handshakes.get_mut(&src).map(|h| mem::take(&mut h.queued)).unwrap_or_default();
+            out.extend(self.seal_queued(&mut table, src, queued));
+        }
+        out
+    }
+
+    /// Finishes a handshake this node started once the peer answered, returning the messages
+    /// that waited on it, sealed
+    pub fn complete (&self, src: SocketAddr, from: &NodeAddr, local_index: u32, remote_index: u32, peer: &PeerKeys, now: Instant) -> Vec<Vec<u8>> {
+        let (ephemeral, static_key) = (&peer.ephemeral, &peer.static_key);
+        let mut table = self.table.lock().unwrap();
+        let mine = match table.handshakes.get(&src) {
+            Some(h) if h.local_index == local_index => h.ephemeral,
+            _ => return Vec::new()
+        };
+        if !table.pin(from, static_key) {
+            return Vec::new()
+        }
+        let shared = match (dh(&mine, ephemeral), dh(&self.identity.secret, ephemeral), dh(&mine, static_key)) {
+            (Some(ee), Some(se), Some(es)) => [ee, se, es],
+            _ => return Vec::new()
+        };
+        let (i2r, r2i) = session_keys(&shared, &[&curve25519_base(&mine), &self.identity.public, ephemeral, static_key]);
+        table.sessions.insert(local_index, Session {
+            peer: src, node: *from, remote_index, send_key: i2r, recv_key: r2i, sent: 0, received: ReplayWindow::default(), established: now
+        });
+        table.current.insert(src, local_index);
+        table.pending.remove(&src);
+        table.plaintext.remove(&src);
+        table.stale.remove(&src);
+        let queued = table.handshakes.remove(&src).map(|h| h.queued).unwrap_or_default();
+        self.seal_queued(&mut table, src, queued)
+    }
+
+    /// seals messages that waited on a handshake in the current session with dst
+    fn seal_queued (&self, table: &mut Table, dst: SocketAddr, queued: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let index = table.current[&dst];
+        let session = table.sessions.get_mut(&index).unwrap();
+        queued.iter().map(|msg| self.seal(session, msg)).collect()
+    }
+
+    /// The message a sealed datagram from src carries, None if it is not one of a session with
+    /// src, was tampered with, was seen before or claims to be from another node than the one
+    /// the session was made with
+    pub fn open (&self, datagram: &[u8], src: SocketAddr) -> Option<Vec<u8>> {
+        if datagram.len() < SEALED_HEADER_LEN + TAG_LEN {
+            return None
+        }
+        let index = u8_4_to_u32(&datagram[SEALED_HEADER_LEN - 12..SEALED_HEADER_LEN - 8]);
+        let mut counter = [0; 8];
+        counter.copy_from_slice(&datagram[SEALED_HEADER_LEN - 8..SEALED_HEADER_LEN]);
+        let mut table = self.table.lock().unwrap();
+        let session = match table.sessions.get_mut(&index) {
+            Some(session) if session.peer == src => session,
+            _ => {
+                table.stale.insert(src);
+                return None
+            }
+        };
+        let (header, rest) = datagram.split_at(SEALED_HEADER_LEN);
+        let (body, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut msg = vec![0; body.len()];
+        if !ChaCha20Poly1305::new(&session.recv_key, &counter, header).decrypt(body, &mut msg, tag) {
+            return None
+        }
+        if msg.get(1..1 + session.node.len()) != Some(&session.node[..]) {
+            return None
+        }
+        if !session.received.accept(u64::from_be_bytes(counter)) {
+            return None
+        }
+        //the peer uses the session this node answered its handshake with, so that handshake
+        //was not a replayed one
+        if table.pending.get(&src) == Some(&index) {
+            table.pending.remove(&src);
+            table.current.insert(src, index);
+            table.plaintext.remove(&src);
+            table.stale.remove(&src);
+        }
+        Some(msg)
+    }
+
+    /// Gives up on handshakes that were not answered in time and forgets sessions long replaced.
+    /// Returns what to send: with encryption preferred, the messages that waited on a handshake
+    /// in plaintext, and handshakes for peers that sent datagrams of sessions this node lost
+    pub fn expire (&self, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut table = self.table.lock().unwrap();
+        let timed_out = table.handshakes.iter()
+                                        .filter(|(_, h)| now.duration_since(h.started) >= HANDSHAKE_TIMEOUT)
+                                        .map(|(addr, _)| *addr)
+                                        .collect::<Vec<SocketAddr>>();
+        let mut out = Vec::new();
+        for addr in timed_out {
+            let handshake = table.handshakes.remove(&addr).unwrap();
+            //a peer with a session keeps it, it just was not renewed
+            if self.mode == Encryption::Preferred && !table.current.contains_key(&addr) {
+                table.plaintext.insert(addr, now);
+                out.extend(handshake.queued.into_iter().map(|msg| (addr, msg)));
+            }
+        }
+        for addr in table.stale.drain().collect::<Vec<SocketAddr>>() {
+            if !table.handshakes.contains_key(&addr) {
+                out.push((addr, self.start_handshake(&mut table, addr, now)));
+            }
+        }
+        table.sessions.retain(|_, s| now.duration_since(s.established) < 2 * SESSION_LIFETIME);
+        let Table {ref mut current, ref sessions, ..} = *table;
+        current.retain(|_, index| sessions.contains_key(index));
+        let Table {ref mut pending, ref sessions, ..} = *table;
+        pending.retain(|_, index| sessions.contains_key(index));
+        table.plaintext.retain(|_, since| now.duration_since(*since) < PLAINTEXT_RETRY);
+        out
+    }
+}
+
+/// How messages leave a node: sealed in the session with their destination if there is
+/// encryption, then followed by an HMAC if the cluster has a key
+#[derive(Clone, Default)]
+pub struct Wire {
+    pub auth: Option<ClusterAuth>,
+    pub sessions: Option<Sessions>
+}
+
+impl Wire {
+    pub fn send <A: ToSocketAddrs> (&self, sock: &UdpSocket, msg: &[u8], addr: A) {
+        let dst = match addr.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+            Some(dst) => dst,
+            None => return
+        };
+        match self.sessions {
+            Some(ref sessions) => self.send_prepared(sock, sessions.outgoing(msg, dst, Instant::now()), dst),
+            None => self.send_prepared(sock, vec![msg.to_vec()], dst)
+        }
+    }
+
+    /// sends datagrams that were already sealed (or need not be) by the sessions
+    pub fn send_prepared (&self, sock: &UdpSocket, datagrams: Vec<Vec<u8>>, dst: SocketAddr) {
+        for datagram in datagrams {
+            let _ = match self.auth {
+                Some(ref auth) => sock.send_to(&auth.seal(&datagram, get_time().sec), dst),
+                None => sock.send_to(&datagram, dst)
+            };
+        }
+    }
+}
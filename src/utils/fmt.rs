@@ -1,6 +1,40 @@
+use std::fmt;
+use std::fmt::Display;
+
 pub fn as_hex_string(inpt: &[u8]) -> String {
     inpt.iter()
         .map(|b| format!("{:02x}", b))
         .collect::<Vec<String>>()
         .join("")
 }
+
+/// the inverse of as_hex_string. None if inpt is not an even number of hex digits
+pub fn from_hex_string(inpt: &str) -> Option<Vec<u8>> {
+    if !inpt.len().is_multiple_of(2) {
+        return None
+    }
+    (0..inpt.len()).step_by(2)
+                   .map(|i| inpt.get(i..i+2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                   .collect()
+}
+
+/// a string as a quoted and escaped JSON string
+pub struct JsonStr<'a>(pub &'a str);
+
+impl <'a> Display for JsonStr<'a> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?
+            }
+        }
+        f.write_str("\"")
+    }
+}
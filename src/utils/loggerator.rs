@@ -1,38 +1,249 @@
 extern crate time;
 
-use utils::fmt::as_hex_string;
-use std::fmt::Debug;
+use utils::fmt::{as_hex_string, JsonStr};
+use std::fmt;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 const ID_TRUNC:usize = 3;
+/// module paths are logged and filtered on without the crate name
+const CRATE_PREFIX: &str = "ailmedak::";
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    fn name (&self) -> &'static str {
+        match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace"
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Level, String> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level {}, expected error, warn, info, debug or trace", s))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    //[id@time LEVEL module] message key=value ...
+    Text,
+    //one JSON object per line
+    Json
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json"
+        })
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expected text or json", s))
+        }
+    }
+}
+
+/// What gets logged, how and where
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    //the most detailed level logged by modules without a level of their own
+    pub level: Level,
+    //levels of modules (and the modules below them), by module path without the crate name
+    pub modules: Vec<(String, Level)>,
+    pub format: LogFormat,
+    //file the log is appended to, stdout if None
+    pub file: Option<String>
+}
+
+impl Default for LogConfig {
+    fn default () -> LogConfig {
+        LogConfig {
+            level: Level::Info,
+            modules: vec![],
+            format: LogFormat::Text,
+            file: None
+        }
+    }
+}
+
+impl LogConfig {
+    /// Sets the levels from a comma separated list of directives: a bare level sets the default
+    /// and module=level the level of a module, e.g. `warn,api_layer=debug,node=info`
+    pub fn set_filter (&mut self, directives: &str) -> Result<(), String> {
+        for directive in directives.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            match directive.find('=') {
+                Some(i) => {
+                    let level = directive[i + 1..].parse::<Level>()?;
+                    self.modules.push((directive[..i].to_string(), level));
+                },
+                None => self.level = directive.parse::<Level>()?
+            }
+        }
+        Ok(())
+    }
+
+    /// the most detailed level logged for module. the most specific directive wins
+    pub fn level_of (&self, module: &str) -> Level {
+        let module = module.trim_start_matches(CRATE_PREFIX);
+        self.modules.iter()
+                    .filter(|(m, _)| module == m || module.starts_with(&format!("{}::", m)))
+                    .max_by_key(|(m, _)| m.len())
+                    .map_or(self.level, |(_, level)| *level)
+    }
+}
+
+/// Where the loggers of a node write to. Lines are written whole, so loggers on different
+/// threads do not interleave
+pub struct LogSink {
+    config: LogConfig,
+    out: Mutex<Box<dyn Write + Send>>
+}
+
+impl LogSink {
+    /// logs to the file of config, or stdout if it has none
+    pub fn open (config: LogConfig) -> io::Result<LogSink> {
+        let out: Box<dyn Write + Send> = match config.file {
+            Some(ref path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout())
+        };
+        Ok(LogSink {config, out: Mutex::new(out)})
+    }
+
+    pub fn stdout () -> LogSink {
+        LogSink {config: LogConfig::default(), out: Mutex::new(Box::new(io::stdout()))}
+    }
+
+    fn write_line (&self, line: &str) {
+        if let Ok(mut out) = self.out.lock() {
+            let _ = out.write_all(line.as_bytes());
+            let _ = out.flush();
+        }
+    }
+}
+
+/// A logger of one module of a node. Every line carries the id of the node
+#[derive(Clone)]
 pub struct Loggerator {
     id: String,
-    id_full: String
+    id_full: String,
+    module: &'static str,
+    level: Level,
+    sink: Arc<LogSink>
 }
 
 impl Loggerator {
-    pub fn new (id: &[u8]) -> Loggerator {
+    /// a logger for module (as given by module_path!()) writing to sink
+    pub fn new (id: &[u8], module: &'static str, sink: Arc<LogSink>) -> Loggerator {
         Loggerator {
             id: as_hex_string(&id[..ID_TRUNC]),
-            id_full: as_hex_string(id)
+            id_full: as_hex_string(id),
+            module,
+            level: sink.config.level_of(module),
+            sink
         }
     }
 
-    pub fn log <T:ToString> (&self, message: &T) {
-        println!("[{}@{}] {}", self.id, Self::now_str(), message.to_string());
+    /// a logger of the same node for another module
+    pub fn for_module (&self, module: &'static str) -> Loggerator {
+        Loggerator {
+            module,
+            level: self.sink.config.level_of(module),
+            ..self.clone()
+        }
     }
 
-    pub fn logs <T: Debug> (&self, message: &T, source_id_bytes: &[u8]) {
-        println!("[{}@{}] |{:?}| <- {}", self.id, Self::now_str(), message, as_hex_string(&source_id_bytes[..ID_TRUNC]));
+    pub fn enabled (&self, level: Level) -> bool {
+        level <= self.level
     }
 
-    pub fn log_with_full_id <T:Debug> (&self, message: &T) {
-        println!("[{}] {:?}", self.id_full, message);
+    /// logs message along with fields, if the level is enabled for the module
+    pub fn log (&self, level: Level, message: &str, fields: &[(&str, &dyn Display)]) {
+        if !self.enabled(level) {
+            return
+        }
+        let module = self.module.trim_start_matches(CRATE_PREFIX);
+        let tm = time::now_utc();
+        let line = match self.sink.config.format {
+            LogFormat::Text => {
+                let mut line = format!("[{}@{} {} {}] {}", self.id, time::strftime("%H:%M:%S.%f", &tm).unwrap(),
+                                       level.name().to_uppercase(), module, message);
+                for (name, value) in fields.iter() {
+                    line.push_str(&format!(" {}={}", name, value));
+                }
+                line
+            },
+            LogFormat::Json => {
+                let mut line = format!("{{\"ts\":\"{}\",\"level\":\"{}\",\"node\":\"{}\",\"module\":\"{}\",\"msg\":{}",
+                                       time::strftime("%Y-%m-%dT%H:%M:%S.%fZ", &tm).unwrap(), level.name(),
+                                       self.id_full, module, JsonStr(message));
+                for (name, value) in fields.iter() {
+                    line.push_str(&format!(",{}:{}", JsonStr(name), JsonStr(&value.to_string())));
+                }
+                line.push('}');
+                line
+            }
+        };
+        self.sink.write_line(&(line + "\n"));
     }
 
-    fn now_str () -> String {
-        let tm = time::now_utc();
-        time::strftime("%H:%m:%S.%f", &tm).unwrap()
+    pub fn error (&self, message: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Level::Error, message, fields);
     }
 
+    pub fn warn (&self, message: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Level::Warn, message, fields);
+    }
+
+    pub fn info (&self, message: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Level::Info, message, fields);
+    }
+
+    pub fn debug (&self, message: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Level::Debug, message, fields);
+    }
+
+    pub fn trace (&self, message: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Level::Trace, message, fields);
+    }
 }
@@ -1,4 +1,6 @@
-use std::net::{SocketAddr, Ipv4Addr};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, Ipv4Addr, TcpStream};
+use std::time::Duration;
 use utils::{u16_to_u8_2};
 
 /// converts a socket address to a tuple of an ip, port represented as bytes
@@ -13,3 +15,23 @@ pub fn ip_port_pair_bytes (s_addr: SocketAddr) -> ([u8; 4], [u8; 2]) {
 pub fn ip_port_pair(ip: &[u8; 4], port: &u16) -> (Ipv4Addr, u16) {
     (Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]), *port)
 }
+
+/// the path of an HTTP GET read off stream, None if the request is anything else or does not
+/// arrive within timeout
+pub fn read_http_get (stream: &mut TcpStream, timeout: Duration) -> Option<String> {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(timeout));
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).unwrap_or(0);
+    let request = String::from_utf8_lossy(&buf[..read]);
+    match request.split_whitespace().take(2).collect::<Vec<&str>>()[..] {
+        ["GET", path] => Some(path.to_string()),
+        _ => None
+    }
+}
+
+/// writes a whole HTTP response, after which the connection is closed
+pub fn write_http_response (stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, content_type, body.len(), body);
+}
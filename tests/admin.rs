@@ -0,0 +1,108 @@
+extern crate ailmedak;
+
+use ailmedak::api_layer::{TRACE_FLAG, hash_key};
+use ailmedak::config::Config;
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::utils::fmt::as_hex_string;
+use std::io::{Read, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+fn config(port: u16, admin_port: Option<u16>, neighbor: Option<u16>) -> Config {
+    let mut config = Config::default_with_port(port);
+    config.async_poll_interval = 50;
+    config.admin_port = admin_port;
+    config.initial_neighbors = neighbor.into_iter().map(|p| format!("127.0.0.1:{}", p)).collect();
+    config
+}
+
+/// the status line and body of a GET of path on the admin port
+fn get(port: u16, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    (head.lines().next().unwrap().to_string(), body[4..].to_string())
+}
+
+#[test]
+fn the_admin_port_reports_the_state_of_the_node() {
+    let a = AilmedakMachine::spawn(config(39401, Some(39411), None), Some([0x10; 20]));
+    let b = AilmedakMachine::spawn(config(39402, None, Some(39401)), Some([0x20; 20]));
+    let timeout = Duration::from_secs(5);
+    thread::sleep(Duration::from_millis(100));
+
+    let (status, body) = get(39411, "/node");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(body.starts_with(&format!("{{\"id\":\"{}\"", "10".repeat(20))));
+
+    //b pings a, which is enough for a to know b
+    let mut buckets = String::new();
+    for _ in 0..50 {
+        buckets = get(39411, "/buckets").1;
+        if buckets.contains(&"20".repeat(20)) {
+            break
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(buckets.contains(&format!("{{\"id\":\"{}\",\"addr\":\"127.0.0.1:39402\",\"last_seen\":1", "20".repeat(20))));
+
+    a.set(b"hello", b"world").wait_timeout(timeout).unwrap();
+    let (_, keys) = get(39411, "/keys");
+    assert!(keys.contains(&format!("{{\"key\":\"{}\",\"versions\":1,\"bytes\":5,", as_hex_string(&hash_key(b"hello")))));
+
+    let (status, lookups) = get(39411, "/lookups");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(lookups.starts_with('[') && lookups.ends_with(']'));
+
+    let (_, config) = get(39411, "/config");
+    assert!(config.starts_with("{\"network_port\":39401,\"api_port\":null,\"metrics_port\":null,\"admin_port\":39411,"));
+
+    let (status, _) = get(39411, "/metrics");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    b.shutdown();
+    a.shutdown();
+}
+
+#[test]
+fn traced_requests_leave_their_trace_on_the_admin_port() {
+    let mut with_api = config(39421, Some(39431), None);
+    with_api.api_port = Some(39441);
+    let a = AilmedakMachine::spawn(with_api, Some([0x10; 20]));
+    let b = AilmedakMachine::spawn(config(39422, None, Some(39421)), Some([0x20; 20]));
+    let timeout = Duration::from_secs(5);
+    for _ in 0..50 {
+        if !a.routing_table().wait_timeout(timeout).unwrap().is_empty() {
+            break
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    //a get of a key a does not hold makes a look it up
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(timeout)).unwrap();
+    let mut request = vec![TRACE_FLAG];
+    request.extend_from_slice(&5u32.to_be_bytes());
+    request.extend_from_slice(b"hello");
+    client.send_to(&request, "127.0.0.1:39441").unwrap();
+    let mut reply = [0; 64];
+    client.recv_from(&mut reply).unwrap();
+
+    let (status, traces) = get(39431, &format!("/traces/{}", as_hex_string(&hash_key(b"hello"))));
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(traces.starts_with(&format!("[{{\"key\":\"{}\",\"purpose\":\"get\",\"duration_ms\":", as_hex_string(&hash_key(b"hello")))));
+    //b was asked and answered with the contacts it knows
+    assert!(traces.contains(&format!("{{\"id\":\"{}\",\"addr\":\"127.0.0.1:39422\",\"sent_ms\":", "20".repeat(20))));
+    assert!(traces.contains("\"color\":\"grey\"},{\"at_ms\":"));
+    assert!(traces.contains("\"color\":\"black\"}],\"returned\":["));
+
+    //untraced lookups are not kept
+    let (_, all) = get(39431, "/traces");
+    assert_eq!(all, traces);
+
+    b.shutdown();
+    a.shutdown();
+}
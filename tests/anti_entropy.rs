@@ -0,0 +1,106 @@
+extern crate ailmedak;
+
+use ailmedak::message_protocol::{try_decode, Message};
+use ailmedak::node::anti_entropy::{SyncTrees, SYNC_TREE_TTL};
+use ailmedak::node::handoff::Handoff;
+use ailmedak::node::state::{KademliaNode, ASizedNode};
+use ailmedak::storage::ValueStore;
+use ailmedak::storage::merkle::MerkleTree;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NEAR: [u8; 20] = [0x0f; 20];
+const FAR: [u8; 20] = [0xf0; 20];
+
+/// a node that stores NEAR and FAR and replicates keys to 2 nodes
+fn node(id: [u8; 20]) -> KademliaNode {
+    let mut data = ValueStore::in_memory(60);
+    data.put(NEAR, vec![1], None).unwrap();
+    data.put(FAR, vec![2], None).unwrap();
+    let mut state = KademliaNode::new(id, 8, data, UdpSocket::bind("127.0.0.1:0").unwrap());
+    state.handoff = Handoff::new(2, 20);
+    state
+}
+
+/// adds a contact that already handed this node a write token
+fn add_contact(state: &mut KademliaNode, id: [u8; 20], sock: &UdpSocket) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    state.peer_tokens.received(sock.local_addr().unwrap(), [7; 8], now);
+    let index = KademliaNode::k_bucket_index(&state.distance_to(&id));
+    state.update_k_bucket(index, (id, sock.local_addr().unwrap()));
+}
+
+#[test]
+fn only_keys_both_nodes_replicate_are_synced() {
+    let mut state = node([0; 20]);
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let others = UdpSocket::bind("127.0.0.1:0").unwrap();
+    add_contact(&mut state, [0x01; 20], &peer);
+    //two nodes closer to FAR than this node and the peer, which leaves FAR to them
+    add_contact(&mut state, [0xf1; 20], &others);
+    add_contact(&mut state, [0xf2; 20], &others);
+
+    let shared = state.shared_tree(&[0x01; 20]);
+    assert_eq!(shared.digest(&NEAR), state.data.merkle().digest(&NEAR));
+    assert_eq!(shared.digest(&FAR), None);
+    assert!(shared.root() != state.data.merkle().root());
+
+    //the peer lists neither key, but only NEAR is pushed to it
+    let src = peer.local_addr().unwrap();
+    state.on_sync_keys(MerkleTree::leaf_of(&FAR), false, &[], src, &[0x01; 20]);
+    state.on_sync_keys(MerkleTree::leaf_of(&NEAR), false, &[], src, &[0x01; 20]);
+    let mut buf = [0; 4096];
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    match try_decode(&buf[..len], &20) {
+        Ok((Message::Store(key, ..), _)) => assert_eq!(key, NEAR),
+        other => panic!("expected a Store, got {:?}", other)
+    }
+    assert!(peer.recv_from(&mut buf).is_err());
+}
+
+#[test]
+fn shared_trees_of_all_keys_match_the_store() {
+    let mut state = node([0; 20]);
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    add_contact(&mut state, [0x01; 20], &peer);
+    assert_eq!(state.shared_tree(&[0x01; 20]).root(), state.data.merkle().root());
+}
+
+#[test]
+fn shared_trees_are_kept_for_one_exchange() {
+    let state = node([0; 20]);
+    let mut trees = SyncTrees::default();
+    trees.keep([0x01; 20], state.shared_tree(&[0x01; 20]), 100);
+    let (tree, built) = trees.take(&[0x01; 20], 100 + SYNC_TREE_TTL - 1).unwrap();
+    assert_eq!(tree.root(), state.data.merkle().root());
+    assert_eq!(built, 100);
+    assert!(trees.is_empty());
+
+    //a later exchange builds its own
+    trees.keep([0x01; 20], tree, built);
+    assert!(trees.take(&[0x01; 20], 100 + SYNC_TREE_TTL).is_none());
+    assert!(trees.is_empty());
+}
+
+#[test]
+fn the_trees_of_an_exchange_are_not_rebuilt_per_message() {
+    let mut state = node([0; 20]);
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    add_contact(&mut state, [0x01; 20], &peer);
+    state.start_sync(([0x01; 20], peer.local_addr().unwrap()));
+    assert_eq!(state.sync_trees.len(), 1);
+
+    //a key stored during the exchange is left to the next one
+    state.data.put([0x0e; 20], vec![3], None).unwrap();
+    let src = peer.local_addr().unwrap();
+    state.on_sync_keys(MerkleTree::leaf_of(&[0x0e; 20]), false, &[], src, &[0x01; 20]);
+    let mut buf = [0; 4096];
+    while let Ok((len, _)) = peer.recv_from(&mut buf) {
+        if let Ok((Message::Store(key, ..), _)) = try_decode(&buf[..len], &20) {
+            assert!(key != [0x0e; 20]);
+        }
+    }
+    assert_eq!(state.sync_trees.len(), 1);
+}
@@ -0,0 +1,86 @@
+extern crate ailmedak;
+
+use ailmedak::api_layer::{parse_request, Request, RequestError, MAX_REQUEST_LEN, READ_NOT_FOUND, REQUEST_MALFORMED, TRACE_FLAG};
+use ailmedak::config::Config;
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::storage::version::VectorClock;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+fn request(op: u8, fields: &[&[u8]], trailer: &[u8]) -> Vec<u8> {
+    let mut request = vec![op];
+    for field in fields {
+        request.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        request.extend_from_slice(field);
+    }
+    request.extend_from_slice(trailer);
+    request
+}
+
+#[test]
+fn requests_are_parsed() {
+    assert_eq!(parse_request(&request(0, &[b"key"], &[]), 8), Ok((Request::Get {key: b"key", quorum: None}, false)));
+    assert_eq!(parse_request(&request(TRACE_FLAG, &[b"key"], &[2]), 8), Ok((Request::Get {key: b"key", quorum: Some(2)}, true)));
+
+    let mut context = VectorClock::new();
+    context.set(&[1; 20], 3);
+    let mut trailer = vec![0];
+    context.encode_into(&mut trailer);
+    assert_eq!(parse_request(&request(1, &[b"key", b"val"], &trailer), 8),
+               Ok((Request::Set {key: b"key", val: b"val", quorum: None, context: Some(context.clone())}, false)));
+    assert_eq!(parse_request(&request(2, &[b"key"], &trailer), 8),
+               Ok((Request::Delete {key: b"key", quorum: None, context: Some(context)}, false)));
+}
+
+#[test]
+fn malformed_requests_are_errors() {
+    assert_eq!(parse_request(&[], 8), Err(RequestError::Empty));
+    assert_eq!(parse_request(&request(3, &[b"key"], &[]), 8), Err(RequestError::UnknownOp(3)));
+
+    //lengths past the end of the datagram
+    let mut get = request(0, &[b"key"], &[]);
+    get[1..5].copy_from_slice(&4u32.to_be_bytes());
+    assert_eq!(parse_request(&get, 8), Err(RequestError::Truncated));
+    get[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert_eq!(parse_request(&get, 8), Err(RequestError::Oversize(u32::MAX as usize)));
+    assert_eq!(parse_request(&request(1, &[b"key"], &[]), 8), Err(RequestError::Truncated));
+    let set = request(1, &[b"key", b"val"], &[]);
+    for len in 0..set.len() {
+        assert!(parse_request(&set[..len], 8).is_err());
+    }
+
+    //what trails the fields has to be a quorum and a whole context
+    assert_eq!(parse_request(&request(0, &[b"key"], &[1, 0]), 8), Err(RequestError::Malformed("trailing bytes")));
+    assert_eq!(parse_request(&request(2, &[b"key"], &[1, 0, 1]), 8), Err(RequestError::Malformed("context")));
+    assert_eq!(parse_request(&request(2, &[b"key"], &[1, 0, 0, 9]), 8), Err(RequestError::Malformed("trailing bytes")));
+
+    //a quorum no more nodes than the replication factor could meet
+    assert_eq!(parse_request(&request(0, &[b"key"], &[9]), 8), Err(RequestError::Quorum {quorum: 9, replicas: 8}));
+    assert!(parse_request(&request(2, &[b"key"], &[8]), 8).is_ok());
+}
+
+#[test]
+fn malformed_requests_get_an_error_and_the_port_keeps_serving() {
+    let mut config = Config::default_with_port(39901);
+    config.async_poll_interval = 50;
+    config.api_port = Some(39911);
+    let node = AilmedakMachine::spawn(config, Some([0x10; 20]));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reply = [0; 256];
+    let mut oversize = request(1, &[b"key"], &[]);
+    oversize.extend_from_slice(&(MAX_REQUEST_LEN as u32 * 2).to_be_bytes());
+    for bad in [vec![0, 0xff, 0xff, 0xff, 0xff], oversize, vec![7]] {
+        client.send_to(&bad, "127.0.0.1:39911").unwrap();
+        let (len, _) = client.recv_from(&mut reply).unwrap();
+        assert_eq!(reply[0], REQUEST_MALFORMED);
+        assert!(len > 1);
+    }
+
+    client.send_to(&request(0, &[b"missing"], &[]), "127.0.0.1:39911").unwrap();
+    let (len, _) = client.recv_from(&mut reply).unwrap();
+    assert_eq!(&reply[..len], &[READ_NOT_FOUND]);
+
+    node.shutdown();
+}
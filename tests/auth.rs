@@ -0,0 +1,85 @@
+extern crate ailmedak;
+
+use ailmedak::auth::{ClusterAuth, MAC_LEN};
+use ailmedak::config::Config;
+use ailmedak::node::machine::AilmedakMachine;
+use std::thread;
+use std::time::Duration;
+
+const OLD: &str = "00112233445566778899aabbccddeeff";
+const NEW: &str = "ffeeddccbbaa99887766554433221100";
+
+#[test]
+fn sealed_messages_open_only_under_the_same_key() {
+    let auth = ClusterAuth::parse(OLD).unwrap();
+    let sealed = auth.seal(b"hello", 0);
+    assert_eq!(sealed.len(), 5 + MAC_LEN);
+    assert_eq!(auth.open(&sealed, 0), Some(&b"hello"[..]));
+
+    let mut tampered = sealed.clone();
+    tampered[0] ^= 1;
+    assert_eq!(auth.open(&tampered, 0), None);
+    assert_eq!(auth.open(&sealed[..MAC_LEN - 1], 0), None);
+    assert_eq!(ClusterAuth::parse(NEW).unwrap().open(&sealed, 0), None);
+}
+
+#[test]
+fn keys_rotate_with_an_overlap() {
+    let rotating = ClusterAuth::parse(&format!("{}@100,{}", OLD, NEW)).unwrap();
+    let old = ClusterAuth::parse(OLD).unwrap();
+    let new = ClusterAuth::parse(NEW).unwrap();
+
+    //until the old key expires it signs, and both are accepted
+    assert!(old.open(&rotating.seal(b"m", 99), 99).is_some());
+    assert!(rotating.open(&new.seal(b"m", 99), 99).is_some());
+    assert!(rotating.open(&old.seal(b"m", 99), 99).is_some());
+
+    //then only the new key is
+    assert!(new.open(&rotating.seal(b"m", 100), 100).is_some());
+    assert!(rotating.open(&old.seal(b"m", 100), 100).is_none());
+}
+
+#[test]
+fn keys_are_parsed_from_a_spec() {
+    let auth = ClusterAuth::parse(&format!(" {}@1700000000 , {}\n", OLD, NEW)).unwrap();
+    assert_eq!(auth.keys().len(), 2);
+    assert_eq!(auth.keys()[0].expires, Some(1700000000));
+    assert_eq!(auth.keys()[1].expires, None);
+
+    assert!(ClusterAuth::parse("").is_err());
+    assert!(ClusterAuth::parse("0011").is_err());
+    assert!(ClusterAuth::parse("not hex at all, really not").is_err());
+    assert!(ClusterAuth::parse(&format!("{}@soon", OLD)).is_err());
+}
+
+fn config(port: u16, key: &str, neighbor: Option<u16>) -> Config {
+    let mut config = Config::default_with_port(port);
+    config.async_poll_interval = 50;
+    config.cluster_auth = Some(ClusterAuth::parse(key).unwrap());
+    config.initial_neighbors = neighbor.into_iter().map(|p| format!("127.0.0.1:{}", p)).collect();
+    config
+}
+
+#[test]
+fn only_nodes_holding_the_key_join() {
+    let a = AilmedakMachine::spawn(config(39701, OLD, None), Some([0x10; 20]));
+    let b = AilmedakMachine::spawn(config(39702, OLD, Some(39701)), Some([0x20; 20]));
+    let c = AilmedakMachine::spawn(config(39703, NEW, Some(39701)), Some([0x30; 20]));
+    let timeout = Duration::from_secs(5);
+    for _ in 0..50 {
+        if !a.routing_table().wait_timeout(timeout).unwrap().is_empty() {
+            break
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    //c had as long to get heard
+    thread::sleep(Duration::from_millis(200));
+    let table = a.routing_table().wait_timeout(timeout).unwrap();
+    let known = table.iter().flat_map(|(_, bucket)| bucket.iter().map(|&(id, _)| id)).collect::<Vec<[u8; 20]>>();
+    assert_eq!(known, vec![[0x20; 20]]);
+    assert!(c.routing_table().wait_timeout(timeout).unwrap().is_empty());
+
+    c.shutdown();
+    b.shutdown();
+    a.shutdown();
+}
@@ -0,0 +1,115 @@
+extern crate ailmedak;
+
+use ailmedak::node::diversity::{Cap, DiversityLimits};
+use ailmedak::node::state::{KademliaNode, ASizedNode};
+use ailmedak::storage::ValueStore;
+use std::net::{SocketAddr, UdpSocket};
+
+fn node(diversity: DiversityLimits) -> KademliaNode {
+    let mut state = KademliaNode::new([0; 20], 8, ValueStore::in_memory(60), UdpSocket::bind("127.0.0.1:0").unwrap());
+    state.diversity = diversity;
+    state
+}
+
+/// adds a contact with the id at addr, returning its k-bucket
+fn add(state: &mut KademliaNode, id: [u8; 20], addr: &str) -> usize {
+    let index = KademliaNode::k_bucket_index(&state.distance_to(&id));
+    state.update_k_bucket(index, (id, addr.parse::<SocketAddr>().unwrap()));
+    index
+}
+
+fn id(first: u8, last: u8) -> [u8; 20] {
+    let mut id = [0; 20];
+    id[0] = first;
+    id[19] = last;
+    id
+}
+
+fn known(state: &KademliaNode, id: [u8; 20]) -> bool {
+    state.contacts().iter().any(|&(n, _)| n == id)
+}
+
+#[test]
+fn a_bucket_holds_few_contacts_of_one_prefix() {
+    let mut state = node(DiversityLimits {
+        same_ip: Cap {per_bucket: Some(1), per_table: None},
+        same_prefix: Cap {per_bucket: Some(2), per_table: None},
+        exempt_local: true
+    });
+    //all of these land in the last bucket
+    let bucket = add(&mut state, id(0x80, 1), "203.0.113.1:3000");
+    add(&mut state, id(0x80, 2), "203.0.113.1:3001");
+    add(&mut state, id(0x80, 3), "203.0.113.2:3000");
+    add(&mut state, id(0x80, 4), "203.0.113.3:3000");
+    add(&mut state, id(0x80, 5), "[::ffff:203.0.113.4]:3000");
+    add(&mut state, id(0x80, 6), "198.51.100.1:3000");
+    assert_eq!(state.buckets[bucket].iter().map(|&(n, _)| n[19]).collect::<Vec<u8>>(), vec![1, 3, 6]);
+    //the rest wait in the replacement cache, most recent last
+    assert_eq!(state.replacements.of(bucket).iter().map(|&(n, _)| n[19]).collect::<Vec<u8>>(), vec![2, 4, 5]);
+
+    //contacts already in the bucket are still refreshed
+    add(&mut state, id(0x80, 1), "203.0.113.1:3000");
+    assert_eq!(state.buckets[bucket].last().unwrap().0, id(0x80, 1));
+}
+
+#[test]
+fn the_routing_table_holds_few_contacts_of_one_prefix() {
+    let mut state = node(DiversityLimits {
+        same_ip: Cap {per_bucket: None, per_table: None},
+        same_prefix: Cap {per_bucket: None, per_table: Some(2)},
+        exempt_local: true
+    });
+    add(&mut state, id(0x80, 1), "[2001:db8:1:1::1]:3000");
+    add(&mut state, id(0x40, 1), "[2001:db8:1:1::2]:3000");
+    add(&mut state, id(0x20, 1), "[2001:db8:1:1:ffff::3]:3000");
+    add(&mut state, id(0x10, 1), "[2001:db8:1:2::1]:3000");
+    assert!(known(&state, id(0x80, 1)) && known(&state, id(0x40, 1)) && known(&state, id(0x10, 1)));
+    assert!(!known(&state, id(0x20, 1)));
+}
+
+#[test]
+fn local_addresses_are_exempt_unless_asked_otherwise() {
+    let mut limits = DiversityLimits::default();
+    let mut state = node(limits.clone());
+    for i in 0..5 {
+        add(&mut state, id(0x80, i), &format!("127.0.0.1:{}", 3000 + i as u16));
+    }
+    assert_eq!(state.contacts().len(), 5);
+
+    limits.exempt_local = false;
+    let mut state = node(limits);
+    for i in 0..5 {
+        add(&mut state, id(0x80, i), &format!("10.0.0.1:{}", 3000 + i as u16));
+    }
+    assert_eq!(state.contacts().len(), 1);
+}
+
+#[test]
+fn replacements_take_the_place_of_contacts_that_leave() {
+    let mut state = node(DiversityLimits {
+        same_ip: Cap {per_bucket: Some(1), per_table: None},
+        same_prefix: Cap {per_bucket: None, per_table: None},
+        exempt_local: true
+    });
+    let bucket = add(&mut state, id(0x80, 1), "203.0.113.1:3000");
+    add(&mut state, id(0x80, 2), "203.0.113.1:3001");
+    add(&mut state, id(0x80, 3), "203.0.113.1:3002");
+    assert!(!known(&state, id(0x80, 3)));
+
+    assert!(state.drop_contact(&id(0x80, 1)));
+    assert!(known(&state, id(0x80, 3)));
+    assert_eq!(state.replacements.of(bucket).len(), 1);
+
+    //a replacement that leaves is forgotten
+    assert!(!state.drop_contact(&id(0x80, 2)));
+    assert!(state.replacements.of(bucket).is_empty());
+}
+
+#[test]
+fn caps_are_parsed_and_printed() {
+    assert_eq!("2:10".parse::<Cap>(), Ok(Cap {per_bucket: Some(2), per_table: Some(10)}));
+    assert_eq!("none:4".parse::<Cap>(), Ok(Cap {per_bucket: None, per_table: Some(4)}));
+    assert_eq!(Cap {per_bucket: Some(1), per_table: None}.to_string(), "1:none");
+    assert!("2".parse::<Cap>().is_err());
+    assert!("a:b".parse::<Cap>().is_err());
+}
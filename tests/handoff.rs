@@ -0,0 +1,136 @@
+extern crate ailmedak;
+
+use ailmedak::message_protocol::{try_decode, Message};
+use ailmedak::node::handoff::HANDOFF_SCAN;
+use ailmedak::node::state::{KademliaNode, ASizedNode};
+use ailmedak::storage::ValueStore;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn node(id: [u8; 20]) -> KademliaNode {
+    let mut data = ValueStore::in_memory(60);
+    data.put([0x0f; 20], vec![1], None).unwrap();
+    data.put([0xf0; 20], vec![2], None).unwrap();
+    KademliaNode::new(id, 8, data, UdpSocket::bind("127.0.0.1:0").unwrap())
+}
+
+/// adds a contact that already handed this node a write token
+fn add_contact(state: &mut KademliaNode, id: [u8; 20], sock: &UdpSocket) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    state.peer_tokens.received(sock.local_addr().unwrap(), [7; 8], now);
+    let index = KademliaNode::k_bucket_index(&state.distance_to(&id));
+    state.update_k_bucket(index, (id, sock.local_addr().unwrap()));
+}
+
+#[test]
+fn new_contacts_are_handed_off_stored_keys() {
+    let mut state = node([0; 20]);
+    let joiner = UdpSocket::bind("127.0.0.1:0").unwrap();
+    joiner.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    add_contact(&mut state, [0xff; 20], &joiner);
+    //the keys are looked for on the next drain
+    assert_eq!(state.handoff.len(), 0);
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), 2);
+
+    //seeing the contact again does not hand anything off twice
+    add_contact(&mut state, [0xff; 20], &joiner);
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), 2);
+
+    state.drain_handoff();
+    assert!(state.handoff.is_empty());
+    let mut keys = Vec::new();
+    for _ in 0..2 {
+        let mut buf = [0; 4096];
+        let (len, _) = joiner.recv_from(&mut buf).unwrap();
+        match try_decode(&buf[..len], &20) {
+            Ok((Message::Store(key, _, _, token), _)) if token == [7; 8] => keys.push(key),
+            other => panic!("expected a Store, got {:?}", other)
+        }
+    }
+    keys.sort();
+    assert_eq!(keys, vec![[0x0f; 20], [0xf0; 20]]);
+}
+
+#[test]
+fn keys_closer_to_other_contacts_are_left_to_them() {
+    let mut state = node([0; 20]);
+    let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let joiner = UdpSocket::bind("127.0.0.1:0").unwrap();
+    //closer to 0xf0.. than this node is, so it hands that key off instead
+    add_contact(&mut state, [0xf1; 20], &other);
+    state.drain_handoff();
+    add_contact(&mut state, [0xff; 20], &joiner);
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), 1);
+}
+
+#[test]
+fn keys_are_looked_for_a_few_at_a_time() {
+    let mut state = node([0; 20]);
+    for i in 0..HANDOFF_SCAN {
+        let mut key = [0x0f; 20];
+        key[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        state.data.put(key, vec![3], None).unwrap();
+    }
+    let joiner = UdpSocket::bind("127.0.0.1:0").unwrap();
+    add_contact(&mut state, [0xff; 20], &joiner);
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), HANDOFF_SCAN);
+    assert!(!state.handoff.is_empty());
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), HANDOFF_SCAN + 2);
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), HANDOFF_SCAN + 2);
+}
+
+#[test]
+fn leaving_hands_every_key_to_the_closest_contacts() {
+    let mut state = node([0; 20]);
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    add_contact(&mut state, [0xff; 20], &peer);
+    state.drain_handoff();
+    let mut buf = [0; 4096];
+    while peer.recv_from(&mut buf).is_ok() {}
+
+    state.begin_leave();
+    assert_eq!(state.handoff.len(), 2);
+    state.drain_handoff();
+    state.announce_leave();
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        received.push(try_decode(&buf[..len], &20).unwrap().0);
+    }
+    assert!(matches!(received[0], Message::Store(..)));
+    assert!(matches!(received[1], Message::Store(..)));
+    assert!(received[2] == Message::Leave);
+}
+
+#[test]
+fn contacts_that_leave_are_dropped() {
+    let mut state = node([0; 20]);
+    let leaver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    add_contact(&mut state, [0xff; 20], &leaver);
+    state.scan_handoff();
+    assert_eq!(state.handoff.len(), 2);
+    assert!(state.drop_contact(&[0xff; 20]));
+    assert!(state.contacts().is_empty());
+    //nothing is handed off to a node that is gone
+    assert!(state.handoff.is_empty());
+    assert!(!state.drop_contact(&[0xff; 20]));
+}
+
+#[test]
+fn leaves_from_another_address_are_ignored() {
+    let mut state = node([0; 20]);
+    let leaver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    add_contact(&mut state, [0xff; 20], &leaver);
+    assert!(!state.on_leave(&[0xff; 20], spoofer.local_addr().unwrap()));
+    assert_eq!(state.contacts().len(), 1);
+    assert!(state.on_leave(&[0xff; 20], leaver.local_addr().unwrap()));
+    assert!(state.contacts().is_empty());
+}
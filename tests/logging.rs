@@ -0,0 +1,67 @@
+extern crate ailmedak;
+
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use ailmedak::utils::loggerator::{Loggerator, LogSink, LogConfig, LogFormat, Level};
+
+const ID: [u8; 20] = [0xab; 20];
+
+fn log_file(name: &str) -> String {
+    let path = env::temp_dir().join(format!("ailmedak-log-{}-{}", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn filters_pick_the_most_specific_module() {
+    let mut config = LogConfig::default();
+    config.set_filter("warn,node=debug,node::machine=error").unwrap();
+    assert_eq!(config.level, Level::Warn);
+    assert_eq!(config.level_of("ailmedak::api_layer"), Level::Warn);
+    assert_eq!(config.level_of("ailmedak::node::state"), Level::Debug);
+    assert_eq!(config.level_of("ailmedak::node::machine"), Level::Error);
+    //a module is not a prefix of every module that starts with its name
+    assert_eq!(config.level_of("ailmedak::nodes"), Level::Warn);
+    assert!(config.set_filter("api_layer=loud").is_err());
+}
+
+#[test]
+fn json_lines_go_to_the_log_file() {
+    let path = log_file("json");
+    let mut config = LogConfig::default();
+    config.set_filter("info,api_layer=debug").unwrap();
+    config.format = LogFormat::Json;
+    config.file = Some(path.clone());
+    let sink = Arc::new(LogSink::open(config).unwrap());
+
+    let machine = Loggerator::new(&ID, "ailmedak::node::machine", sink);
+    let api = machine.for_module("ailmedak::api_layer");
+    machine.debug("left out", &[]);
+    machine.info("node bound to cluster port", &[("port", &3000)]);
+    api.debug("set", &[("from", &"127.0.0.1:5000"), ("note", &"say \"hi\"")]);
+
+    let log = fs::read_to_string(&path).unwrap();
+    let lines = log.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("{\"ts\":\""));
+    assert!(lines[0].ends_with(&format!("\"level\":\"info\",\"node\":\"{}\",\"module\":\"node::machine\",\"msg\":\"node bound to cluster port\",\"port\":\"3000\"}}",
+                                        "ab".repeat(20))));
+    assert!(lines[1].contains("\"level\":\"debug\""));
+    assert!(lines[1].ends_with("\"module\":\"api_layer\",\"msg\":\"set\",\"from\":\"127.0.0.1:5000\",\"note\":\"say \\\"hi\\\"\"}"));
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn text_lines_carry_the_time_level_and_fields() {
+    let path = log_file("text");
+    let config = LogConfig {file: Some(path.clone()), ..LogConfig::default()};
+    let logger = Loggerator::new(&ID, "ailmedak::metrics", Arc::new(LogSink::open(config).unwrap()));
+    logger.warn("rejected store", &[("key", &"00ff"), ("reason", &"too large")]);
+
+    let log = fs::read_to_string(&path).unwrap();
+    //[ababab@HH:MM:SS.nanos WARN metrics] ...
+    assert!(log.starts_with("[ababab@"));
+    assert!(log.ends_with(" WARN metrics] rejected store key=00ff reason=too large\n"));
+    let _ = fs::remove_file(&path);
+}
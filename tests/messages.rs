@@ -1,13 +1,22 @@
 extern crate ailmedak;
 
 use ailmedak::message_protocol::*;
-use ailmedak::node::NodeAddr;
+use ailmedak::node::state::NodeAddr;
+use ailmedak::storage::version::VectorClock;
 
 const KEYSIZE:&'static usize = &20;
 static MOCK_ID:[u8; 20] = [9; 20];
 
 struct MessageFactory;
 
+fn clock(counters: &[(u8, u64)]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for &(actor, counter) in counters {
+        clock.set(&[actor; 20], counter);
+    }
+    clock
+}
+
 impl ProtoMessage for MessageFactory {
     fn id(&self) -> &NodeAddr{
         &MOCK_ID
@@ -32,9 +41,23 @@ fn msg_ping_ack() {
 fn msg_store() {
     let key = [10; 20];
     let val = vec![1, 2, 3, 4, 5];
-    let store = MessageFactory.store_msg(&key, &val);
+    let version = clock(&[(1, 2), (3, 4)]);
+    let store = MessageFactory.store_msg(&key, &version, &val, &[7; TOKEN_LEN]);
     let ds = try_decode(&store, KEYSIZE).unwrap();
-    assert_eq!(ds, (Message::Store(key, val), MOCK_ID));
+    assert_eq!(ds, (Message::Store(key, version, val, [7; TOKEN_LEN]), MOCK_ID));
+}
+
+#[test]
+fn msg_find_val_resp() {
+    let key = [10; 20];
+    let siblings = vec![(clock(&[(1, 1)]), vec![1, 2]),
+                        (clock(&[(2, 1)]), vec![]),
+                        (clock(&[(1, 1), (2, 1)]), vec![3])];
+    let find_val_resp = MessageFactory.find_val_resp(&key, &siblings, &[7; TOKEN_LEN]);
+    let ds = try_decode(&find_val_resp, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::FindValResp(key, siblings, [7; TOKEN_LEN]), MOCK_ID));
+    //a sibling cut short invalidates the message
+    assert!(try_decode(&find_val_resp[..find_val_resp.len() - 1], KEYSIZE).is_err());
 }
 
 #[test]
@@ -45,6 +68,15 @@ fn msg_find_node() {
     assert_eq!(ds, (Message::FindNode(key), MOCK_ID));
 }
 
+#[test]
+fn msg_find_node_resp() {
+    let key = [10; 20];
+    let closest = vec![([1; 20], ([5; 20], ([1, 2, 3, 4], [1, 2])))];
+    let find_node_resp = MessageFactory.find_node_resp(&closest, &key, &[7; TOKEN_LEN]);
+    let ds = try_decode(&find_node_resp, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::FindNodeResp(key, vec![NodeContact {id: [5; 20], ip: [1, 2, 3, 4], port: 258}], [7; TOKEN_LEN]), MOCK_ID));
+}
+
 #[test]
 fn msg_find_val() {
     let key = [10; 20];
@@ -66,3 +98,205 @@ fn resp_find_node() {
                                           //([1; 20])
                                           ]), MOCK_ID));
 }*/ //on hold. refactoring
+
+#[test]
+fn msg_delete() {
+    let key = [10; 20];
+    let version = clock(&[(5, 1)]);
+    let delete = MessageFactory.delete_msg(&key, &version, &[7; TOKEN_LEN]);
+    let ds = try_decode(&delete, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::Delete(key, version, [7; TOKEN_LEN]), MOCK_ID));
+}
+
+#[test]
+fn msg_store_resp() {
+    let key = [10; 20];
+    let store_resp = MessageFactory.store_resp_msg(&key, StoreStatus::QuotaExceeded);
+    let ds = try_decode(&store_resp, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::StoreResp(key, StoreStatus::QuotaExceeded), MOCK_ID));
+}
+
+#[test]
+fn msg_error() {
+    let error = MessageFactory.error_msg(OP_STORE, "disk full");
+    let ds = try_decode(&error, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::Error(OP_STORE, "disk full".to_string()), MOCK_ID));
+}
+
+#[test]
+fn msg_sync_tree() {
+    let nodes = vec![(1, [3; 20]), (4095, [4; 20])];
+    let sync_tree = MessageFactory.sync_tree_msg(&nodes);
+    let ds = try_decode(&sync_tree, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::SyncTree(nodes), MOCK_ID));
+}
+
+#[test]
+fn msg_sync_keys() {
+    let keys = vec![([1; 20], [2; 20]), ([3; 20], [4; 20])];
+    let sync_keys = MessageFactory.sync_keys_msg(4100, true, &keys);
+    let ds = try_decode(&sync_keys, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::SyncKeys(4100, true, keys), MOCK_ID));
+    assert!(try_decode(&sync_keys[..sync_keys.len() - 1], KEYSIZE).is_err());
+}
+
+#[test]
+fn msg_leave() {
+    let leave = MessageFactory.leave_msg();
+    let ds = try_decode(&leave, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::Leave, MOCK_ID));
+}
+
+#[test]
+fn msg_handshake() {
+    let handshake = MessageFactory.handshake_msg(7, &[1; 32], &[2; 32]);
+    let ds = try_decode(&handshake, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::Handshake(7, [1; 32], [2; 32]), MOCK_ID));
+    let handshake_resp = MessageFactory.handshake_resp_msg(7, 8, &[1; 32], &[2; 32]);
+    let ds = try_decode(&handshake_resp, KEYSIZE).unwrap();
+    assert_eq!(ds, (Message::HandshakeResp(7, 8, [1; 32], [2; 32]), MOCK_ID));
+}
+
+fn samples() -> Vec<Vec<u8>> {
+    let key = [10; 20];
+    vec![MessageFactory.ping_msg().to_vec(),
+         MessageFactory.store_msg(&key, &clock(&[(1, 2)]), b"value", &[7; TOKEN_LEN]),
+         MessageFactory.find_node_msg(&key).to_vec(),
+         MessageFactory.find_node_resp(&vec![([1; 20], ([5; 20], ([1, 2, 3, 4], [1, 2])))], &key, &[7; TOKEN_LEN]),
+         MessageFactory.find_val_resp(&key, &[(clock(&[(1, 1)]), vec![1, 2])], &[7; TOKEN_LEN]),
+         MessageFactory.delete_msg(&key, &clock(&[(5, 1)]), &[7; TOKEN_LEN]),
+         MessageFactory.store_resp_msg(&key, StoreStatus::Ok),
+         MessageFactory.error_msg(OP_STORE, "disk full"),
+         MessageFactory.sync_tree_msg(&[(1, [3; 20])]),
+         MessageFactory.sync_keys_msg(1, false, &[([1; 20], [2; 20])]),
+         MessageFactory.leave_msg(),
+         MessageFactory.handshake_msg(7, &[1; 32], &[2; 32])]
+}
+
+#[test]
+fn truncated_messages_are_errors() {
+    for sample in samples() {
+        for len in 0..sample.len() {
+            assert!(try_decode(&sample[..len], KEYSIZE).is_err(), "{:?} cut to {} bytes decoded", sample, len);
+        }
+    }
+    assert_eq!(try_decode(&[], KEYSIZE), Err(DecodeError::Truncated));
+    assert_eq!(try_decode(&[OP_FIND_NODE, 9, 9], KEYSIZE), Err(DecodeError::Truncated));
+}
+
+#[test]
+fn lengths_must_match_the_payload() {
+    let mut find_node = MessageFactory.find_node_msg(&[10; 20]).to_vec();
+    find_node.push(0);
+    assert_eq!(try_decode(&find_node, KEYSIZE), Err(DecodeError::LengthMismatch {declared: 20, actual: 21}));
+
+    //a store whose length field leaves out part of the value
+    let mut store = MessageFactory.store_msg(&[10; 20], &clock(&[]), b"value", &[7; TOKEN_LEN]);
+    let declared = (store.len() - 25 - 1) as u32;
+    store[21..25].copy_from_slice(&declared.to_be_bytes());
+    assert!(matches!(try_decode(&store, KEYSIZE), Err(DecodeError::LengthMismatch {..})));
+
+    //trailing bytes the fields do not take up: the length field says 22, the fields hold 21
+    let mut store_resp = MessageFactory.store_resp_msg(&[10; 20], StoreStatus::Ok);
+    store_resp.push(0);
+    store_resp[21..25].copy_from_slice(&22u32.to_be_bytes());
+    assert_eq!(try_decode(&store_resp, KEYSIZE), Err(DecodeError::LengthMismatch {declared: 22, actual: 21}));
+
+    //a contact cut short
+    let mut find_node_resp = MessageFactory.find_node_resp(&vec![([1; 20], ([5; 20], ([1, 2, 3, 4], [1, 2])))], &[10; 20], &[7; TOKEN_LEN]);
+    find_node_resp.pop();
+    let declared = (find_node_resp.len() - 25) as u32;
+    find_node_resp[21..25].copy_from_slice(&declared.to_be_bytes());
+    assert_eq!(try_decode(&find_node_resp, KEYSIZE), Err(DecodeError::LengthMismatch {declared: declared as usize, actual: 28}));
+
+    let mut ping = MessageFactory.ping_msg().to_vec();
+    ping.push(0);
+    assert_eq!(try_decode(&ping, KEYSIZE), Err(DecodeError::LengthMismatch {declared: 0, actual: 1}));
+}
+
+#[test]
+fn unknown_and_oversize_messages_are_errors() {
+    let mut leave = MessageFactory.leave_msg();
+    leave[0] = 200;
+    assert_eq!(try_decode(&leave, KEYSIZE), Err(DecodeError::UnknownOpcode(200)));
+
+    let mut huge = MessageFactory.leave_msg();
+    huge[21..25].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert_eq!(try_decode(&huge, KEYSIZE), Err(DecodeError::Oversize(u32::MAX as usize)));
+    assert_eq!(try_decode(&vec![OP_LEAVE; MAX_MESSAGE_LEN + 1], KEYSIZE), Err(DecodeError::Oversize(MAX_MESSAGE_LEN + 1)));
+
+    let mut sync_tree = MessageFactory.sync_tree_msg(&[]);
+    sync_tree[25..27].copy_from_slice(&((MAX_SYNC_NODES + 1) as u16).to_be_bytes());
+    assert_eq!(try_decode(&sync_tree, KEYSIZE), Err(DecodeError::Oversize(MAX_SYNC_NODES + 1)));
+
+    let mut store_resp = MessageFactory.store_resp_msg(&[10; 20], StoreStatus::Ok);
+    *store_resp.last_mut().unwrap() = 99;
+    assert_eq!(try_decode(&store_resp, KEYSIZE), Err(DecodeError::Malformed("store status")));
+}
+
+#[test]
+fn garbled_messages_never_panic() {
+    //the fuzz target does this at scale, see fuzz/
+    let mut seed: u32 = 1;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+    for sample in samples() {
+        for _ in 0..2000 {
+            let mut garbled = sample.clone();
+            let flips = 1 + next() as usize % 4;
+            for _ in 0..flips {
+                let at = next() as usize % garbled.len();
+                garbled[at] = next() as u8;
+            }
+            let _ = try_decode(&garbled, KEYSIZE);
+        }
+    }
+}
+
+#[test]
+fn views_borrow_from_the_message() {
+    let version = clock(&[(1, 2), (3, 4)]);
+    let store = MessageFactory.store_msg(&[10; 20], &version, b"value", &[7; TOKEN_LEN]);
+    match MessageView::parse(&store).unwrap() {
+        (MessageView::Store(key, clock, val, token), sender) => {
+            assert_eq!((key, val, token, sender), (&[10; 20], &b"value"[..], &[7; TOKEN_LEN], &MOCK_ID));
+            assert!(store.as_ptr_range().contains(&val.as_ptr()));
+            assert!(store.as_ptr_range().contains(&key.as_ptr()));
+            assert_eq!(clock.entries().collect::<Vec<_>>(), vec![(&[1; 20], 2), (&[3; 20], 4)]);
+            assert_eq!(clock.to_clock(), version);
+        },
+        other => panic!("expected a Store, got {:?}", other)
+    }
+}
+
+#[test]
+fn view_lists_are_read_as_they_are_iterated() {
+    let closest = vec![([1; 20], ([5; 20], ([1, 2, 3, 4], [1, 2]))),
+                       ([2; 20], ([6; 20], ([5, 6, 7, 8], [0, 3])))];
+    let find_node_resp = MessageFactory.find_node_resp(&closest, &[10; 20], &[7; TOKEN_LEN]);
+    let (view, _) = MessageView::parse(&find_node_resp).unwrap();
+    match view.clone() {
+        MessageView::FindNodeResp(_, mut contacts, _) => {
+            assert_eq!(contacts.len(), 2);
+            assert_eq!(contacts.next(), Some(NodeContact {id: &[5; 20], ip: [1, 2, 3, 4], port: 258}));
+            assert_eq!(contacts.next(), Some(NodeContact {id: &[6; 20], ip: [5, 6, 7, 8], port: 3}));
+            assert_eq!(contacts.next(), None);
+        },
+        other => panic!("expected a FindNodeResp, got {:?}", other)
+    }
+    assert_eq!((view.to_message(), MOCK_ID), try_decode(&find_node_resp, KEYSIZE).unwrap());
+
+    let siblings = vec![(clock(&[(1, 1)]), vec![1, 2]), (clock(&[]), vec![])];
+    let find_val_resp = MessageFactory.find_val_resp(&[10; 20], &siblings, &[7; TOKEN_LEN]);
+    match MessageView::parse(&find_val_resp).unwrap().0 {
+        MessageView::FindValResp(_, views, _) => {
+            let read = views.map(|(clock, val)| (clock.to_clock(), val.to_vec())).collect::<Vec<Sibling>>();
+            assert_eq!(read, siblings);
+        },
+        other => panic!("expected a FindValResp, got {:?}", other)
+    }
+}
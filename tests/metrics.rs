@@ -0,0 +1,39 @@
+extern crate ailmedak;
+
+use ailmedak::message_protocol::{OP_STORE, OP_FIND_NODE};
+use ailmedak::metrics::Metrics;
+
+#[test]
+fn metrics_render_as_prometheus_text() {
+    let metrics = Metrics::new();
+    metrics.received(OP_STORE);
+    metrics.received(OP_STORE);
+    metrics.sent(&[OP_FIND_NODE, 0, 0]);
+    metrics.decode_failed();
+    metrics.api_request(1);
+    metrics.set_store(3, 120);
+    metrics.lookup_latency.observe(0.02);
+    metrics.lookup_latency.observe(3.0);
+    metrics.lookup_hops.observe(2.0);
+
+    let text = metrics.render();
+    let lines = text.lines().collect::<Vec<&str>>();
+    for expected in ["ailmedak_messages_received_total{type=\"Store\"} 2",
+                     "ailmedak_messages_received_total{type=\"Ping\"} 0",
+                     "ailmedak_messages_sent_total{type=\"FindNode\"} 1",
+                     "ailmedak_decode_failures_total 1",
+                     "ailmedak_api_requests_total{op=\"set\"} 1",
+                     "ailmedak_stored_keys 3",
+                     "ailmedak_stored_bytes 120",
+                     "ailmedak_bucket_contacts{bucket=\"160\"} 0",
+                     "# TYPE ailmedak_lookup_duration_seconds histogram",
+                     "ailmedak_lookup_duration_seconds_bucket{le=\"0.01\"} 0",
+                     "ailmedak_lookup_duration_seconds_bucket{le=\"0.025\"} 1",
+                     "ailmedak_lookup_duration_seconds_bucket{le=\"5\"} 2",
+                     "ailmedak_lookup_duration_seconds_bucket{le=\"+Inf\"} 2",
+                     "ailmedak_lookup_duration_seconds_count 2",
+                     "ailmedak_lookup_hops_bucket{le=\"2\"} 1",
+                     "ailmedak_lookup_hops_sum 2"].iter() {
+        assert!(lines.contains(expected), "missing {}", expected);
+    }
+}
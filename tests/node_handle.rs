@@ -0,0 +1,101 @@
+extern crate ailmedak;
+
+use ailmedak::api_layer::{ReadResult, hash_key};
+use ailmedak::config::Config;
+use ailmedak::message_protocol::StoreStatus;
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::node::events::Event;
+use std::time::{Duration, Instant};
+
+fn config(port: u16, neighbor: Option<u16>) -> Config {
+    let mut config = Config::default_with_port(port);
+    config.async_poll_interval = 50;
+    config.initial_neighbors = neighbor.into_iter().map(|p| format!("127.0.0.1:{}", p)).collect();
+    config
+}
+
+#[test]
+fn spawned_nodes_are_used_through_their_handles() {
+    let a = AilmedakMachine::spawn(config(39301, None), Some([0x10; 20]));
+    let b = AilmedakMachine::spawn(config(39302, Some(39301)), Some([0x20; 20]));
+    assert_eq!(a.id(), &[0x10; 20]);
+
+    //b pings a, which adds b to its k-buckets and answers
+    let timeout = Duration::from_secs(5);
+    let mut joined = false;
+    for _ in 0..50 {
+        let table = b.routing_table().wait_timeout(timeout).unwrap();
+        if table.iter().any(|(_, bucket)| bucket.iter().any(|(id, _)| *id == [0x10; 20])) {
+            joined = true;
+            break
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(joined);
+
+    let found = a.lookup([0x20; 20]).wait_timeout(timeout).unwrap();
+    assert!(found.iter().any(|c| c.id == [0x20; 20]));
+
+    let written = b.set(b"hello", b"world").wait_timeout(timeout).unwrap();
+    assert_eq!(written.status, Some(StoreStatus::Ok));
+    assert!(written.acks >= 1);
+    match a.get(b"hello").wait_timeout(timeout).unwrap() {
+        ReadResult::Found(siblings) => {
+            assert_eq!(siblings.len(), 1);
+            assert_eq!(siblings[0].1, b"world".to_vec());
+        },
+        other => panic!("expected the value, got {:?}", other)
+    }
+
+    b.shutdown();
+    a.shutdown();
+}
+
+#[test]
+fn subscribers_see_the_events_they_filter_for() {
+    let a = AilmedakMachine::spawn(config(39303, None), Some([0x30; 20]));
+    let contacts = a.subscribe(|e| matches!(e, Event::ContactAdded(..) | Event::JoinCompleted(..)));
+    let values = a.subscribe(|e| matches!(e, Event::ValueStored(..)));
+    let b = AilmedakMachine::spawn(config(39304, Some(39303)), Some([0x40; 20]));
+
+    let timeout = Duration::from_secs(5);
+    match contacts.recv_timeout(timeout).unwrap() {
+        Event::ContactAdded(id, _) => assert_eq!(id, [0x40; 20]),
+        other => panic!("expected b to be added, got {:?}", other)
+    }
+    //b was a's first contact, so a joins through it
+    match contacts.recv_timeout(timeout).unwrap() {
+        Event::JoinCompleted(closest) => assert!(closest.iter().any(|c| c.id == [0x40; 20])),
+        other => panic!("expected a to join, got {:?}", other)
+    }
+
+    b.set(b"key", b"val").wait_timeout(timeout).unwrap();
+    assert_eq!(values.recv_timeout(timeout).unwrap(), Event::ValueStored(hash_key(b"key")));
+
+    b.shutdown();
+    a.shutdown();
+}
+
+#[test]
+fn requests_on_the_same_key_are_answered_separately() {
+    let a = AilmedakMachine::spawn(config(39305, None), Some([0x50; 20]));
+    let b = AilmedakMachine::spawn(config(39306, Some(39305)), Some([0x60; 20]));
+    let timeout = Duration::from_secs(5);
+    while a.routing_table().wait_timeout(timeout).unwrap().is_empty() {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    //both lookups of the key hear from a, neither waits for it to time out
+    let started = Instant::now();
+    let first = b.set(b"key", b"one");
+    let second = b.set(b"key", b"two");
+    for pending in [first, second] {
+        let written = pending.wait_timeout(timeout).unwrap();
+        assert_eq!(written.status, Some(StoreStatus::Ok));
+        assert!(written.acks >= 1);
+    }
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    b.shutdown();
+    a.shutdown();
+}
@@ -0,0 +1,36 @@
+extern crate ailmedak;
+
+use ailmedak::config::Config;
+
+#[test]
+fn nodes_replicate_to_8_and_wait_for_one_replica_by_default() {
+    let replication = Config::default_with_port(4000).replication();
+    assert_eq!((replication.n, replication.r, replication.w), (8, 1, 1));
+}
+
+#[test]
+fn replication_follows_the_config() {
+    let mut config = Config::default_with_port(4000);
+    config.replication_factor = 5;
+    config.read_quorum = 3;
+    config.write_quorum = 2;
+    let replication = config.replication();
+    assert_eq!((replication.n, replication.r, replication.w), (5, 3, 2));
+}
+
+#[test]
+fn quorums_are_kept_between_1_and_the_replication_factor() {
+    let mut config = Config::default_with_port(4000);
+    config.replication_factor = 3;
+    config.read_quorum = 0;
+    config.write_quorum = 9;
+    let replication = config.replication();
+    assert_eq!((replication.r, replication.w), (1, 3));
+
+    assert_eq!(replication.read_quorum(None), 1);
+    assert_eq!(replication.read_quorum(Some(0)), 1);
+    assert_eq!(replication.read_quorum(Some(2)), 2);
+    assert_eq!(replication.write_quorum(None), 3);
+    assert_eq!(replication.write_quorum(Some(0)), 1);
+    assert_eq!(replication.write_quorum(Some(9)), 3);
+}
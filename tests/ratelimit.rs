@@ -0,0 +1,103 @@
+extern crate ailmedak;
+
+use ailmedak::api_layer::REQUEST_NAMES;
+use ailmedak::config::Config;
+use ailmedak::message_protocol::{MESSAGE_NAMES, OP_PING, OP_STORE};
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::ratelimit::{Rate, RateLimits, RateLimiter, Verdict};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+fn ip(last: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+}
+
+#[test]
+fn a_source_gets_its_burst_then_the_rate() {
+    let mut limiter = RateLimiter::new(RateLimits::with_default(Rate::new(10.0, 3.0)));
+    let now = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Allow);
+    }
+    assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Drop);
+    //other sources and other message types have buckets of their own
+    assert_eq!(limiter.check(ip(2), OP_PING, now), Verdict::Allow);
+    assert_eq!(limiter.check(ip(1), OP_STORE, now), Verdict::Allow);
+
+    //a tenth of a second refills one token
+    let later = now + Duration::from_millis(100);
+    assert_eq!(limiter.check(ip(1), OP_PING, later), Verdict::Allow);
+    assert_eq!(limiter.check(ip(1), OP_PING, later), Verdict::Drop);
+}
+
+#[test]
+fn message_types_can_have_rates_of_their_own() {
+    let mut limits = RateLimits::unlimited();
+    limits.set_rates("store=1:2", &MESSAGE_NAMES).unwrap();
+    let mut limiter = RateLimiter::new(limits);
+    let now = Instant::now();
+    assert_eq!(limiter.check(ip(1), OP_STORE, now), Verdict::Allow);
+    assert_eq!(limiter.check(ip(1), OP_STORE, now), Verdict::Allow);
+    assert_eq!(limiter.check(ip(1), OP_STORE, now), Verdict::Drop);
+    for _ in 0..100 {
+        assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Allow);
+    }
+}
+
+#[test]
+fn sources_that_keep_flooding_are_banned_for_a_while() {
+    let mut limits = RateLimits::with_default(Rate::new(1.0, 1.0));
+    limits.ban_after = Some(3);
+    limits.ban_secs = 10;
+    let mut limiter = RateLimiter::new(limits);
+    let now = Instant::now();
+    assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Allow);
+    assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Drop);
+    assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Drop);
+    assert_eq!(limiter.check(ip(1), OP_PING, now), Verdict::Ban);
+    //even message types it has tokens for
+    assert_eq!(limiter.check(ip(1), OP_STORE, now), Verdict::Banned);
+    assert_eq!(limiter.check(ip(2), OP_PING, now), Verdict::Allow);
+
+    let after_ban = now + Duration::from_secs(11);
+    assert_eq!(limiter.check(ip(1), OP_PING, after_ban), Verdict::Allow);
+}
+
+#[test]
+fn rates_are_parsed_from_a_spec() {
+    let mut limits = RateLimits::unlimited();
+    limits.set_rates("100, set=5:20,GET=50", &REQUEST_NAMES).unwrap();
+    assert_eq!(limits.default, Some(Rate::new(100.0, 100.0)));
+    assert_eq!(limits.per_type.get(&1), Some(&Rate::new(5.0, 20.0)));
+    assert_eq!(limits.per_type.get(&0), Some(&Rate::new(50.0, 50.0)));
+
+    assert!(limits.set_rates("put=5", &REQUEST_NAMES).is_err());
+    assert!(limits.set_rates("fast", &REQUEST_NAMES).is_err());
+    assert!(limits.set_rates("set=5:0", &REQUEST_NAMES).is_err());
+    assert!(limits.set_rates("-1", &REQUEST_NAMES).is_err());
+}
+
+#[test]
+fn the_api_port_drops_requests_over_the_rate() {
+    let mut config = Config::default_with_port(39501);
+    config.async_poll_interval = 50;
+    config.api_port = Some(39511);
+    config.api_limits = RateLimits::unlimited();
+    config.api_limits.set_rates("get=0.001:2", &REQUEST_NAMES).unwrap();
+    let node = AilmedakMachine::spawn(config, Some([0x10; 20]));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut request = vec![0];
+    request.extend_from_slice(&5u32.to_be_bytes());
+    request.extend_from_slice(b"hello");
+    let mut reply = [0; 64];
+    for _ in 0..2 {
+        client.send_to(&request, "127.0.0.1:39511").unwrap();
+        assert!(client.recv_from(&mut reply).is_ok());
+    }
+    client.send_to(&request, "127.0.0.1:39511").unwrap();
+    assert!(client.recv_from(&mut reply).is_err());
+
+    node.shutdown();
+}
@@ -0,0 +1,105 @@
+extern crate ailmedak;
+
+use ailmedak::api_layer::ReadResult;
+use ailmedak::config::Config;
+use ailmedak::message_protocol::{try_decode, Message, ProtoMessage, StoreStatus, Key, Sibling};
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::storage::version::VectorClock;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A replica played by a socket: it answers FindVals with the siblings it holds and keeps the
+/// Stores it is sent
+struct Replica {
+    id: Key,
+    sock: UdpSocket,
+    held: Vec<Sibling>,
+    stored: Arc<Mutex<Vec<Sibling>>>
+}
+
+impl ProtoMessage for Replica {
+    fn id(&self) -> &Key {
+        &self.id
+    }
+}
+
+impl Replica {
+    fn spawn(id: Key, held: Vec<Sibling>, stop: Arc<AtomicBool>) -> (u16, Arc<Mutex<Vec<Sibling>>>, JoinHandle<()>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let port = sock.local_addr().unwrap().port();
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let replica = Replica {id, sock, held, stored: stored.clone()};
+        (port, stored, thread::spawn(move || replica.serve(&stop)))
+    }
+
+    fn serve(&self, stop: &AtomicBool) {
+        let mut buf = [0; 4096];
+        while !stop.load(Ordering::SeqCst) {
+            let (len, src) = match self.sock.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue
+            };
+            let answer = match try_decode(&buf[..len], &20) {
+                Ok((Message::Ping, _)) => self.ping_ack().to_vec(),
+                Ok((Message::FindVal(key), _)) if !self.held.is_empty() => {
+                    //answers late, so the get only reaches its quorum once the replica without
+                    //the value was heard from as well
+                    thread::sleep(Duration::from_millis(200));
+                    self.find_val_resp(&key, &self.held, &[7; 8])
+                },
+                Ok((Message::FindNode(key), _)) | Ok((Message::FindVal(key), _)) => self.find_node_resp(&vec![], &key, &[7; 8]),
+                Ok((Message::Store(key, clock, val, _), _)) => {
+                    self.stored.lock().unwrap().push((clock, val));
+                    self.store_resp_msg(&key, StoreStatus::Ok)
+                },
+                _ => continue
+            };
+            self.sock.send_to(&answer, src).unwrap();
+        }
+    }
+}
+
+fn clock(counter: u64) -> VectorClock {
+    let mut clock = VectorClock::new();
+    clock.set(&[0xaa; 20], counter);
+    clock
+}
+
+#[test]
+fn a_get_repairs_the_replicas_that_are_behind() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let newest = (clock(2), b"new".to_vec());
+    let (current, current_stored, t1) = Replica::spawn([0x21; 20], vec![newest.clone()], stop.clone());
+    let (behind, behind_stored, t2) = Replica::spawn([0x22; 20], vec![(clock(1), b"old".to_vec())], stop.clone());
+    let (empty, empty_stored, t3) = Replica::spawn([0x23; 20], vec![], stop.clone());
+
+    let mut config = Config::default_with_port(39201);
+    config.async_poll_interval = 50;
+    config.read_quorum = 2;
+    config.initial_neighbors = [current, behind, empty].iter().map(|p| format!("127.0.0.1:{}", p)).collect();
+    let node = AilmedakMachine::spawn(config, Some([0x20; 20]));
+    let timeout = Duration::from_secs(5);
+    while node.routing_table().wait_timeout(timeout).unwrap().iter().map(|(_, bucket)| bucket.len()).sum::<usize>() < 3 {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    match node.get(b"key").wait_timeout(timeout).unwrap() {
+        ReadResult::Found(siblings) => assert_eq!(siblings, vec![newest.clone()]),
+        other => panic!("expected the newest value, got {:?}", other)
+    }
+    //the repair goes out after the client has its answer
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(*behind_stored.lock().unwrap(), vec![newest.clone()]);
+    assert_eq!(*empty_stored.lock().unwrap(), vec![newest.clone()]);
+    assert!(current_stored.lock().unwrap().is_empty());
+
+    node.shutdown();
+    stop.store(true, Ordering::SeqCst);
+    for t in [t1, t2, t3] {
+        t.join().unwrap();
+    }
+}
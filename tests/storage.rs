@@ -0,0 +1,267 @@
+extern crate ailmedak;
+
+use ailmedak::storage::{ValueStore, StoredValue, FsyncPolicy, StoreLimits, EvictionPolicy, StoreError, write_atomic};
+use ailmedak::storage::version::{VectorClock, Causality};
+use ailmedak::storage::merkle::{MerkleTree, ROOT};
+use ailmedak::storage::routing::{save_node_id, load_node_id, save_contacts, load_contacts};
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ailmedak-storage-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn clock(counters: &[(u8, u64)]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for &(actor, counter) in counters {
+        clock.set(&[actor; 20], counter);
+    }
+    clock
+}
+
+fn data_of(store: &ValueStore, key: &[u8; 20]) -> Vec<Vec<u8>> {
+    let mut data = store.get(key).into_iter().map(|v| v.data.clone()).collect::<Vec<_>>();
+    data.sort();
+    data
+}
+
+#[test]
+fn store_recovers_from_wal() {
+    let dir = scratch_dir("wal");
+    {
+        let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+        store.put([1; 20], vec![1, 2, 3], None).unwrap();
+        store.put([2; 20], vec![4, 5], None).unwrap();
+        store.remove(&[1; 20]).unwrap();
+    }
+    let store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+    assert_eq!(store.len(), 1);
+    assert!(store.get(&[1; 20]).is_empty());
+    assert_eq!(data_of(&store, &[2; 20]), vec![vec![4, 5]]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn store_recovers_from_snapshot_and_wal() {
+    let dir = scratch_dir("snapshot");
+    {
+        let mut store = ValueStore::open(&dir, FsyncPolicy::Never, 60).unwrap();
+        store.put([1; 20], vec![1], None).unwrap();
+        store.compact().unwrap();
+        store.put([2; 20], vec![2], None).unwrap();
+        store.sync().unwrap();
+    }
+    let store = ValueStore::open(&dir, FsyncPolicy::Never, 60).unwrap();
+    assert_eq!(data_of(&store, &[1; 20]), vec![vec![1]]);
+    assert_eq!(data_of(&store, &[2; 20]), vec![vec![2]]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn store_drops_torn_wal_tail() {
+    let dir = scratch_dir("torn");
+    {
+        let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+        store.put([3; 20], vec![7; 10], None).unwrap();
+    }
+    {
+        //simulate a crash halfway through appending a record
+        let mut wal = OpenOptions::new().append(true).open(dir.join("values.wal")).unwrap();
+        wal.write_all(&[0, 4, 4, 4]).unwrap();
+    }
+    let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+    assert_eq!(store.len(), 1);
+    store.put([5; 20], vec![5], None).unwrap();
+    drop(store);
+    let store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+    assert_eq!(store.len(), 2);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn store_skips_expired_values() {
+    let dir = scratch_dir("expiry");
+    {
+        let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+        store.insert([6; 20], StoredValue {data: vec![6], expires_at: 1, clock: clock(&[(1, 1)])}, None).unwrap();
+        assert!(store.get(&[6; 20]).is_empty());
+    }
+    let store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+    assert!(store.is_empty());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn routing_table_roundtrip() {
+    let dir = scratch_dir("routing");
+    fs::create_dir_all(&dir).unwrap();
+    assert_eq!(load_node_id(&dir).unwrap(), None);
+    assert!(load_contacts(&dir).unwrap().is_empty());
+
+    let contacts = vec![([1; 20], "127.0.0.1:3001".parse().unwrap()),
+                        ([2; 20], "10.0.0.2:4000".parse().unwrap())];
+    save_node_id(&dir, &[9; 20]).unwrap();
+    save_contacts(&dir, &contacts).unwrap();
+    assert_eq!(load_node_id(&dir).unwrap(), Some([9; 20]));
+    assert_eq!(load_contacts(&dir).unwrap(), contacts);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn atomic_writes_keep_the_whole_file_name_in_their_temporary_file() {
+    let dir = scratch_dir("atomic");
+    fs::create_dir_all(&dir).unwrap();
+    //with_extension would have put both of these through node.tmp
+    fs::write(dir.join("node.tmp"), b"unrelated").unwrap();
+    write_atomic(&dir.join("node.id"), b"id").unwrap();
+    write_atomic(&dir.join("node.key"), b"key").unwrap();
+    assert_eq!(fs::read(dir.join("node.id")).unwrap(), b"id");
+    assert_eq!(fs::read(dir.join("node.key")).unwrap(), b"key");
+    assert_eq!(fs::read(dir.join("node.tmp")).unwrap(), b"unrelated");
+    assert!(!dir.join("node.id.tmp").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn store_rejects_oversize_and_over_quota() {
+    let mut store = ValueStore::in_memory(60);
+    store.set_limits(StoreLimits {
+        max_value_size: Some(4),
+        per_source_bytes: Some(6),
+        ..StoreLimits::default()
+    }, EvictionPolicy::Lru, [0; 20]);
+    let source = Some("10.0.0.1".parse().unwrap());
+
+    match store.put([1; 20], vec![0; 5], source) {
+        Err(StoreError::TooLarge) => (),
+        other => panic!("expected TooLarge, got {:?}", other)
+    }
+    store.put([1; 20], vec![0; 4], source).unwrap();
+    match store.put([2; 20], vec![0; 4], source) {
+        Err(StoreError::QuotaExceeded) => (),
+        other => panic!("expected QuotaExceeded, got {:?}", other)
+    }
+    //overwriting its own key does not count twice, and other sources are unaffected
+    store.put([1; 20], vec![0; 3], source).unwrap();
+    store.put([2; 20], vec![0; 4], Some("10.0.0.2".parse().unwrap())).unwrap();
+    assert_eq!(store.bytes(), 7);
+}
+
+#[test]
+fn store_evicts_by_policy() {
+    let limits = StoreLimits {max_keys: Some(2), ..StoreLimits::default()};
+
+    let mut lru = ValueStore::in_memory(60);
+    lru.set_limits(limits.clone(), EvictionPolicy::Lru, [0; 20]);
+    lru.put([1; 20], vec![1], None).unwrap();
+    lru.put([2; 20], vec![2], None).unwrap();
+    lru.get(&[1; 20]);
+    assert_eq!(lru.put([3; 20], vec![3], None).unwrap(), vec![[2; 20]]);
+
+    let mut farthest = ValueStore::in_memory(60);
+    farthest.set_limits(limits.clone(), EvictionPolicy::FarthestFirst, [0; 20]);
+    farthest.put([0xf0; 20], vec![1], None).unwrap();
+    farthest.put([1; 20], vec![2], None).unwrap();
+    assert_eq!(farthest.put([2; 20], vec![3], None).unwrap(), vec![[0xf0; 20]]);
+
+    let mut expiry = ValueStore::in_memory(60);
+    expiry.set_limits(limits, EvictionPolicy::SoonestExpiry, [0; 20]);
+    expiry.insert([1; 20], StoredValue {data: vec![1], expires_at: i64::MAX, clock: clock(&[(1, 1)])}, None).unwrap();
+    expiry.insert([2; 20], StoredValue {data: vec![2], expires_at: i64::MAX - 1, clock: clock(&[(1, 1)])}, None).unwrap();
+    assert_eq!(expiry.put([3; 20], vec![3], None).unwrap(), vec![[2; 20]]);
+}
+
+#[test]
+fn tombstones_block_superseded_versions() {
+    let dir = scratch_dir("tombstone");
+    let remote = Some("10.0.0.1".parse().unwrap());
+    let old = clock(&[(1, 1)]);
+    {
+        let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+        store.put_version([1; 20], vec![1], old.clone(), remote).unwrap();
+        assert_eq!(store.delete(&[1; 20]).unwrap().unwrap()[0].data, vec![1]);
+        assert!(store.get(&[1; 20]).is_empty());
+    }
+    let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+    match store.put_version([1; 20], vec![1], old, remote) {
+        Err(StoreError::Deleted) => (),
+        other => panic!("expected Deleted, got {:?}", other)
+    }
+    //a version concurrent with the delete survives it
+    store.put_version([1; 20], vec![2], clock(&[(2, 1)]), remote).unwrap();
+    assert!(store.is_deleted(&[1; 20]));
+    //and a version written after it clears the tombstone
+    store.put([1; 20], vec![3], None).unwrap();
+    assert!(!store.is_deleted(&[1; 20]));
+    assert_eq!(data_of(&store, &[1; 20]), vec![vec![3]]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn vector_clocks_order_versions() {
+    let a = clock(&[(1, 1)]);
+    let b = clock(&[(2, 1)]);
+    let mut both = a.clone();
+    both.merge(&b);
+    assert_eq!(a.compare(&b), Causality::Concurrent);
+    assert_eq!(a.compare(&both), Causality::Before);
+    assert_eq!(both.compare(&b), Causality::After);
+    assert_eq!(both.compare(&both.clone()), Causality::Equal);
+    assert_eq!(VectorClock::decode(&both.encode()), Some((both.clone(), both.encoded_len())));
+}
+
+#[test]
+fn concurrent_versions_are_kept_as_siblings() {
+    let dir = scratch_dir("siblings");
+    {
+        let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+        store.put_version([1; 20], vec![1], clock(&[(1, 1)]), None).unwrap();
+        store.put_version([1; 20], vec![2], clock(&[(2, 1)]), None).unwrap();
+        //a version that is already superseded changes nothing
+        store.put_version([1; 20], vec![0], VectorClock::new(), None).unwrap();
+        assert_eq!(data_of(&store, &[1; 20]), vec![vec![1], vec![2]]);
+        assert_eq!(store.bytes(), 2);
+    }
+    let mut store = ValueStore::open(&dir, FsyncPolicy::Always, 60).unwrap();
+    assert_eq!(data_of(&store, &[1; 20]), vec![vec![1], vec![2]]);
+
+    //a write with the merged context resolves the conflict
+    let context = store.seen(&[1; 20]);
+    let resolved = store.next_version(&[1; 20], Some(&context));
+    store.put_version([1; 20], vec![3], resolved, None).unwrap();
+    assert_eq!(data_of(&store, &[1; 20]), vec![vec![3]]);
+    assert_eq!(store.bytes(), 1);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn merkle_roots_track_store_contents() {
+    let mut a = ValueStore::in_memory(60);
+    let mut b = ValueStore::in_memory(60);
+    assert_eq!(a.merkle().root(), b.merkle().root());
+
+    a.put_version([1; 20], vec![1], clock(&[(1, 1)]), None).unwrap();
+    a.put_version([2; 20], vec![2], clock(&[(1, 2)]), None).unwrap();
+    b.put_version([2; 20], vec![2], clock(&[(1, 2)]), None).unwrap();
+    assert!(a.merkle().root() != b.merkle().root());
+    //only the leaf of the missing key differs
+    let leaf = MerkleTree::leaf_of(&[1; 20]);
+    assert!(a.merkle().hash(leaf) != b.merkle().hash(leaf));
+    assert_eq!(a.merkle().hash(MerkleTree::leaf_of(&[2; 20])), b.merkle().hash(MerkleTree::leaf_of(&[2; 20])));
+    assert_eq!(a.merkle().bucket(leaf).len(), 1);
+
+    //the order versions arrive in does not matter
+    b.put_version([1; 20], vec![1], clock(&[(1, 1)]), None).unwrap();
+    assert_eq!(a.merkle().root(), b.merkle().root());
+
+    a.delete_version(&[1; 20], clock(&[(1, 3)])).unwrap();
+    assert!(a.merkle().root() != b.merkle().root());
+    b.delete_version(&[1; 20], clock(&[(1, 3)])).unwrap();
+    assert_eq!(a.merkle().root(), b.merkle().root());
+    assert!(a.merkle().hash(ROOT).is_some());
+}
@@ -2,7 +2,7 @@
 
 extern crate ailmedak;
 
-use ailmedak::node::{KademliaNode};
+use ailmedak::node::state::{KademliaNode};
 
 #[test]
 fn test_k_bucket_index_0() {
@@ -0,0 +1,104 @@
+extern crate ailmedak;
+
+use ailmedak::config::Config;
+use ailmedak::message_protocol::{try_decode, Message, ProtoMessage, StoreStatus, Key, NO_TOKEN, TOKEN_LEN};
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::node::tokens::{WriteTokens, PeerTokens, Stamped};
+use ailmedak::storage::version::VectorClock;
+use std::net::{IpAddr, UdpSocket};
+use std::time::Duration;
+
+struct Peer;
+
+impl ProtoMessage for Peer {
+    fn id(&self) -> &Key {
+        &[0x20; 20]
+    }
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn tokens_are_good_for_one_rotation_after_the_one_they_were_issued_in() {
+    let mut tokens = WriteTokens::new(300, 0);
+    let token = tokens.issue(ip("203.0.113.1"));
+    assert!(tokens.accepts(ip("203.0.113.1"), &token));
+    assert!(!tokens.accepts(ip("203.0.113.2"), &token));
+    assert!(!tokens.accepts(ip("203.0.113.1"), &NO_TOKEN));
+
+    tokens.rotate_if_due(299);
+    assert_eq!(tokens.issue(ip("203.0.113.1")), token);
+    tokens.rotate_if_due(300);
+    assert_ne!(tokens.issue(ip("203.0.113.1")), token);
+    assert!(tokens.accepts(ip("203.0.113.1"), &token));
+    tokens.rotate_if_due(600);
+    assert!(!tokens.accepts(ip("203.0.113.1"), &token));
+}
+
+#[test]
+fn stores_wait_for_the_token_of_their_destination() {
+    let tokens = PeerTokens::new(300);
+    let dst = "127.0.0.1:3000".parse().unwrap();
+    let store = || Peer.store_msg(&[1; 20], &VectorClock::new(), b"v", &NO_TOKEN);
+    assert!(matches!(tokens.stamp(store(), dst, 0), Stamped::Waiting {ask: true}));
+    assert!(matches!(tokens.stamp(store(), dst, 0), Stamped::Waiting {ask: false}));
+    //deletes wait too, other messages need no token
+    assert!(matches!(tokens.stamp(Peer.delete_msg(&[1; 20], &VectorClock::new(), &NO_TOKEN), dst, 0), Stamped::Waiting {ask: false}));
+    assert!(matches!(tokens.stamp(Peer.find_node_msg(&[1; 20]).to_vec(), dst, 0), Stamped::Ready(_)));
+
+    let waited = tokens.received(dst, [7; TOKEN_LEN], 10);
+    assert_eq!(waited.len(), 3);
+    match try_decode(&waited[0], &20) {
+        Ok((Message::Store(_, _, _, token), _)) => assert_eq!(token, [7; TOKEN_LEN]),
+        other => panic!("expected a Store, got {:?}", other)
+    }
+    match try_decode(&waited[2], &20) {
+        Ok((Message::Delete(_, _, token), _)) => assert_eq!(token, [7; TOKEN_LEN]),
+        other => panic!("expected a Delete, got {:?}", other)
+    }
+    assert!(matches!(tokens.stamp(store(), dst, 20), Stamped::Ready(_)));
+
+    //a token is not used past its lifetime
+    tokens.expire(310);
+    assert!(matches!(tokens.stamp(store(), dst, 310), Stamped::Waiting {ask: true}));
+}
+
+#[test]
+fn stores_without_a_token_are_rejected() {
+    let mut config = Config::default_with_port(39601);
+    config.async_poll_interval = 50;
+    let node = AilmedakMachine::spawn(config, Some([0x10; 20]));
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let mut next = || {
+        loop {
+            let (len, _) = peer.recv_from(&mut buf).unwrap();
+            //the node pings the peer and looks itself up through it, which is not what is tested
+            match try_decode(&buf[..len], &20).unwrap().0 {
+                Message::Ping | Message::FindNode(_) => continue,
+                message => return message
+            }
+        }
+    };
+
+    let key = [1; 20];
+    peer.send_to(&Peer.store_msg(&key, &VectorClock::new(), b"v", &[7; TOKEN_LEN]), "127.0.0.1:39601").unwrap();
+    assert!(next() == Message::StoreResp(key, StoreStatus::InvalidToken));
+
+    peer.send_to(&Peer.find_node_msg(&key), "127.0.0.1:39601").unwrap();
+    let token = match next() {
+        Message::FindNodeResp(_, _, token) => token,
+        other => panic!("expected a FindNodeResp, got {:?}", other)
+    };
+    peer.send_to(&Peer.store_msg(&key, &VectorClock::new(), b"v", &token), "127.0.0.1:39601").unwrap();
+    assert!(next() == Message::StoreResp(key, StoreStatus::Ok));
+
+    //deletes are checked the same way
+    peer.send_to(&Peer.delete_msg(&key, &VectorClock::new(), &[7; TOKEN_LEN]), "127.0.0.1:39601").unwrap();
+    assert!(next() == Message::StoreResp(key, StoreStatus::InvalidToken));
+
+    node.shutdown();
+}
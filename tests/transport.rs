@@ -0,0 +1,217 @@
+extern crate ailmedak;
+
+use ailmedak::api_layer::ReadResult;
+use ailmedak::config::Config;
+use ailmedak::message_protocol::{try_decode, Message, StoreStatus};
+use ailmedak::node::machine::AilmedakMachine;
+use ailmedak::node::handle::NodeHandle;
+use ailmedak::transport::{Encryption, Identity, PeerKeys, Sessions, HANDSHAKE_TIMEOUT};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const A: [u8; 20] = [0x10; 20];
+const B: [u8; 20] = [0x20; 20];
+
+fn addr(port: u16) -> SocketAddr {
+    format!("127.0.0.1:{}", port).parse().unwrap()
+}
+
+/// a message from the node with id from, its opcode and length do not matter here
+fn msg(from: [u8; 20], body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![0];
+    msg.extend_from_slice(&from);
+    msg.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    msg.extend_from_slice(body);
+    msg
+}
+
+/// runs the handshake a starts to send msg to b, returning what a sends once it is done
+fn handshake(a: &Sessions, b: &Sessions, msg: &[u8], now: Instant) -> Vec<Vec<u8>> {
+    let sent = a.outgoing(msg, addr(2), now);
+    assert_eq!(sent.len(), 1);
+    let answer = match try_decode(&sent[0], &20) {
+        Ok((Message::Handshake(index, ephemeral, static_key), id)) => b.respond(addr(1), &id, index, &PeerKeys {ephemeral, static_key}, now),
+        other => panic!("expected a Handshake, got {:?}", other)
+    };
+    match try_decode(&answer[0], &20) {
+        Ok((Message::HandshakeResp(initiator, responder, ephemeral, static_key), id)) => {
+            a.complete(addr(2), &id, initiator, responder, &PeerKeys {ephemeral, static_key}, now)
+        },
+        other => panic!("expected a HandshakeResp, got {:?}", other)
+    }
+}
+
+#[test]
+fn messages_wait_for_the_handshake_and_open_only_once() {
+    let a = Sessions::new(A, Identity::generate(), Encryption::Required);
+    let b = Sessions::new(B, Identity::generate(), Encryption::Required);
+    let now = Instant::now();
+
+    let sealed = handshake(&a, &b, &msg(A, b"secret value"), now);
+    assert_eq!(sealed.len(), 1);
+    assert!(!sealed[0].windows(6).any(|w| w == b"secret"));
+    assert_eq!(b.open(&sealed[0], addr(1)), Some(msg(A, b"secret value")));
+    //replayed, tampered with or from elsewhere, it does not open
+    assert_eq!(b.open(&sealed[0], addr(1)), None);
+    let again = a.outgoing(&msg(A, b"secret value"), addr(2), now);
+    let mut tampered = again[0].clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(b.open(&tampered, addr(1)), None);
+    assert_eq!(b.open(&again[0], addr(3)), None);
+    assert_eq!(b.open(&again[0], addr(1)), Some(msg(A, b"secret value")));
+
+    //the session works both ways
+    let back = b.outgoing(&msg(B, b"reply"), addr(1), now);
+    assert_eq!(back.len(), 1);
+    assert_eq!(a.open(&back[0], addr(2)), Some(msg(B, b"reply")));
+}
+
+/// what to answers to a handshake datagram from the node at from
+fn deliver(to: &Sessions, datagram: &[u8], from: SocketAddr, now: Instant) -> Vec<Vec<u8>> {
+    match try_decode(datagram, &20) {
+        Ok((Message::Handshake(index, ephemeral, static_key), id)) => to.respond(from, &id, index, &PeerKeys {ephemeral, static_key}, now),
+        Ok((Message::HandshakeResp(initiator, responder, ephemeral, static_key), id)) => {
+            to.complete(from, &id, initiator, responder, &PeerKeys {ephemeral, static_key}, now)
+        },
+        other => panic!("expected a handshake, got {:?}", other)
+    }
+}
+
+#[test]
+fn sealed_messages_carry_the_node_id_of_their_session() {
+    let a = Sessions::new(A, Identity::generate(), Encryption::Required);
+    let b = Sessions::new(B, Identity::generate(), Encryption::Required);
+    let now = Instant::now();
+    handshake(&a, &b, &msg(A, b"m"), now);
+
+    //a peer with a session can not pass its messages off as another node's
+    let forged = a.outgoing(&msg([0x30; 20], b"forged"), addr(2), now);
+    assert_eq!(b.open(&forged[0], addr(1)), None);
+    let short = a.outgoing(b"", addr(2), now);
+    assert_eq!(b.open(&short[0], addr(1)), None);
+    let sent = a.outgoing(&msg(A, b"m"), addr(2), now);
+    assert_eq!(b.open(&sent[0], addr(1)), Some(msg(A, b"m")));
+}
+
+#[test]
+fn crossing_handshakes_end_in_one_session() {
+    let a = Sessions::new(A, Identity::generate(), Encryption::Required);
+    let b = Sessions::new(B, Identity::generate(), Encryption::Required);
+    let now = Instant::now();
+
+    let from_a = a.outgoing(&msg(A, b"to b"), addr(2), now);
+    let from_b = b.outgoing(&msg(B, b"to a"), addr(1), now);
+    //a has the lower id, so its handshake is the one answered
+    assert!(deliver(&a, &from_b[0], addr(2), now).is_empty());
+    let answer = deliver(&b, &from_a[0], addr(1), now);
+    assert_eq!(answer.len(), 2);
+    let sealed = deliver(&a, &answer[0], addr(2), now);
+    assert_eq!(sealed.len(), 1);
+    assert_eq!(b.open(&sealed[0], addr(1)), Some(msg(A, b"to b")));
+    assert_eq!(a.open(&answer[1], addr(2)), Some(msg(B, b"to a")));
+
+    let back = b.outgoing(&msg(B, b"again"), addr(1), now);
+    assert_eq!(a.open(&back[0], addr(2)), Some(msg(B, b"again")));
+}
+
+#[test]
+fn a_replayed_handshake_does_not_replace_a_session() {
+    let a = Sessions::new(A, Identity::generate(), Encryption::Required);
+    let b = Sessions::new(B, Identity::generate(), Encryption::Required);
+    let now = Instant::now();
+
+    let init = a.outgoing(&msg(A, b"m"), addr(2), now);
+    let answer = deliver(&b, &init[0], addr(1), now);
+    let sealed = deliver(&a, &answer[0], addr(2), now);
+    assert_eq!(b.open(&sealed[0], addr(1)), Some(msg(A, b"m")));
+
+    //b answers the replay, but keeps sealing in the session a knows
+    assert_eq!(deliver(&b, &init[0], addr(1), now).len(), 1);
+    let back = b.outgoing(&msg(B, b"still here"), addr(1), now);
+    assert_eq!(a.open(&back[0], addr(2)), Some(msg(B, b"still here")));
+}
+
+#[test]
+fn peers_that_do_not_answer_are_talked_to_in_plaintext_if_preferred() {
+    let now = Instant::now();
+    let later = now + HANDSHAKE_TIMEOUT;
+
+    let preferred = Sessions::new(A, Identity::generate(), Encryption::Preferred);
+    assert_eq!(preferred.outgoing(&msg(A, b"m1"), addr(2), now).len(), 1);
+    assert!(preferred.outgoing(&msg(A, b"m2"), addr(2), now).is_empty());
+    assert_eq!(preferred.expire(later), vec![(addr(2), msg(A, b"m1")), (addr(2), msg(A, b"m2"))]);
+    assert_eq!(preferred.outgoing(&msg(A, b"m3"), addr(2), later), vec![msg(A, b"m3")]);
+    assert!(preferred.accepts_plaintext(Some(0)));
+
+    let required = Sessions::new(A, Identity::generate(), Encryption::Required);
+    required.outgoing(&msg(A, b"m1"), addr(2), now);
+    assert!(required.expire(later).is_empty());
+    assert!(!required.accepts_plaintext(Some(0)));
+}
+
+#[test]
+fn a_node_id_keeps_the_identity_key_it_first_showed() {
+    let a = Sessions::new(A, Identity::generate(), Encryption::Required);
+    let b = Sessions::new(B, Identity::generate(), Encryption::Required);
+    let impostor = Sessions::new(A, Identity::generate(), Encryption::Required);
+    let now = Instant::now();
+    assert_eq!(handshake(&a, &b, &msg(A, b"m"), now).len(), 1);
+
+    let sent = impostor.outgoing(&msg(A, b"m"), addr(2), now);
+    match try_decode(&sent[0], &20) {
+        Ok((Message::Handshake(index, ephemeral, static_key), id)) => {
+            assert!(b.respond(addr(1), &id, index, &PeerKeys {ephemeral, static_key}, now).is_empty())
+        },
+        other => panic!("expected a Handshake, got {:?}", other)
+    }
+}
+
+fn config(port: u16, encryption: Encryption, neighbor: Option<u16>) -> Config {
+    let mut config = Config::default_with_port(port);
+    config.async_poll_interval = 50;
+    config.encryption = encryption;
+    config.initial_neighbors = neighbor.into_iter().map(|p| format!("127.0.0.1:{}", p)).collect();
+    config
+}
+
+fn wait_for_contact(node: &NodeHandle, id: [u8; 20]) -> bool {
+    let timeout = Duration::from_secs(5);
+    for _ in 0..100 {
+        let table = node.routing_table().wait_timeout(timeout).unwrap();
+        if table.iter().any(|(_, bucket)| bucket.iter().any(|&(i, _)| i == id)) {
+            return true
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn nodes_requiring_encryption_store_and_find_values() {
+    let a = AilmedakMachine::spawn(config(39801, Encryption::Required, None), Some(A));
+    let b = AilmedakMachine::spawn(config(39802, Encryption::Required, Some(39801)), Some(B));
+    assert!(wait_for_contact(&b, A));
+
+    let timeout = Duration::from_secs(5);
+    let written = b.set(b"hello", b"world").wait_timeout(timeout).unwrap();
+    assert_eq!(written.status, Some(StoreStatus::Ok));
+    match a.get(b"hello").wait_timeout(timeout).unwrap() {
+        ReadResult::Found(siblings) => assert_eq!(siblings[0].1, b"world".to_vec()),
+        other => panic!("expected the value, got {:?}", other)
+    }
+
+    b.shutdown();
+    a.shutdown();
+}
+
+#[test]
+fn nodes_preferring_encryption_fall_back_for_nodes_without_it() {
+    let a = AilmedakMachine::spawn(config(39803, Encryption::Preferred, None), Some(A));
+    let b = AilmedakMachine::spawn(config(39804, Encryption::Off, Some(39803)), Some(B));
+    assert!(wait_for_contact(&b, A));
+    assert!(wait_for_contact(&a, B));
+
+    b.shutdown();
+    a.shutdown();
+}